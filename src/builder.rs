@@ -0,0 +1,66 @@
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::compare::OrdComparator;
+use super::pager::ReadWriteSeek;
+use super::tree::{sort_dedup, BPTree};
+
+/// Builds a tree over a file or other storage from pairs in any order.
+///
+/// The pairs are staged in memory, sorted and deduplicated before being
+/// bulk-loaded, so the resulting nodes are packed as tightly as with
+/// [`BPTree::bulk_load`]. When a key appears more than once the value that
+/// came last wins.
+pub struct BPTreeBuilder {
+    degree: usize,
+    file: Box<dyn ReadWriteSeek>,
+}
+
+impl BPTreeBuilder {
+    pub fn new(degree: usize, file: impl ReadWriteSeek + 'static) -> Self {
+        Self {
+            degree,
+            file: Box::new(file),
+        }
+    }
+
+    pub fn build_from<K, V>(self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<BPTree<K, V>>
+    where
+        K: Ord + Clone + Encode + Decode + 'static,
+        V: Clone + Encode + Decode + 'static,
+    {
+        BPTree::bulk_load(self.degree, self.file, sort_dedup(&OrdComparator, pairs).into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::*;
+
+    #[test]
+    fn build_from_sorts_and_keeps_last_value() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/build_from_sorts_and_keeps_last_value.ldb")?;
+
+        let pairs = vec![(5u32, 50u32), (1, 10), (3, 30), (1, 11), (4, 40), (5, 51), (2, 20), (1, 12)];
+        let mut tree = BPTreeBuilder::new(4, file).build_from(pairs)?;
+        tree.validate()?;
+
+        let expected = vec![(1, 12), (2, 20), (3, 30), (4, 40), (5, 51)];
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected);
+
+        tree.extend(vec![(7, 70), (0, 0), (3, 31), (7, 71)])?;
+        tree.validate()?;
+
+        let expected = vec![(0, 0), (1, 12), (2, 20), (3, 31), (4, 40), (5, 51), (7, 71)];
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use std::cmp::Ordering;
+
+/// Ordering the tree keeps its keys in.
+///
+/// The comparator is not stored in the file, so a tree has to be reopened
+/// with the comparator it was built with or lookups will miss keys.
+pub trait KeyComparator<K> {
+    fn compare(&self, a: &K, b: &K) -> Ordering;
+
+    /// Key an internal node separates two leaves by when a leaf splits,
+    /// given `left`, the last key staying in the left leaf, and `right`,
+    /// the first key moving to the right one. Lookups route keys ordered at
+    /// or before a separator to its left, so it has to order at or after
+    /// `left` and before `right`. The default is `left` itself; a shorter
+    /// key leaves room for more children in an internal node.
+    fn separator(&self, left: &K, _right: &K) -> K
+    where
+        K: Clone,
+    {
+        left.clone()
+    }
+
+    /// Whether keys are ordered as their `Ord` implementation orders them.
+    /// Lookups by a borrowed `&str` then compare strings directly, and
+    /// walk the whole tree otherwise. False unless a comparator says so.
+    fn orders_like_ord(&self) -> bool {
+        false
+    }
+}
+
+/// Orders keys by their `Ord` implementation. Used unless a tree is given
+/// another comparator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OrdComparator;
+
+impl<K: Ord> KeyComparator<K> for OrdComparator {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn orders_like_ord(&self) -> bool {
+        true
+    }
+}
+
+/// Orders string keys like [`OrdComparator`] and separates split leaves by
+/// the shortest key that still tells them apart, e.g. `"applj"` between
+/// `"application"` and `"apply"`. Internal nodes of long keys with common
+/// prefixes then fit more children. The order is the same, so a tree built
+/// with it may be reopened with either comparator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShortSeparators;
+
+impl KeyComparator<String> for ShortSeparators {
+    fn compare(&self, a: &String, b: &String) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn orders_like_ord(&self) -> bool {
+        true
+    }
+
+    /// Takes `left` up to the first character that differs from `right`,
+    /// and that character bumped to the next one. The result orders after
+    /// `left`, and before `right` unless it is `right` itself, in which case
+    /// nothing shorter than `left` fits.
+    fn separator(&self, left: &String, right: &String) -> String {
+        let differs = left.char_indices().zip(right.chars()).find(|((_, ours), theirs)| ours != theirs);
+        let Some(((index, ours), theirs)) = differs else {
+            return left.clone();
+        };
+
+        // The next character, skipping the surrogates `char` can't hold.
+        let next = (ours as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        match next {
+            Some(next) if next < theirs || (next == theirs && right.len() > index + theirs.len_utf8()) => {
+                let mut separator = left[..index].to_string();
+                separator.push(next);
+                separator
+            },
+            _ => left.clone(),
+        }
+    }
+}
+
+impl<K, F: Fn(&K, &K) -> Ordering> KeyComparator<K> for F {
+    fn compare(&self, a: &K, b: &K) -> Ordering {
+        self(a, b)
+    }
+}
+
+/// Binary search of `key` in `keys` sorted by `cmp`, with the same result
+/// as `slice::binary_search`.
+pub(crate) fn search<K>(cmp: &dyn KeyComparator<K>, keys: &[K], key: &K) -> Result<usize, usize> {
+    keys.binary_search_by(|probe| cmp.compare(probe, key))
+}
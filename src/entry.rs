@@ -0,0 +1,243 @@
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::node::leaf::LeafNode;
+use super::pager::{DynOperator, Offset, PageOperator};
+use super::tree::BPTree;
+
+/// A key of a tree, present or not, as found by [`BPTree::entry`].
+///
+/// An entry remembers the pages the lookup descended through and where
+/// the key sits in its leaf, so reading it and replacing its value don't
+/// descend again. It borrows the tree mutably, so nothing else can change
+/// the tree and move those pages while the entry is alive.
+pub enum Entry<'a, K, V, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V>> {
+    Occupied(OccupiedEntry<'a, K, V, P>),
+    Vacant(VacantEntry<'a, K, V, P>),
+}
+
+/// A key that is present, see [`Entry`].
+pub struct OccupiedEntry<'a, K, V, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V>> {
+    tree: &'a mut BPTree<K, V, P>,
+    path: Vec<Offset>,
+    leaf_node: LeafNode<K, V>,
+    position: usize,
+}
+
+/// A key that is absent, see [`Entry`].
+pub struct VacantEntry<'a, K, V, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V>> {
+    tree: &'a mut BPTree<K, V, P>,
+    key: K,
+    path: Vec<Offset>,
+    leaf_node: Option<LeafNode<K, V>>,
+    position: usize,
+}
+
+impl<'a, K, V, P> Entry<'a, K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    pub(crate) fn new(tree: &'a mut BPTree<K, V, P>, key: K, path: Vec<Offset>, leaf_node: Option<LeafNode<K, V>>) -> Self {
+        let search = match &leaf_node {
+            None => Err(0),
+            Some(leaf_node) => tree.search_leaf(leaf_node, &key),
+        };
+        match (search, leaf_node) {
+            (Ok(position), Some(leaf_node)) => Entry::Occupied(OccupiedEntry { tree, path, leaf_node, position }),
+            (search, leaf_node) => {
+                let position = search.unwrap_or_else(|position| position);
+                Entry::Vacant(VacantEntry { tree, key, path, leaf_node, position })
+            },
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns the value of the key, first inserting `default` if the key
+    /// is absent.
+    pub fn or_insert(self, default: V) -> Result<V> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value of the key, first inserting what `f` returns if
+    /// the key is absent. `f` isn't called for a present key.
+    pub fn or_insert_with(self, f: impl FnOnce() -> V) -> Result<V> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    /// Applies `f` to the value of a present key and writes the result
+    /// back. An absent key is left as it is.
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Result<Self> {
+        match self {
+            Entry::Occupied(mut entry) => {
+                let mut value = entry.get().clone();
+                f(&mut value);
+                entry.insert(value)?;
+                Ok(Entry::Occupied(entry))
+            },
+            Entry::Vacant(entry) => Ok(Entry::Vacant(entry)),
+        }
+    }
+}
+
+impl<K, V, P> OccupiedEntry<'_, K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    pub fn key(&self) -> &K {
+        &self.leaf_node.keys[self.position]
+    }
+
+    pub fn get(&self) -> &V {
+        &self.leaf_node.values[self.position]
+    }
+
+    /// Offset of the leaf holding the key and the position of the key
+    /// within it.
+    pub fn location(&self) -> (Offset, usize) {
+        (self.path[self.path.len() - 1], self.position)
+    }
+
+    /// Replaces the value of the key and returns the one it had. Only the
+    /// pages the lookup went through are copied, without descending again.
+    pub fn insert(&mut self, value: V) -> Result<V> {
+        self.tree.check_entry(self.key(), &value)?;
+        let mut leaf_node = self.leaf_node.clone();
+        let previous = std::mem::replace(&mut leaf_node.values[self.position], value);
+        self.path = self.tree.write_leaf_path(&self.path, &mut leaf_node)?;
+        self.leaf_node = leaf_node;
+        Ok(previous)
+    }
+
+    /// Returns the value of the key, leaving the tree as it is.
+    pub fn into_value(self) -> V {
+        let mut leaf_node = self.leaf_node;
+        leaf_node.values.swap_remove(self.position)
+    }
+}
+
+impl<K, V, P> VacantEntry<'_, K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts the key with `value` and returns the value. A leaf with room
+    /// for the key takes it without descending again; a full leaf or an
+    /// empty tree goes through a regular insert, which splits as needed.
+    pub fn insert(self, value: V) -> Result<V> {
+        let degree = self.tree.degree();
+        match self.leaf_node {
+            Some(mut leaf_node) if leaf_node.keys.len() < degree - 1 => {
+                self.tree.check_entry(&self.key, &value)?;
+                leaf_node.keys.insert(self.position, self.key);
+                leaf_node.values.insert(self.position, value.clone());
+                self.tree.write_leaf_path(&self.path, &mut leaf_node)?;
+            },
+            _ => self.tree.insert(self.key, value.clone())?,
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::error::BPTreeError;
+    use crate::pager::{memory::MemoryPager, STARTUP_OFFSET};
+    use crate::tree::{Key, Value};
+
+    use super::*;
+
+    #[test]
+    fn entries_read_and_change_the_key() -> anyhow::Result<()> {
+        let mut tree = BPTree::<Key, Value>::with_pager(4, Box::new(MemoryPager::new()))?;
+        for word in ["apple", "apple", "pear", "apple", "fig"] {
+            tree.entry(word.to_string())?
+                .and_modify(|count| count[0] += 1)?
+                .or_insert(vec![1])?;
+        }
+        assert_eq!(tree.search("apple".to_string())?, Some(vec![3]));
+        assert_eq!(tree.search("pear".to_string())?, Some(vec![1]));
+        assert_eq!(tree.search("fig".to_string())?, Some(vec![1]));
+
+        let mut calls = 0;
+        let value = tree.entry("fig".to_string())?.or_insert_with(|| {
+            calls += 1;
+            vec![9]
+        })?;
+        assert_eq!((value, calls), (vec![1], 0));
+
+        // Enough keys to split leaves and grow the root, through both the
+        // in-place path and the regular insert of full leaves.
+        for i in 0..200 {
+            let key = format!("{:03}", i * 37 % 200);
+            match tree.entry(key.clone())? {
+                Entry::Occupied(_) => panic!("{key} is not in the tree yet"),
+                Entry::Vacant(entry) => assert_eq!(entry.insert(key.clone().into_bytes())?, key.as_bytes()),
+            }
+        }
+        tree.validate()?;
+        assert_eq!(tree.len()?, 203);
+
+        let Entry::Occupied(mut entry) = tree.entry("123".to_string())? else {
+            panic!("123 is in the tree");
+        };
+        let (leaf_offset, position) = entry.location();
+        assert_eq!(entry.insert(b"new".to_vec())?, b"123");
+        assert_ne!(entry.location().0, leaf_offset);
+        assert_eq!(entry.location().1, position);
+        assert_eq!(entry.get(), b"new");
+        let location = entry.location();
+        assert_eq!(tree.locate("123".to_string())?, Some(location));
+
+        tree.validate()?;
+        assert_eq!(tree.search("123".to_string())?, Some(b"new".to_vec()));
+        assert_eq!(tree.entry("999".to_string())?.key(), "999");
+
+        Ok(())
+    }
+
+    #[test]
+    fn replacing_a_value_checks_the_limits() -> anyhow::Result<()> {
+        let mut tree = BPTree::<Key, Value>::new(4, STARTUP_OFFSET, Cursor::new(Vec::new()))?;
+        tree.insert("key".to_string(), b"value".to_vec())?;
+        let writes = tree.io_stats().writes;
+
+        let err = tree.entry("key".to_string())?.and_modify(|value| value.resize(1400, 0x00)).err().unwrap();
+        assert!(matches!(err, BPTreeError::ValueTooLarge { size: 1403, limit: 1365 }), "{err}");
+        let Entry::Occupied(mut entry) = tree.entry("key".to_string())? else {
+            panic!("key is in the tree");
+        };
+        let err = entry.insert(vec![0x00; 1400]).unwrap_err();
+        assert!(matches!(err, BPTreeError::ValueTooLarge { .. }), "{err}");
+        assert_eq!(entry.get(), b"value");
+
+        assert_eq!(tree.io_stats().writes, writes);
+        assert_eq!(tree.search("key".to_string())?, Some(b"value".to_vec()));
+        tree.validate()?;
+
+        Ok(())
+    }
+}
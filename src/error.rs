@@ -0,0 +1,131 @@
+use std::fmt;
+
+use bincode::error::{DecodeError, EncodeError};
+
+use super::header::FORMAT_VERSION;
+use super::pager::Offset;
+use super::tree::MIN_DEGREE;
+
+pub type Result<T, E = BPTreeError> = std::result::Result<T, E>;
+
+/// Failure of a tree operation.
+#[derive(Debug)]
+pub enum BPTreeError {
+    /// Reading or writing the underlying storage failed.
+    Io(std::io::Error),
+    /// A page or the header could not be decoded.
+    Decode(DecodeError),
+    /// A node or the header could not be encoded.
+    Encode(EncodeError),
+    /// An encoded node is larger than a page.
+    PageOverflow { size: usize, page_size: usize },
+    /// An encoded key is larger than the tree accepts.
+    KeyTooLarge { size: usize, limit: usize },
+    /// An encoded value is larger than the tree accepts.
+    ValueTooLarge { size: usize, limit: usize },
+    /// The page at `offset` doesn't match its checksum.
+    Corrupt { offset: Offset },
+    /// The file was written by a newer build, in a layout this one can't
+    /// read.
+    UnsupportedFormat { version: u16 },
+    /// The file was written in an older layout that this build no longer
+    /// reads. The build that wrote it can [`export`](crate::BPTree::export)
+    /// the pairs for this one to [`import`](crate::BPTree::import).
+    OutdatedFormat { version: u16 },
+    /// The degree is below [`MIN_DEGREE`].
+    InvalidDegree(usize),
+    /// The file holds a tree written with another degree than the one it
+    /// was opened with.
+    DegreeMismatch { stored: usize, requested: usize },
+    /// Anything else, including errors returned by closures passed to the
+    /// tree.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for BPTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BPTreeError::Io(err) => write!(f, "I/O error: {err}"),
+            BPTreeError::Decode(err) => write!(f, "decode error: {err}"),
+            BPTreeError::Encode(err) => write!(f, "encode error: {err}"),
+            BPTreeError::PageOverflow { size, page_size } => {
+                write!(f, "node encoding {size} bytes exceeds page size {page_size}")
+            },
+            BPTreeError::KeyTooLarge { size, limit } => write!(f, "key encoding {size} bytes exceeds the limit of {limit}"),
+            BPTreeError::ValueTooLarge { size, limit } => {
+                write!(f, "value encoding {size} bytes exceeds the limit of {limit}")
+            },
+            BPTreeError::Corrupt { offset } => write!(f, "checksum mismatch for page at offset {offset}"),
+            BPTreeError::UnsupportedFormat { version } => {
+                write!(f, "unsupported format version {version}, this build supports {FORMAT_VERSION}")
+            },
+            BPTreeError::OutdatedFormat { version } => {
+                write!(f, "format version {version} predates {FORMAT_VERSION}, export the tree with the build that wrote it")
+            },
+            BPTreeError::InvalidDegree(degree) => write!(f, "degree must be at least {MIN_DEGREE}, got {degree}"),
+            BPTreeError::DegreeMismatch { stored, requested } => {
+                write!(f, "file holds a tree of degree {stored}, not {requested}")
+            },
+            BPTreeError::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for BPTreeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BPTreeError::Io(err) => Some(err),
+            BPTreeError::Decode(err) => Some(err),
+            BPTreeError::Encode(err) => Some(err),
+            BPTreeError::Other(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for BPTreeError {
+    fn from(err: std::io::Error) -> Self {
+        BPTreeError::Io(err)
+    }
+}
+
+impl From<fmt::Error> for BPTreeError {
+    fn from(err: fmt::Error) -> Self {
+        BPTreeError::Other(err.into())
+    }
+}
+
+impl From<DecodeError> for BPTreeError {
+    fn from(err: DecodeError) -> Self {
+        BPTreeError::Decode(err)
+    }
+}
+
+impl From<EncodeError> for BPTreeError {
+    fn from(err: EncodeError) -> Self {
+        BPTreeError::Encode(err)
+    }
+}
+
+/// Internals report errors through `anyhow`; the typed error they started
+/// from is recovered here.
+impl From<anyhow::Error> for BPTreeError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<BPTreeError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<std::io::Error>() {
+            Ok(err) => return BPTreeError::Io(err),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<DecodeError>() {
+            Ok(err) => return BPTreeError::Decode(err),
+            Err(err) => err,
+        };
+        match err.downcast::<EncodeError>() {
+            Ok(err) => BPTreeError::Encode(err),
+            Err(err) => BPTreeError::Other(err),
+        }
+    }
+}
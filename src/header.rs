@@ -0,0 +1,51 @@
+use bincode::{Decode, Encode};
+use crate::pager::{Compression, Encoding, Offset, ValueStorage};
+
+/// Marks the first bytes of a file holding a tree.
+pub(crate) const MAGIC: [u8; 4] = *b"BPTR";
+/// Version of the file layout written by this build. Bump it whenever the
+/// encoding of the header or of the pages changes. Newer versions are
+/// refused as unsupported. Older ones are refused as outdated, as no
+/// earlier layout has an upgrade path: a file of one is carried over by
+/// exporting it with the build that wrote it and importing the records.
+pub(crate) const FORMAT_VERSION: u16 = 10;
+
+/// Tree metadata stored in the reserved header page at the start of the file.
+/// `cursor`, `free_pages` and `retained_pages` describe page allocation and
+/// are filled in by the pager when the header is written.
+#[derive(Clone, Debug, Default, Encode, Decode)]
+pub struct Header {
+    /// `MAGIC` and `FORMAT_VERSION`, filled in by the pager. They come
+    /// first so they can be checked before the rest is decoded.
+    pub magic: [u8; 4],
+    pub format_version: u16,
+    pub root_node: Option<Offset>,
+    pub degree: usize,
+    pub cursor: Offset,
+    pub free_pages: Vec<Offset>,
+    /// Roots of the versions pinned by live snapshots.
+    pub snapshots: Vec<Offset>,
+    /// Whether appending splits may have left nodes on the right edge
+    /// short of the minimum, see `SplitBias::Append`. Cleared by a gc.
+    pub short_right_edge: bool,
+    /// Whether deferred deletes may have left leaves short of the minimum,
+    /// see `DeleteMode::Deferred`. Cleared by a gc.
+    pub short_leaves: bool,
+    /// Pages freed while a snapshot was pinned. They only become reusable
+    /// once every snapshot has been released.
+    pub retained_pages: Vec<Offset>,
+    /// Size of the node pages, filled in by the pager.
+    pub page_size: usize,
+    /// Compression of the node pages, filled in by the pager.
+    pub compression: Compression,
+    /// Bincode configuration of the node pages, filled in by the pager.
+    pub encoding: Encoding,
+    /// Where leaves keep their values, filled in by the pager.
+    pub value_storage: ValueStorage,
+    /// Whether the pages are laid out for direct I/O, filled in by the
+    /// pager.
+    pub direct_io: bool,
+    /// Counts the header writes, filled in by the pager. Of the two header
+    /// slots, the one with the higher number holds the newer header.
+    pub sequence: u64,
+}
@@ -0,0 +1,319 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::vec::IntoIter;
+
+use crate::compare::KeyComparator;
+use crate::error::Result;
+use crate::node::{cursor::LeafCursor, leaf::LeafNode, Node};
+use crate::pager::{Offset, PageRead};
+
+/// Lazy iterator over every key/value pair of a tree in ascending key
+/// order. The leftmost leaf is found on the first call to `next`, after
+/// which the iterator walks on one leaf at a time.
+pub struct TreeIter<'a, K, V> {
+    pager: &'a dyn PageRead<K, V>,
+    root_node: Option<Offset>,
+    keys: IntoIter<K>,
+    values: IntoIter<V>,
+    cursor: Option<LeafCursor>,
+}
+
+impl<'a, K: Ord + Clone, V: Clone> TreeIter<'a, K, V> {
+    pub(crate) fn new(pager: &'a dyn PageRead<K, V>, root_node: Option<Offset>) -> Self {
+        Self {
+            pager,
+            root_node,
+            keys: Vec::new().into_iter(),
+            values: Vec::new().into_iter(),
+            cursor: None,
+        }
+    }
+
+    fn load(&mut self, leaf: LeafNode<K, V>) {
+        self.keys = leaf.keys.into_iter();
+        self.values = leaf.values.into_iter();
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for TreeIter<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root_offset) = self.root_node.take() {
+            match LeafCursor::first(self.pager, Some(root_offset)) {
+                Ok((cursor, leaf)) => {
+                    self.cursor = Some(cursor);
+                    self.load(leaf?);
+                },
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+
+        loop {
+            if let (Some(key), Some(value)) = (self.keys.next(), self.values.next()) {
+                return Some(Ok((key, value)));
+            }
+
+            match self.cursor.as_mut()?.next(self.pager) {
+                Ok(Some(leaf)) => self.load(leaf),
+                Ok(None) => return None,
+                Err(err) => {
+                    self.cursor = None;
+                    return Some(Err(err.into()));
+                },
+            }
+        }
+    }
+}
+
+/// Lazy iterator over the pairs of a tree between two bounds in ascending
+/// key order, see [`BPTree::range_iter`](crate::BPTree::range_iter). The
+/// leaf holding the start bound is found on the first call to `next`, after
+/// which the iterator walks on leaf by leaf until it passes the end bound.
+pub struct RangeIter<'a, K, V> {
+    pager: &'a dyn PageRead<K, V>,
+    cmp: &'a dyn KeyComparator<K>,
+    root_node: Option<Offset>,
+    /// Dropped to `Unbounded` once the first key past it is reached.
+    start: Bound<K>,
+    end: Bound<K>,
+    keys: IntoIter<K>,
+    values: IntoIter<V>,
+    cursor: Option<LeafCursor>,
+}
+
+impl<'a, K: Ord + Clone, V: Clone> RangeIter<'a, K, V> {
+    pub(crate) fn new(
+        pager: &'a dyn PageRead<K, V>,
+        cmp: &'a dyn KeyComparator<K>,
+        root_node: Option<Offset>,
+        start: Bound<K>,
+        end: Bound<K>,
+    ) -> Self {
+        Self {
+            pager,
+            cmp,
+            root_node,
+            start,
+            end,
+            keys: Vec::new().into_iter(),
+            values: Vec::new().into_iter(),
+            cursor: None,
+        }
+    }
+
+    fn load(&mut self, leaf: LeafNode<K, V>) {
+        self.keys = leaf.keys.into_iter();
+        self.values = leaf.values.into_iter();
+    }
+
+    fn after_start(&self, key: &K) -> bool {
+        match &self.start {
+            Bound::Included(start) => self.cmp.compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.cmp.compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn before_end(&self, key: &K) -> bool {
+        match &self.end {
+            Bound::Included(end) => self.cmp.compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.cmp.compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for RangeIter<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root_offset) = self.root_node.take() {
+            let start = match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => LeafCursor::seek(self.pager, self.cmp, Some(root_offset), key),
+                Bound::Unbounded => LeafCursor::first(self.pager, Some(root_offset)),
+            };
+            match start {
+                Ok((cursor, leaf)) => {
+                    self.cursor = Some(cursor);
+                    self.load(leaf?);
+                },
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+
+        loop {
+            if let (Some(key), Some(value)) = (self.keys.next(), self.values.next()) {
+                if !self.after_start(&key) {
+                    continue;
+                }
+                self.start = Bound::Unbounded;
+                if !self.before_end(&key) {
+                    self.keys = Vec::new().into_iter();
+                    self.cursor = None;
+                    return None;
+                }
+                return Some(Ok((key, value)));
+            }
+
+            match self.cursor.as_mut()?.next(self.pager) {
+                Ok(Some(leaf)) => self.load(leaf),
+                Ok(None) => return None,
+                Err(err) => {
+                    self.cursor = None;
+                    return Some(Err(err.into()));
+                },
+            }
+        }
+    }
+}
+
+/// Lazy iterator over the keys of a tree in ascending order, see
+/// [`BPTree::keys`](crate::BPTree::keys).
+pub struct Keys<'a, K, V> {
+    inner: TreeIter<'a, K, V>,
+}
+
+impl<'a, K, V> Keys<'a, K, V> {
+    pub(crate) fn new(inner: TreeIter<'a, K, V>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for Keys<'_, K, V> {
+    type Item = Result<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next()?.map(|(key, _)| key))
+    }
+}
+
+/// Lazy iterator over the values of a tree in ascending key order, see
+/// [`BPTree::values`](crate::BPTree::values).
+pub struct Values<'a, K, V> {
+    inner: TreeIter<'a, K, V>,
+}
+
+impl<'a, K, V> Values<'a, K, V> {
+    pub(crate) fn new(inner: TreeIter<'a, K, V>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for Values<'_, K, V> {
+    type Item = Result<V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inner.next()?.map(|(_, value)| value))
+    }
+}
+
+/// Lazy iterator over every key/value pair of a tree in descending key
+/// order. The rightmost leaf is found on the first call to `next`, after
+/// which the iterator walks back one leaf at a time.
+pub struct TreeIterRev<'a, K, V> {
+    pager: &'a dyn PageRead<K, V>,
+    root_node: Option<Offset>,
+    keys: IntoIter<K>,
+    values: IntoIter<V>,
+    cursor: Option<LeafCursor>,
+}
+
+impl<'a, K: Ord + Clone, V: Clone> TreeIterRev<'a, K, V> {
+    pub(crate) fn new(pager: &'a dyn PageRead<K, V>, root_node: Option<Offset>) -> Self {
+        Self {
+            pager,
+            root_node,
+            keys: Vec::new().into_iter(),
+            values: Vec::new().into_iter(),
+            cursor: None,
+        }
+    }
+
+    fn load(&mut self, leaf: LeafNode<K, V>) {
+        self.keys = leaf.keys.into_iter();
+        self.values = leaf.values.into_iter();
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for TreeIterRev<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root_offset) = self.root_node.take() {
+            match LeafCursor::last(self.pager, Some(root_offset)) {
+                Ok((cursor, leaf)) => {
+                    self.cursor = Some(cursor);
+                    self.load(leaf?);
+                },
+                Err(err) => return Some(Err(err.into())),
+            }
+        }
+
+        loop {
+            if let (Some(key), Some(value)) = (self.keys.next_back(), self.values.next_back()) {
+                return Some(Ok((key, value)));
+            }
+
+            match self.cursor.as_mut()?.prev(self.pager) {
+                Ok(Some(leaf)) => self.load(leaf),
+                Ok(None) => return None,
+                Err(err) => {
+                    self.cursor = None;
+                    return Some(Err(err.into()));
+                },
+            }
+        }
+    }
+}
+
+/// Lazy iterator over a tree version in ascending key order, visiting its
+/// nodes depth first from the root it started from.
+pub struct SnapshotIter<'a, K, V> {
+    pager: &'a dyn PageRead<K, V>,
+    /// Children still to visit, one entry per level of the descent.
+    stack: Vec<IntoIter<Offset>>,
+    keys: IntoIter<K>,
+    values: IntoIter<V>,
+}
+
+impl<'a, K: Ord + Clone, V: Clone> SnapshotIter<'a, K, V> {
+    pub(crate) fn new(pager: &'a dyn PageRead<K, V>, root_node: Option<Offset>) -> Self {
+        Self {
+            pager,
+            stack: vec![root_node.into_iter().collect::<Vec<_>>().into_iter()],
+            keys: Vec::new().into_iter(),
+            values: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for SnapshotIter<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let (Some(key), Some(value)) = (self.keys.next(), self.values.next()) {
+                return Some(Ok((key, value)));
+            }
+
+            let children = self.stack.last_mut()?;
+            let Some(offset) = children.next() else {
+                self.stack.pop();
+                continue;
+            };
+
+            match self.pager.read_at(offset) {
+                Ok(Node::Leaf(leaf)) => {
+                    self.keys = leaf.keys.into_iter();
+                    self.values = leaf.values.into_iter();
+                },
+                Ok(Node::Internal(internal)) => self.stack.push(internal.children.into_iter()),
+                Err(err) => {
+                    self.stack.clear();
+                    return Some(Err(err.into()));
+                },
+            }
+        }
+    }
+}
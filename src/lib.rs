@@ -1,3 +1,28 @@
+pub mod builder;
+pub mod compare;
+pub mod entry;
+mod error;
+mod header;
+pub mod iter;
+pub mod multi;
 mod node;
 mod pager;
+pub mod reader;
+pub mod shared;
+pub mod snapshot;
 pub mod tree;
+pub mod txn;
+
+pub use compare::{KeyComparator, OrdComparator, ShortSeparators};
+pub use entry::Entry;
+pub use error::{BPTreeError, Result};
+pub use header::Header;
+pub use node::internal::InternalNode;
+pub use node::leaf::LeafNode;
+pub use node::{Node, SplitBias};
+pub use pager::cache::CacheStats;
+pub use pager::memory::MemoryPager;
+pub use pager::{Compression, DynOperator, Encoding, Endian, IntEncoding, IoStats, Offset, PageOperator, PageRead, ReadWriteSeek, SharedPages, ValueStorage, DEFAULT_PAGE_SIZE, MIN_PAGE_SIZE};
+pub use reader::TreeReader;
+pub use shared::SharedBPTree;
+pub use tree::{BPTree, CompactStats, DeleteMode, FlushPolicy, Key, Value, TreeOptions, MIN_DEGREE};
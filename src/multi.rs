@@ -0,0 +1,185 @@
+use std::ops::Bound;
+
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::pager::{PageOperator, ReadWriteSeek};
+use super::tree::{BPTree, Key, Value};
+
+/// Position of a value among the values stored under the same key.
+type Sequence = u64;
+
+/// Tree that keeps every value inserted under a key instead of replacing
+/// it, e.g. for secondary indexes.
+///
+/// Entries are stored in a regular [`BPTree`] under `(key, sequence)`
+/// pairs, where the sequence grows with each insert of the same key. The
+/// composite keys are unique, so a run of equal keys is split across leaves
+/// like any other keys and is read back with one range scan in insertion
+/// order.
+pub struct BPTreeMulti<K = Key, V = Value> {
+    tree: BPTree<(K, Sequence), V>,
+}
+
+impl<K, V> BPTreeMulti<K, V>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + PartialEq + Encode + Decode + 'static,
+{
+    /// Creates an empty multimap over `file`, see [`BPTree::new`].
+    pub fn new(degree: usize, startup_offset: usize, file: impl ReadWriteSeek + 'static) -> Result<Self> {
+        Ok(Self {
+            tree: BPTree::new(degree, startup_offset, file)?,
+        })
+    }
+
+    /// Creates an empty multimap over any page operator.
+    pub fn with_pager(degree: usize, pager: Box<dyn PageOperator<(K, Sequence), V>>) -> Result<Self> {
+        Ok(Self {
+            tree: BPTree::with_pager(degree, pager)?,
+        })
+    }
+
+    /// Reopens a multimap previously written to `file`.
+    pub fn open(file: impl ReadWriteSeek + 'static) -> Result<Self> {
+        Ok(Self {
+            tree: BPTree::open(file)?,
+        })
+    }
+
+    /// Adds `value` after every value already stored under `key`. The last
+    /// of them is found with one lookup, however many there are.
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        let sequence = match self.tree.floor((key.clone(), Sequence::MAX))? {
+            Some(((last_key, sequence), _)) if last_key == key => sequence + 1,
+            _ => 0,
+        };
+        // Sequences stay below `Sequence::MAX`, which bounds the runs
+        // removed by `delete`.
+        if sequence == Sequence::MAX {
+            return Err(anyhow::anyhow!("insert: no sequence left under the key").into());
+        }
+        self.tree.insert((key, sequence), value)
+    }
+
+    /// Returns every value stored under `key` in insertion order.
+    pub fn search(&self, key: K) -> Result<Vec<V>> {
+        Ok(self.entries(&key)?.into_iter().map(|(_, value)| value).collect())
+    }
+
+    /// Removes every value stored under `key` and returns them in insertion
+    /// order.
+    pub fn delete(&mut self, key: K) -> Result<Vec<V>> {
+        let removed = self.search(key.clone())?;
+        if !removed.is_empty() {
+            self.tree.delete_range((key.clone(), Sequence::MIN), (key, Sequence::MAX))?;
+        }
+        Ok(removed)
+    }
+
+    /// Removes the first occurrence of `value` under `key`. Returns `false`
+    /// if there was none.
+    pub fn delete_value(&mut self, key: K, value: &V) -> Result<bool> {
+        let entry = self.entries(&key)?.into_iter().find(|(_, stored)| stored == value);
+        match entry {
+            None => Ok(false),
+            Some((entry_key, _)) => Ok(self.tree.delete(entry_key)?.is_some()),
+        }
+    }
+
+    /// Number of values stored across all keys.
+    pub fn len(&self) -> Result<usize> {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.tree.is_empty()
+    }
+
+    /// Rewrites the header page and syncs the underlying storage.
+    pub fn flush(&mut self) -> Result<()> {
+        self.tree.flush()
+    }
+
+    fn entries(&self, key: &K) -> Result<Vec<((K, Sequence), V)>> {
+        self.tree.range(
+            Bound::Included((key.clone(), Sequence::MIN)),
+            Bound::Included((key.clone(), Sequence::MAX)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::pager::memory::MemoryPager;
+    use crate::pager::STARTUP_OFFSET;
+
+    use super::*;
+
+    #[test]
+    fn multimap_works() -> anyhow::Result<()> {
+        let mut tree = BPTreeMulti::with_pager(4, Box::new(MemoryPager::new()))?;
+
+        // Enough values under one key to span several leaves.
+        for i in 0..20 {
+            tree.insert("b".to_string(), i.to_string().into_bytes())?;
+        }
+        tree.insert("a".to_string(), b"a".to_vec())?;
+        tree.insert("c".to_string(), b"c".to_vec())?;
+        tree.insert("b".to_string(), b"3".to_vec())?;
+
+        let expected = (0..20).map(|i| i.to_string().into_bytes()).chain([b"3".to_vec()]).collect::<Vec<_>>();
+        assert_eq!(tree.search("b".to_string())?, expected);
+        assert_eq!(tree.search("a".to_string())?, vec![b"a".to_vec()]);
+        assert!(tree.search("d".to_string())?.is_empty());
+        assert_eq!(tree.len()?, 23);
+
+        assert!(tree.delete_value("b".to_string(), &b"3".to_vec())?);
+        assert!(!tree.delete_value("b".to_string(), &b"missing".to_vec())?);
+        let values = tree.search("b".to_string())?;
+        assert_eq!(values.len(), 20);
+        assert_eq!(values.iter().filter(|value| *value == b"3").count(), 1);
+
+        assert_eq!(tree.delete("b".to_string())?.len(), 20);
+        assert!(tree.search("b".to_string())?.is_empty());
+        assert_eq!(tree.len()?, 2);
+        assert!(tree.delete("b".to_string())?.is_empty());
+
+        // Neighbouring keys are left alone and a deleted key starts over.
+        tree.insert("b".to_string(), b"again".to_vec())?;
+        tree.insert("b".to_string(), b"more".to_vec())?;
+        assert_eq!(tree.search("b".to_string())?, vec![b"again".to_vec(), b"more".to_vec()]);
+        assert_eq!(tree.search("a".to_string())?, vec![b"a".to_vec()]);
+        assert_eq!(tree.search("c".to_string())?, vec![b"c".to_vec()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn multimap_survives_reopen() -> anyhow::Result<()> {
+        let path = "/tmp/multimap_survives_reopen.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTreeMulti::new(4, STARTUP_OFFSET, file)?;
+        tree.insert("key".to_string(), b"first".to_vec())?;
+        tree.insert("key".to_string(), b"second".to_vec())?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTreeMulti::<Key, Value>::open(file)?;
+        tree.insert("key".to_string(), b"third".to_vec())?;
+        assert_eq!(
+            tree.search("key".to_string())?,
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+
+        Ok(())
+    }
+}
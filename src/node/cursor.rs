@@ -0,0 +1,107 @@
+use super::{leaf::LeafNode, internal::InternalNode, Node};
+use crate::compare::{self, KeyComparator};
+use crate::pager::{Offset, PageRead};
+
+/// Position of a leaf within one version of the tree: the children of
+/// every internal node on the way down from the root and the one taken.
+///
+/// Leaves hold no links to their neighbours, as copy-on-write would have to
+/// copy a neighbour every time a leaf moved, and that neighbour's neighbour
+/// in turn. Stepping to the next or previous leaf climbs to the nearest
+/// node with a child on that side instead and descends its near edge, so a
+/// walk never leaves the version whose root it started from.
+pub(crate) struct LeafCursor {
+    stack: Vec<(Vec<Offset>, usize)>,
+}
+
+impl LeafCursor {
+    /// Descends to the leftmost leaf of the version at `root_node`.
+    pub(crate) fn first<K, V>(
+        pager: &dyn PageRead<K, V>,
+        root_node: Option<Offset>,
+    ) -> anyhow::Result<(Self, Option<LeafNode<K, V>>)> {
+        Self::start(pager, root_node, |_| 0)
+    }
+
+    /// Descends to the rightmost leaf of the version at `root_node`.
+    pub(crate) fn last<K, V>(
+        pager: &dyn PageRead<K, V>,
+        root_node: Option<Offset>,
+    ) -> anyhow::Result<(Self, Option<LeafNode<K, V>>)> {
+        Self::start(pager, root_node, |internal_node| internal_node.children.len().wrapping_sub(1))
+    }
+
+    /// Descends to the leaf that would hold `key`.
+    pub(crate) fn seek<K, V>(
+        pager: &dyn PageRead<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        root_node: Option<Offset>,
+        key: &K,
+    ) -> anyhow::Result<(Self, Option<LeafNode<K, V>>)> {
+        Self::start(pager, root_node, |internal_node| {
+            compare::search(cmp, &internal_node.keys, key).unwrap_or_else(|pos| pos)
+        })
+    }
+
+    /// Steps to the leaf after the current one, or returns `None` past the
+    /// rightmost leaf.
+    pub(crate) fn next<K, V>(&mut self, pager: &dyn PageRead<K, V>) -> anyhow::Result<Option<LeafNode<K, V>>> {
+        while let Some((children, position)) = self.stack.last_mut() {
+            if *position + 1 < children.len() {
+                *position += 1;
+                let offset = children[*position];
+                return self.descend(pager, offset, |_| 0).map(Some);
+            }
+            self.stack.pop();
+        }
+        Ok(None)
+    }
+
+    /// Steps to the leaf before the current one, or returns `None` past the
+    /// leftmost leaf.
+    pub(crate) fn prev<K, V>(&mut self, pager: &dyn PageRead<K, V>) -> anyhow::Result<Option<LeafNode<K, V>>> {
+        while let Some((children, position)) = self.stack.last_mut() {
+            if *position > 0 {
+                *position -= 1;
+                let offset = children[*position];
+                return self.descend(pager, offset, |internal_node| internal_node.children.len().wrapping_sub(1)).map(Some);
+            }
+            self.stack.pop();
+        }
+        Ok(None)
+    }
+
+    fn start<K, V>(
+        pager: &dyn PageRead<K, V>,
+        root_node: Option<Offset>,
+        pick: impl Fn(&InternalNode<K>) -> usize,
+    ) -> anyhow::Result<(Self, Option<LeafNode<K, V>>)> {
+        let mut cursor = Self { stack: Vec::new() };
+        let leaf = match root_node {
+            None => None,
+            Some(root_offset) => Some(cursor.descend(pager, root_offset, pick)?),
+        };
+        Ok((cursor, leaf))
+    }
+
+    /// Descends from the node at `offset` to a leaf, taking the child
+    /// `pick` chooses on every level. A position the node has no child at
+    /// fails the descent.
+    fn descend<K, V>(
+        &mut self,
+        pager: &dyn PageRead<K, V>,
+        mut offset: Offset,
+        pick: impl Fn(&InternalNode<K>) -> usize,
+    ) -> anyhow::Result<LeafNode<K, V>> {
+        loop {
+            match pager.read_at(offset)? {
+                Node::Leaf(leaf_node) => return Ok(leaf_node),
+                Node::Internal(internal_node) => {
+                    let position = pick(&internal_node);
+                    offset = internal_node.child(position)?;
+                    self.stack.push((internal_node.children, position));
+                },
+            }
+        }
+    }
+}
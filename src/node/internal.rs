@@ -1,103 +1,498 @@
-use bincode::{Decode, Encode};
-use super::Node;
-use crate::tree::{Key, Value};
-use crate::pager::{PageOperator, Offset};
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::io::Write;
 
+use bincode::{Decode, Encode};
+use super::{leaf::chunk_sizes, min_keys, Change, Node, SplitBias, Update};
+use crate::compare::{self, KeyComparator};
+use crate::pager::{PageOperator, PageRead, Offset};
+
+/// `keys[i]` separates `children[i]` from `children[i + 1]`: every key
+/// below the first orders at or before it and every key below the second
+/// after it. A separator needn't be a key of the tree, see
+/// [`KeyComparator::separator`].
 #[derive(Clone, Debug, Encode, Decode)]
-pub(crate) struct InternalNode {
-    pub keys: Vec<Key>,
+#[bincode(encode_bounds = "K: Encode + 'static", decode_bounds = "K: Decode + 'static")]
+pub struct InternalNode<K> {
+    pub keys: Vec<K>,
     pub children: Vec<Offset>,
     pub offset: Option<Offset>,
 }
 
-impl InternalNode {
-    pub(crate) fn insert(
+impl<K> InternalNode<K> {
+    /// Offset of the child at `position`. A corrupted page may decode to a
+    /// node with fewer children than its keys call for, or none, which is
+    /// an error rather than an index out of bounds.
+    pub(crate) fn child(&self, position: usize) -> anyhow::Result<Offset> {
+        match self.children.get(position) {
+            Some(child_offset) => Ok(*child_offset),
+            None => anyhow::bail!("internal node {:?} has no child {position}, only {}", self.offset, self.children.len()),
+        }
+    }
+
+    /// Offset of the last child, failing like [`InternalNode::child`] for a
+    /// node without children.
+    pub(crate) fn last_child(&self) -> anyhow::Result<Offset> {
+        self.child(self.children.len().wrapping_sub(1))
+    }
+}
+
+impl<K: Ord + Clone> InternalNode<K> {
+    pub(crate) fn insert<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
-        key: Key,
-        value: Value,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        value: V,
         degree: usize,
-    ) -> anyhow::Result<Option<(Key, Node)>> {
-        let position = self.keys.binary_search(&key).unwrap_or_else(|pos| pos);
+        bias: SplitBias,
+    ) -> anyhow::Result<Option<(K, Node<K, V>)>> {
+        let position = compare::search(cmp, &self.keys, &key).unwrap_or_else(|pos| pos);
         let child_offset = self.children[position];
-        let mut child_node = pager.read(child_offset)?;
-        let child_node_copy_offset = pager.write(&child_node)?;
+        let mut child_node = pager.read_at(child_offset)?;
+        let child_node_copy_offset = child_node.relocate(pager)?;
         self.children[position] = child_node_copy_offset;
 
-        let is_splitted = child_node.insert(pager, key, value, degree)?;
+        let child_bias = bias.for_child(position, self.children.len());
+        let is_splitted = child_node.insert(pager, cmp, key, value, degree, child_bias)?;
         pager.write_at(&child_node, child_node_copy_offset)?;
 
         match is_splitted {
             None => Ok(None),
             Some((mid_key, sibling)) => {
-                let sibling_offset = pager.write(&sibling)?;
-                self.keys.insert(position, mid_key);
-                self.children.insert(position + 1, sibling_offset);
+                let sibling_offset = sibling.write_sibling(pager)?;
+                Ok(self.adopt(pager, position, mid_key, sibling_offset, degree, bias))
+            },
+        }
+    }
 
-                if self.keys.len() > degree - 1 {
-                    Ok(Some(self.split(pager)))
+    pub(crate) fn update<V: Clone, F>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        f: F,
+        degree: usize,
+        bias: SplitBias,
+    ) -> anyhow::Result<Update<K, V>>
+    where
+        F: FnOnce(Option<&V>) -> Change<V>,
+    {
+        let position = compare::search(cmp, &self.keys, &key).unwrap_or_else(|pos| pos);
+        let mut child_node = pager.read_at(self.children[position])?;
+
+        let child_bias = bias.for_child(position, self.children.len());
+        match child_node.update(pager, cmp, key, f, degree, child_bias)? {
+            Update::Untouched => Ok(Update::Untouched),
+            Update::Unchanged => {
+                self.replace_child(pager, position, &mut child_node)?;
+                Ok(Update::Unchanged)
+            },
+            Update::Split(mid_key, sibling) => {
+                let sibling_offset = sibling.write_sibling(pager)?;
+                self.replace_child(pager, position, &mut child_node)?;
+                match self.adopt(pager, position, mid_key, sibling_offset, degree, bias) {
+                    None => Ok(Update::Unchanged),
+                    Some((mid_key, sibling)) => Ok(Update::Split(mid_key, sibling)),
+                }
+            },
+            Update::Removed(need_rebalance) => {
+                self.replace_child(pager, position, &mut child_node)?;
+                if need_rebalance {
+                    Ok(Update::Removed(self.rebalance(pager, position, &mut child_node, degree)?))
                 } else {
-                    Ok(None)
+                    Ok(Update::Removed(false))
                 }
             },
         }
     }
 
-    fn split(&mut self, pager: &mut Box<dyn PageOperator>) -> (Key, Node) {
-        let split_index = self.keys.len() / 2;
+    /// Moves the changed child at `position` to a fresh page and writes it
+    /// there.
+    fn replace_child<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        position: usize,
+        child_node: &mut Node<K, V>,
+    ) -> anyhow::Result<()> {
+        let child_node_copy_offset = child_node.relocate(pager)?;
+        self.children[position] = child_node_copy_offset;
+        pager.write_at(child_node, child_node_copy_offset)
+    }
+
+    /// Links in the sibling written at `sibling_offset` after the child at
+    /// `position`, splitting this node in turn if it overflows.
+    fn adopt<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        position: usize,
+        mid_key: K,
+        sibling_offset: Offset,
+        degree: usize,
+        bias: SplitBias,
+    ) -> Option<(K, Node<K, V>)> {
+        self.keys.insert(position, mid_key);
+        self.children.insert(position + 1, sibling_offset);
+
+        if self.keys.len() > degree - 1 {
+            let at_right_edge = position + 2 == self.children.len();
+            Some(self.split(pager, bias.keys_kept(self.keys.len(), true, at_right_edge)))
+        } else {
+            None
+        }
+    }
+
+    /// Routes a batch of pairs sorted by key to the children that should
+    /// hold them, copying each touched child once. Returns the written
+    /// siblings this node was split into, if it overflowed.
+    pub(crate) fn insert_batch<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        pairs: Vec<(K, V)>,
+        degree: usize,
+    ) -> anyhow::Result<Vec<(K, Offset)>> {
+        let mut groups: Vec<(usize, Vec<(K, V)>)> = Vec::new();
+        for (key, value) in pairs {
+            let position = compare::search(cmp, &self.keys, &key).unwrap_or_else(|pos| pos);
+            match groups.last_mut() {
+                Some((last_position, group)) if *last_position == position => group.push((key, value)),
+                _ => groups.push((position, vec![(key, value)])),
+            }
+        }
+
+        // Later children first, so inserting separators doesn't shift the
+        // positions of the groups that are still pending.
+        for (position, group) in groups.into_iter().rev() {
+            let mut child_node = pager.read_at(self.children[position])?;
+            let child_node_copy_offset = child_node.relocate(pager)?;
+            self.children[position] = child_node_copy_offset;
+
+            let siblings = child_node.insert_batch(pager, cmp, group, degree)?;
+            pager.write_at(&child_node, child_node_copy_offset)?;
+
+            for (i, (separator, sibling_offset)) in siblings.into_iter().enumerate() {
+                self.keys.insert(position + i, separator);
+                self.children.insert(position + i + 1, sibling_offset);
+            }
+        }
+
+        if self.keys.len() > degree - 1 {
+            self.split_many(pager, degree)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Splits an overflowing node into as many siblings as needed, writing
+    /// every sibling and returning it with the key promoted in front of it.
+    pub(crate) fn split_many<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        degree: usize,
+    ) -> anyhow::Result<Vec<(K, Offset)>> {
+        let sizes = chunk_sizes(self.children.len(), degree);
+        let mut keys = std::mem::take(&mut self.keys).into_iter();
+        let mut children = std::mem::take(&mut self.children).into_iter();
+
+        self.children = children.by_ref().take(sizes[0]).collect();
+        self.keys = keys.by_ref().take(sizes[0] - 1).collect();
+
+        let mut siblings = Vec::with_capacity(sizes.len() - 1);
+        for size in &sizes[1..] {
+            let Some(separator) = keys.next() else {
+                anyhow::bail!("split_many: node {:?} ran out of separator keys", self.offset);
+            };
+
+            let offset = pager.allocate();
+            let sibling = InternalNode {
+                keys: keys.by_ref().take(size - 1).collect(),
+                children: children.by_ref().take(*size).collect(),
+                offset: Some(offset),
+            };
+            pager.write_at(&Node::Internal(sibling), offset)?;
+            siblings.push((separator, offset));
+        }
+
+        Ok(siblings)
+    }
+
+    /// Splits the node, keeping the first `split_index` keys and promoting
+    /// the one after them.
+    fn split<V: Clone>(&mut self, pager: &mut dyn PageOperator<K, V>, split_index: usize) -> (K, Node<K, V>) {
         let mut sibling_keys = self.keys.split_off(split_index);
         let median_key = sibling_keys.remove(0);
 
         let new_internal_node = InternalNode {
             keys: sibling_keys,
             children: self.children.split_off(split_index + 1),
-            offset: Some(pager.next_offset()),
+            offset: Some(pager.allocate()),
         };
 
         (median_key, Node::Internal(new_internal_node))
     }
 
-    pub(crate) fn remove(&mut self, pager: &mut Box<dyn PageOperator>, key: Key, degree: usize) -> anyhow::Result<Option<bool>> {
-        let position = self.keys.binary_search(&key).unwrap_or_else(|pos| pos);
-        let child_offset = self.children[position];
-        let mut child_node = pager.read(child_offset)?;
-        let child_node_copy_offset = pager.write(&child_node)?;
-        self.children[position] = child_node_copy_offset;
+    pub(crate) fn remove<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        degree: usize,
+    ) -> anyhow::Result<Option<(V, bool)>> {
+        let position = compare::search(cmp, &self.keys, &key).unwrap_or_else(|pos| pos);
+        let mut child_node = pager.read_at(self.children[position])?;
+
+        // The child is only copied once the key was found in it, so removing
+        // an absent key leaves every page on the path untouched.
+        let Some((value, need_rebalance)) = child_node.remove(pager, cmp, key, degree)? else {
+            return Ok(None);
+        };
+        self.replace_child(pager, position, &mut child_node)?;
 
-        match child_node.remove(pager, key, degree)? {
-            None => Ok(None),
-            Some(need_rebalance) => {
-                pager.write_at(&child_node, child_node_copy_offset)?;
+        if need_rebalance {
+            Ok(Some((value, self.rebalance(pager, position, &mut child_node, degree)?)))
+        } else {
+            Ok(Some((value, false)))
+        }
+    }
 
-                if need_rebalance {
-                    Ok(Some(self.rebalance(pager, position, &mut child_node, degree)?))
-                } else {
-                    Ok(Some(false))
+    /// Releases the children below the one that would hold `key` with
+    /// their whole subtrees and truncates that child in turn.
+    pub(crate) fn truncate_below<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: &K,
+    ) -> anyhow::Result<(usize, bool)> {
+        let position = compare::search(cmp, &self.keys, key).unwrap_or_else(|pos| pos);
+        let mut removed = 0;
+        for child_offset in self.children.drain(..position) {
+            removed += release(pager, child_offset)?;
+        }
+        self.keys.drain(..position);
+
+        let mut child_node = pager.read_at(self.children[0])?;
+        let (child_removed, child_changed) = child_node.truncate_below(pager, cmp, key)?;
+        if child_changed {
+            self.replace_child(pager, 0, &mut child_node)?;
+        }
+        Ok((removed + child_removed, position > 0 || child_changed))
+    }
+
+    /// Borrows for or merges the first child until it holds the minimum
+    /// number of keys again, after a truncation left it short by any
+    /// number of them, doing the same further down the left edge first.
+    /// A node with a single child is left to its own parent to fix. A
+    /// merge can leave an untouched sibling of the committed tree as the
+    /// first child, so a child that changes is moved to a fresh page like
+    /// any other. Returns whether the node changed; the caller moves a
+    /// changed node.
+    pub(crate) fn fix_first_child<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        degree: usize,
+    ) -> anyhow::Result<bool> {
+        let mut changed = false;
+        loop {
+            let mut child_node = pager.read_at(self.children[0])?;
+            if let Node::Internal(internal_node) = &mut child_node {
+                if internal_node.fix_first_child(pager, degree)? {
+                    self.replace_child(pager, 0, &mut child_node)?;
+                    changed = true;
                 }
+            }
+
+            if !child_node.underflows(degree) || self.children.len() == 1 {
+                return Ok(changed);
+            }
+            self.rebalance(pager, 0, &mut child_node, degree)?;
+            changed = true;
+        }
+    }
+
+    /// Borrows for or merges every underflowing node below this one, such
+    /// as the leaves that deferred deletes left short. Each child's subtree
+    /// is fixed before the child itself, and only the nodes that change are
+    /// copied. A node with a single child is left to its own parent to fix.
+    /// Returns whether the node changed; the caller moves a changed node.
+    pub(crate) fn fix_underflows<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        degree: usize,
+    ) -> anyhow::Result<bool> {
+        let mut changed = false;
+        for position in 0..self.children.len() {
+            let mut child_node = pager.read_at(self.children[position])?;
+            if let Node::Internal(internal_node) = &mut child_node {
+                if internal_node.fix_underflows(pager, degree)? {
+                    self.replace_child(pager, position, &mut child_node)?;
+                    changed = true;
+                }
+            }
+        }
+
+        Ok(self.fix_children(pager, degree)? || changed)
+    }
+
+    /// Removes every key of `keys`, which are in ascending order, from the
+    /// subtree. Only the children some of the keys route to are read, each
+    /// once, and the children left short are rebalanced once all of them
+    /// are done. Returns how many keys were removed; the caller moves a
+    /// node that had any removed.
+    pub(crate) fn remove_sorted<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        mut keys: &[K],
+        degree: usize,
+    ) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for position in 0..self.children.len() {
+            let routed = match self.keys.get(position) {
+                Some(separator) => keys.partition_point(|key| cmp.compare(key, separator) != Ordering::Greater),
+                None => keys.len(),
+            };
+            let (child_keys, rest) = keys.split_at(routed);
+            keys = rest;
+            if child_keys.is_empty() {
+                continue;
+            }
+
+            let mut child_node = pager.read_at(self.children[position])?;
+            let child_removed = child_node.remove_sorted(pager, cmp, child_keys, degree)?;
+            if child_removed > 0 {
+                self.replace_child(pager, position, &mut child_node)?;
+                removed += child_removed;
+            }
+        }
+
+        if removed > 0 {
+            self.fix_children(pager, degree)?;
+        }
+        Ok(removed)
+    }
+
+    /// Removes every key in `[start, end)`, or from `start` on without an
+    /// `end`, from the subtree. The children lying wholly inside the range
+    /// are released with their subtrees, and only the children the bounds
+    /// fall in are descended into and copied. The children left short are
+    /// rebalanced once both are done.
+    /// Returns how many keys were removed; the caller moves a node that
+    /// had any removed.
+    pub(crate) fn remove_range<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        start: &K,
+        end: Option<&K>,
+        degree: usize,
+    ) -> anyhow::Result<usize> {
+        let first = compare::search(cmp, &self.keys, start).unwrap_or_else(|pos| pos);
+        let mut removed = 0;
+        let last = match end {
+            Some(end) => compare::search(cmp, &self.keys, end).unwrap_or_else(|pos| pos),
+            // Without an end every child after the first lies inside.
+            None => {
+                for child_offset in self.children.drain(first + 1..) {
+                    removed += release(pager, child_offset)?;
+                }
+                self.keys.truncate(first);
+                first
             },
+        };
+        let last = if last > first + 1 {
+            for child_offset in self.children.drain(first + 1..last) {
+                removed += release(pager, child_offset)?;
+            }
+            // The separator above the last child still parts it from the
+            // first one.
+            self.keys.drain(first..last - 1);
+            first + 1
+        } else {
+            last
+        };
+
+        for position in (first..=last).rev() {
+            let mut child_node = pager.read_at(self.children[position])?;
+            let child_removed = child_node.remove_range(pager, cmp, start, end, degree)?;
+            if child_removed > 0 {
+                self.replace_child(pager, position, &mut child_node)?;
+                removed += child_removed;
+            }
+        }
+
+        if removed > 0 {
+            self.fix_children(pager, degree)?;
+        }
+        Ok(removed)
+    }
+
+    /// Borrows for or merges every child short of the minimum, however
+    /// far short it is. Returns whether the node changed.
+    fn fix_children<V: Clone>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        degree: usize,
+    ) -> anyhow::Result<bool> {
+        let mut changed = false;
+        // A merge can leave the merged child short in turn, and a borrow
+        // may not be enough for a child that was far below the minimum, so
+        // the child before the one just fixed is looked at again.
+        let mut position = 0;
+        while position < self.children.len() && self.children.len() > 1 {
+            let mut child_node = pager.read_at(self.children[position])?;
+            if !child_node.underflows(degree) {
+                position += 1;
+                continue;
+            }
+
+            self.replace_child(pager, position, &mut child_node)?;
+            self.rebalance(pager, position, &mut child_node, degree)?;
+            changed = true;
+            position = position.saturating_sub(1);
+
+            // An internal node with a single child can't fix that child, so
+            // once a rebalance moved it, as the child or as the sibling,
+            // the node it ended up in has to.
+            if matches!(child_node, Node::Internal(_)) {
+                for merged in position..(position + 2).min(self.children.len()) {
+                    let mut merged_node = pager.read_at(self.children[merged])?;
+                    if let Node::Internal(internal_node) = &mut merged_node {
+                        if internal_node.fix_children(pager, degree)? {
+                            self.replace_child(pager, merged, &mut merged_node)?;
+                        }
+                    }
+                }
+            }
         }
+        Ok(changed)
     }
 
-    fn rebalance(
+    /// Borrows for or merges the underflowing child at
+    /// `child_offset_position`. Only the sibling that ends up changed is
+    /// copied; a right sibling merged into the child is released as is.
+    fn rebalance<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
+        pager: &mut dyn PageOperator<K, V>,
         child_offset_position: usize,
-        child_node: &mut Node,
+        child_node: &mut Node<K, V>,
         degree: usize
     ) -> anyhow::Result<bool> {
         let child_offset = self.children[child_offset_position];
 
+        let mut left_sibling = None;
         if child_offset_position > 0 {
-            let left_sibling_offset = self.children[child_offset_position - 1];
-            let mut left_sibling = pager.read(left_sibling_offset)?;
-            let left_sibling_copy_offset = pager.write(&left_sibling)?;
-            self.children[child_offset_position - 1] = left_sibling_copy_offset;
+            let mut sibling = pager.read_at(self.children[child_offset_position - 1])?;
+            if !sibling.can_borrow(degree) {
+                left_sibling = Some(sibling);
+            } else {
+                let left_sibling_copy_offset = sibling.relocate(pager)?;
+                self.children[child_offset_position - 1] = left_sibling_copy_offset;
 
-            if left_sibling.can_borrow(degree) {
                 self.borrow_left(
                     pager,
                     child_offset_position,
-                    &mut left_sibling,
+                    &mut sibling,
                     left_sibling_copy_offset,
                     child_node,
                     child_offset
@@ -106,17 +501,19 @@ impl InternalNode {
             }
         }
 
-        if child_offset_position < self.children.len() - 1 {
-            let right_sibling_offset = self.children[child_offset_position + 1];
-            let mut right_sibling = pager.read(right_sibling_offset)?;
-            let right_sibling_copy_offset = pager.write(&right_sibling)?;
-            self.children[child_offset_position + 1] = right_sibling_copy_offset;
+        let mut right_sibling = None;
+        if child_offset_position + 1 < self.children.len() {
+            let mut sibling = pager.read_at(self.children[child_offset_position + 1])?;
+            if !sibling.can_borrow(degree) {
+                right_sibling = Some(sibling);
+            } else {
+                let right_sibling_copy_offset = sibling.relocate(pager)?;
+                self.children[child_offset_position + 1] = right_sibling_copy_offset;
 
-            if right_sibling.can_borrow(degree) {
                 self.borrow_right(
                     pager,
                     child_offset_position,
-                    &mut right_sibling,
+                    &mut sibling,
                     right_sibling_copy_offset,
                     child_node,
                     child_offset,
@@ -125,55 +522,57 @@ impl InternalNode {
             }
         }
 
-        if child_offset_position > 0 {
-            let left_sibling_offset = self.children[child_offset_position - 1];
-            let mut left_sibling = pager.read(left_sibling_offset)?;
-            let left_sibling_copy_offset = pager.write(&left_sibling)?;
-            self.children[child_offset_position - 1] = left_sibling_copy_offset;
-
-            self.merge_left(
-                pager,
-                child_offset_position,
-                &mut left_sibling,
-                left_sibling_copy_offset,
-                child_node,
-                child_offset
-            )?;
-        } else {
-            let right_sibling_offset = self.children[child_offset_position + 1];
-            let mut right_sibling = pager.read(right_sibling_offset)?;
-            let right_sibling_copy_offset = pager.write(&right_sibling)?;
-            self.children[child_offset_position + 1] = right_sibling_copy_offset;
-
-            self.merge_right(
-                pager,
-                child_offset_position,
-                &mut right_sibling,
-                right_sibling_copy_offset,
-                child_node,
-                child_offset,
-            )?;
+        match (left_sibling, right_sibling) {
+            (Some(mut left_sibling), _) => {
+                let left_sibling_copy_offset = left_sibling.relocate(pager)?;
+                self.children[child_offset_position - 1] = left_sibling_copy_offset;
+
+                self.merge_left(
+                    pager,
+                    child_offset_position,
+                    &mut left_sibling,
+                    left_sibling_copy_offset,
+                    child_node,
+                    child_offset
+                )?;
+            },
+            (None, Some(mut right_sibling)) => {
+                let right_sibling_offset = self.children[child_offset_position + 1];
+                self.merge_right(
+                    pager,
+                    child_offset_position,
+                    &mut right_sibling,
+                    right_sibling_offset,
+                    child_node,
+                    child_offset,
+                )?;
+            },
+            (None, None) => anyhow::bail!(
+                "rebalance: node {:?} has no sibling for child {}",
+                self.offset,
+                child_offset_position
+            ),
         }
 
-        Ok(self.keys.len() < (degree / 2))
+        Ok(self.keys.len() < min_keys(degree))
     }
 
-    fn borrow_left(
+    fn borrow_left<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
+        pager: &mut dyn PageOperator<K, V>,
         index: usize,
-        left_sibling: &mut Node,
+        left_sibling: &mut Node<K, V>,
         left_sibling_offset: Offset,
-        child_node: &mut Node,
+        child_node: &mut Node<K, V>,
         child_offset: Offset,
     ) -> anyhow::Result<()> {
         match (left_sibling, child_node) {
             (Node::Internal(ref mut sibling), Node::Internal(ref mut current)) => {
-                let borrowed_key = sibling.keys.pop().unwrap();
+                let (Some(borrowed_key), Some(borrowed_child)) = (sibling.keys.pop(), sibling.children.pop()) else {
+                    anyhow::bail!("borrow_left: left sibling {:?} has nothing to lend", sibling.offset);
+                };
                 current.keys.insert(0, self.keys[index - 1].clone());
                 self.keys[index - 1] = borrowed_key;
-
-                let borrowed_child = sibling.children.pop().unwrap();
                 current.children.insert(0, borrowed_child);
 
                 pager
@@ -182,34 +581,41 @@ impl InternalNode {
                     .write_at(&Node::Internal(current.clone()), child_offset)?;
             },
             (Node::Leaf(ref mut sibling), Node::Leaf(ref mut current)) => {
-                let borrowed_key = sibling.keys.pop().unwrap();
-                let borrowed_value = sibling.values.pop().unwrap();
-                current.keys.insert(0, borrowed_key.clone());
+                let (Some(borrowed_key), Some(borrowed_value)) = (sibling.keys.pop(), sibling.values.pop()) else {
+                    anyhow::bail!("borrow_left: left sibling {:?} has nothing to lend", sibling.offset);
+                };
+                let Some(separator) = sibling.keys.last() else {
+                    anyhow::bail!("borrow_left: left sibling {:?} was emptied by the borrow", sibling.offset);
+                };
+                self.keys[index - 1].clone_from(separator);
+                current.keys.insert(0, borrowed_key);
                 current.values.insert(0, borrowed_value);
-                self.keys[index - 1].clone_from(&sibling.keys[0]);
 
                 pager
                     .write_at(&Node::Leaf(sibling.clone()), left_sibling_offset)?;
                 pager
                     .write_at(&Node::Leaf(current.clone()), child_offset)?;
             },
-            _ => {},
+            _ => anyhow::bail!("borrow_left: sibling node type mismatch"),
         }
 
         Ok(())
     }
 
-    fn borrow_right(
+    fn borrow_right<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
+        pager: &mut dyn PageOperator<K, V>,
         index: usize,
-        right_sibling: &mut Node,
+        right_sibling: &mut Node<K, V>,
         right_sibling_offset: Offset,
-        child_node: &mut Node,
+        child_node: &mut Node<K, V>,
         child_offset: Offset,
     ) -> anyhow::Result<()> {
         match (right_sibling, child_node) {
             (Node::Internal(ref mut sibling), Node::Internal(ref mut current)) => {
+                if sibling.keys.is_empty() || sibling.children.is_empty() {
+                    anyhow::bail!("borrow_right: right sibling {:?} has nothing to lend", sibling.offset);
+                }
                 let borrowed_key = sibling.keys.remove(0);
                 current.keys.push(self.keys[index].clone());
                 self.keys[index] = borrowed_key;
@@ -223,6 +629,9 @@ impl InternalNode {
                     .write_at(&Node::Internal(current.clone()), child_offset)?;
             },
             (Node::Leaf(ref mut sibling), Node::Leaf(ref mut current)) => {
+                if sibling.keys.is_empty() || sibling.values.is_empty() {
+                    anyhow::bail!("borrow_right: right sibling {:?} has nothing to lend", sibling.offset);
+                }
                 let borrowed_key = sibling.keys.remove(0);
                 let borrowed_value = sibling.values.remove(0);
                 self.keys[index].clone_from(&borrowed_key);
@@ -235,19 +644,19 @@ impl InternalNode {
                 pager
                     .write_at(&Node::Leaf(current.clone()), child_offset)?;
             },
-            _ => {},
+            _ => anyhow::bail!("borrow_right: sibling node type mismatch"),
         }
 
         Ok(())
     }
 
-    fn merge_left(
+    fn merge_left<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
+        pager: &mut dyn PageOperator<K, V>,
         index: usize,
-        left_sibling: &mut Node,
+        left_sibling: &mut Node<K, V>,
         left_sibling_offset: Offset,
-        child_node: &mut Node,
+        child_node: &mut Node<K, V>,
         child_offset: Offset,
     ) -> anyhow::Result<()> {
         match (left_sibling, child_node) {
@@ -259,34 +668,31 @@ impl InternalNode {
 
                 pager
                     .write_at(&Node::Internal(sibling.clone()), left_sibling_offset)?;
-                pager
-                    .write_at(&Node::Internal(current.clone()), child_offset)?;
+                pager.free(child_offset);
             },
             (Node::Leaf(ref mut sibling), Node::Leaf(ref mut current)) => {
-                sibling.keys.append(&mut current.keys);
-                sibling.values.append(&mut current.values);
+                sibling.absorb(current);
 
                 self.keys.remove(index - 1);
                 self.children.remove(index);
 
                 pager
                     .write_at(&Node::Leaf(sibling.clone()), left_sibling_offset)?;
-                pager
-                    .write_at(&Node::Leaf(current.clone()), child_offset)?;
+                pager.free(child_offset);
             },
-            _ => {},
+            _ => anyhow::bail!("merge_left: sibling node type mismatch"),
         }
 
         Ok(())
     }
 
-    fn merge_right(
+    fn merge_right<V: Clone>(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
+        pager: &mut dyn PageOperator<K, V>,
         index: usize,
-        right_sibling: &mut Node,
+        right_sibling: &mut Node<K, V>,
         right_sibling_offset: Offset,
-        child_node: &mut Node,
+        child_node: &mut Node<K, V>,
         child_offset: Offset,
     ) -> anyhow::Result<()> {
         match (child_node, right_sibling) {
@@ -296,48 +702,79 @@ impl InternalNode {
                 current.children.append(&mut sibling.children);
                 self.children.remove(index + 1);
 
-                pager
-                    .write_at(&Node::Internal(sibling.clone()), right_sibling_offset)?;
+                pager.free(right_sibling_offset);
                 pager
                     .write_at(&Node::Internal(current.clone()), child_offset)?;
             },
             (Node::Leaf(ref mut current), Node::Leaf(ref mut sibling)) => {
-                current.keys.append(&mut sibling.keys);
-                current.values.append(&mut sibling.values);
+                current.absorb(sibling);
 
                 self.keys.remove(index);
                 self.children.remove(index + 1);
 
-                pager
-                    .write_at(&Node::Leaf(sibling.clone()), right_sibling_offset)?;
+                pager.free(right_sibling_offset);
                 pager
                     .write_at(&Node::Leaf(current.clone()), child_offset)?;
             },
-            _ => {},
+            _ => anyhow::bail!("merge_right: sibling node type mismatch"),
         }
 
         Ok(())
     }
 
-    pub(crate) fn search(&self, pager: &mut Box<dyn PageOperator>, key: Key) -> anyhow::Result<Option<Value>> {
-        let position = self.keys.binary_search(&key).unwrap_or_else(|pos| pos);
-        let child_offset = self.children[position];
-        let child_node = pager.read(child_offset)?;
-        child_node.search(pager, key)
+    pub(crate) fn search<V: Clone>(
+        &self,
+        pager: &dyn PageRead<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+    ) -> anyhow::Result<Option<V>> {
+        let position = compare::search(cmp, &self.keys, &key).unwrap_or_else(|pos| pos);
+        let child_node = pager.read_at(self.child(position)?)?;
+        child_node.search(pager, cmp, key)
     }
 
-    pub(crate) fn debug_print(&self, pager: &mut Box<dyn PageOperator>, level: usize) -> anyhow::Result<()> {
+    pub(crate) fn debug_print<V: Clone + Debug>(
+        &self,
+        pager: &dyn PageRead<K, V>,
+        out: &mut dyn Write,
+        level: usize,
+    ) -> anyhow::Result<()>
+    where
+        K: Debug,
+    {
         let indent = "  ".repeat(level);
-        println!(
-            "{}InternalNode: {:?} keys = {:?}, children = {:?}",
-            indent, self.offset, self.keys, self.children
-        );
+        writeln!(
+            out,
+            "{}InternalNode: {:?} len = {}, keys = {:?}, children = {:?}",
+            indent,
+            self.offset,
+            self.keys.len(),
+            self.keys,
+            self.children
+        )?;
         for (i, child_offset) in self.children.iter().enumerate() {
-            println!("{indent}  Child {i}:");
-            let child = pager.read(*child_offset)?;
-            let _ = child.debug_print(pager, level + 1);
+            writeln!(out, "{indent}  Child {i}:")?;
+            let child = pager.read_at(*child_offset)?;
+            child.debug_print(pager, out, level + 1)?;
         }
 
         Ok(())
     }
 }
+
+/// Frees the subtree at `offset` and returns the number of keys its leaves
+/// held.
+fn release<K: Ord + Clone, V: Clone>(pager: &mut dyn PageOperator<K, V>, offset: Offset) -> anyhow::Result<usize> {
+    let removed = match pager.read_at(offset)? {
+        Node::Leaf(leaf_node) => leaf_node.keys.len(),
+        Node::Internal(internal_node) => {
+            let mut removed = 0;
+            for child_offset in internal_node.children {
+                removed += release(pager, child_offset)?;
+            }
+            removed
+        },
+    };
+    pager.free(offset);
+    Ok(removed)
+}
@@ -1,69 +1,241 @@
+use std::fmt::Debug;
+use std::io::Write;
+
 use bincode::{Decode, Encode};
-use crate::tree::{Key, Value};
+use super::{min_keys, Change, Node, SplitBias, Update};
+use crate::compare::{self, KeyComparator};
 use crate::pager::{PageOperator, Offset};
 
+/// Keys and values of a leaf, in ascending key order. Leaves hold no
+/// links to their neighbours: scans step from leaf to leaf through the
+/// parents, so moving a leaf never touches the pages next to it.
 #[derive(Clone, Debug, Encode, Decode)]
-pub(crate) struct LeafNode {
-    pub keys: Vec<Key>,
-    pub values: Vec<Value>,
+#[bincode(
+    encode_bounds = "K: Encode + 'static, V: Encode + 'static",
+    decode_bounds = "K: Decode + 'static, V: Decode + 'static"
+)]
+pub struct LeafNode<K, V> {
+    pub keys: Vec<K>,
+    pub values: Vec<V>,
     pub offset: Option<Offset>,
 }
 
-impl LeafNode {
+impl<K: Ord + Clone, V: Clone> LeafNode<K, V> {
     pub(crate) fn insert(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
-        key: Key,
-        value: Value,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        value: V,
         degree: usize,
-    ) -> Option<(Key, LeafNode)> {
-        let position = self.keys.binary_search(&key).unwrap_or_else(|pos| pos);
+        bias: SplitBias,
+    ) -> anyhow::Result<Option<(K, LeafNode<K, V>)>> {
+        let position = match compare::search(cmp, &self.keys, &key) {
+            Ok(position) => {
+                self.values[position] = value;
+                return Ok(None);
+            },
+            Err(position) => position,
+        };
         self.keys.insert(position, key);
         self.values.insert(position, value);
 
         if self.keys.len() > degree - 1 {
-            Some(self.split(pager))
+            Ok(Some(self.split(pager, cmp, bias, position)))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    fn split(&mut self, pager: &mut Box<dyn PageOperator>) -> (Key, LeafNode) {
-        let split_index = self.keys.len() / 2;
-        let mid_key = self.keys[split_index - 1].clone();
+    /// Splits the leaf after the key inserted at `position` overflowed it.
+    fn split(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        bias: SplitBias,
+        position: usize,
+    ) -> (K, LeafNode<K, V>) {
+        let at_right_edge = position == self.keys.len() - 1;
+        let split_index = bias.keys_kept(self.keys.len(), false, at_right_edge);
+        let mid_key = cmp.separator(&self.keys[split_index - 1], &self.keys[split_index]);
 
         let new_leaf_node = LeafNode {
             keys: self.keys.split_off(split_index),
             values: self.values.split_off(split_index),
-            offset: Some(pager.next_offset()),
+            offset: Some(pager.allocate()),
         };
 
         (mid_key, new_leaf_node)
     }
 
-    pub(crate) fn remove(&mut self, key: Key, degree: usize) -> Option<bool> {
-        match self.keys.binary_search(&key) {
+    /// Inserts a batch of pairs sorted by key, replacing existing values.
+    /// When the leaf overflows it is split into as many siblings as needed;
+    /// the siblings are written and returned with their separator keys.
+    pub(crate) fn insert_batch(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        pairs: Vec<(K, V)>,
+        degree: usize,
+    ) -> anyhow::Result<Vec<(K, Offset)>> {
+        for (key, value) in pairs {
+            match compare::search(cmp, &self.keys, &key) {
+                Ok(position) => self.values[position] = value,
+                Err(position) => {
+                    self.keys.insert(position, key);
+                    self.values.insert(position, value);
+                },
+            }
+        }
+
+        if self.keys.len() < degree {
+            return Ok(Vec::new());
+        }
+
+        let sizes = chunk_sizes(self.keys.len(), degree - 1);
+        let offsets = sizes[1..].iter().map(|_| pager.allocate()).collect::<Vec<_>>();
+        let mut keys = self.keys.split_off(sizes[0]).into_iter();
+        let mut values = self.values.split_off(sizes[0]).into_iter();
+
+        let mut last_key = self.keys[self.keys.len() - 1].clone();
+        let mut siblings = Vec::with_capacity(offsets.len());
+
+        for (i, size) in sizes[1..].iter().enumerate() {
+            let sibling = LeafNode {
+                keys: keys.by_ref().take(*size).collect::<Vec<_>>(),
+                values: values.by_ref().take(*size).collect(),
+                offset: Some(offsets[i]),
+            };
+
+            siblings.push((cmp.separator(&last_key, &sibling.keys[0]), offsets[i]));
+            last_key = sibling.keys[sibling.keys.len() - 1].clone();
+            pager.write_at(&Node::Leaf(sibling), offsets[i])?;
+        }
+
+        Ok(siblings)
+    }
+
+    /// Moves every entry of the right neighbour `other` into this leaf.
+    pub(crate) fn absorb(&mut self, other: &mut LeafNode<K, V>) {
+        self.keys.append(&mut other.keys);
+        self.values.append(&mut other.values);
+    }
+
+    pub(crate) fn update<F>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        f: F,
+        degree: usize,
+        bias: SplitBias,
+    ) -> anyhow::Result<Update<K, V>>
+    where
+        F: FnOnce(Option<&V>) -> Change<V>,
+    {
+        let search = compare::search(cmp, &self.keys, &key);
+        let current = search.ok().map(|position| &self.values[position]);
+
+        match (search, f(current)) {
+            (_, Change::Keep) | (Err(_), Change::Remove) => return Ok(Update::Untouched),
+            (Ok(position), Change::Put(value)) => self.values[position] = value,
+            (Ok(_), Change::Remove) => {
+                let removed = self.remove(cmp, key, degree);
+                return Ok(Update::Removed(removed.is_some_and(|(_, underflow)| underflow)));
+            },
+            (Err(position), Change::Put(value)) => {
+                self.keys.insert(position, key);
+                self.values.insert(position, value);
+
+                if self.keys.len() > degree - 1 {
+                    let (mid_key, sibling) = self.split(pager, cmp, bias, position);
+                    return Ok(Update::Split(mid_key, Node::Leaf(sibling)));
+                }
+            },
+        }
+
+        Ok(Update::Unchanged)
+    }
+
+    pub(crate) fn remove(&mut self, cmp: &dyn KeyComparator<K>, key: K, degree: usize) -> Option<(V, bool)> {
+        match compare::search(cmp, &self.keys, &key) {
             Err(_) => None,
             Ok(position) => {
                 self.keys.remove(position);
-                self.values.remove(position);
-                Some(self.keys.len() < (degree / 2))
+                let value = self.values.remove(position);
+                Some((value, self.keys.len() < min_keys(degree)))
             },
         }
     }
 
-    pub(crate) fn search(&self, key: Key) -> Option<Value> {
-        match self.keys.binary_search(&key) {
+    /// Drops every key of `keys`, which are in ascending order, and returns
+    /// how many were dropped.
+    pub(crate) fn remove_sorted(&mut self, cmp: &dyn KeyComparator<K>, keys: &[K]) -> usize {
+        let len = self.keys.len();
+        let mut pending = keys.iter().peekable();
+        let kept = std::mem::take(&mut self.keys)
+            .into_iter()
+            .zip(std::mem::take(&mut self.values))
+            .filter(|(key, _)| {
+                while pending.next_if(|pending_key| cmp.compare(pending_key, key) == std::cmp::Ordering::Less).is_some() {}
+                pending.next_if(|pending_key| cmp.compare(pending_key, key) == std::cmp::Ordering::Equal).is_none()
+            });
+        (self.keys, self.values) = kept.unzip();
+        len - self.keys.len()
+    }
+
+    /// Drops every key in `[start, end)`, or from `start` on without an
+    /// `end`, and returns how many were dropped.
+    pub(crate) fn remove_range(&mut self, cmp: &dyn KeyComparator<K>, start: &K, end: Option<&K>) -> usize {
+        let from = self.keys.partition_point(|probe| cmp.compare(probe, start) == std::cmp::Ordering::Less);
+        let to = match end {
+            Some(end) => self.keys.partition_point(|probe| cmp.compare(probe, end) == std::cmp::Ordering::Less).max(from),
+            None => self.keys.len(),
+        };
+        self.keys.drain(from..to);
+        self.values.drain(from..to);
+        to - from
+    }
+
+    /// Drops every key below `key` and returns how many were dropped.
+    pub(crate) fn truncate_below(&mut self, cmp: &dyn KeyComparator<K>, key: &K) -> usize {
+        let below = self.keys.partition_point(|probe| cmp.compare(probe, key) == std::cmp::Ordering::Less);
+        self.keys.drain(..below);
+        self.values.drain(..below);
+        below
+    }
+
+    pub(crate) fn search(&self, cmp: &dyn KeyComparator<K>, key: K) -> Option<V> {
+        match compare::search(cmp, &self.keys, &key) {
             Err(_) => None,
             Ok(position) => Some(self.values[position].clone()),
         }
     }
 
-    pub(crate) fn debug_print(&self, level: usize) {
+    pub(crate) fn debug_print(&self, out: &mut dyn Write, level: usize) -> anyhow::Result<()>
+    where
+        K: Debug,
+        V: Debug,
+    {
         let indent = "  ".repeat(level);
-        println!(
-            "{}LeafNode: {:?} keys = {:?}, values = {:?}",
-            indent, self.offset, self.keys, self.values
-        );
+        writeln!(
+            out,
+            "{}LeafNode: {:?} len = {}, keys = {:?}, values = {:?}",
+            indent,
+            self.offset,
+            self.keys.len(),
+            self.keys,
+            self.values
+        )?;
+        Ok(())
     }
 }
+
+/// Splits `len` entries into the fewest chunks of at most `max` entries,
+/// keeping the chunk sizes as even as possible.
+pub(crate) fn chunk_sizes(len: usize, max: usize) -> Vec<usize> {
+    let count = len.div_ceil(max);
+    let base = len / count;
+    let extra = len % count;
+    (0..count).map(|i| if i < extra { base + 1 } else { base }).collect()
+}
@@ -1,49 +1,164 @@
+pub(crate) mod cursor;
 pub(crate) mod leaf;
 pub(crate) mod internal;
 
+use std::fmt::Debug;
+use std::io::Write;
+
 use bincode::{Decode, Encode};
 use leaf::LeafNode;
 use internal::InternalNode;
-use crate::tree::{Key, Value};
-use crate::pager::PageOperator;
+use crate::compare::KeyComparator;
+use crate::pager::{PageOperator, PageRead, Offset};
 
 #[derive(Clone, Debug, Encode, Decode)]
-pub(crate) enum Node {
-    Leaf(LeafNode),
-    Internal(InternalNode),
+#[bincode(
+    encode_bounds = "K: Encode + 'static, V: Encode + 'static",
+    decode_bounds = "K: Decode + 'static, V: Decode + 'static"
+)]
+pub enum Node<K, V> {
+    Leaf(LeafNode<K, V>),
+    Internal(InternalNode<K>),
+}
+
+/// What an update does to the value of its key.
+pub(crate) enum Change<V> {
+    /// Leave the key as it is, present or not.
+    Keep,
+    /// Insert the key or replace its value.
+    Put(V),
+    /// Remove the key if it is present.
+    Remove,
+}
+
+/// Outcome of applying an update to a subtree.
+pub(crate) enum Update<K, V> {
+    /// Nothing in the subtree changed, so none of its pages were copied.
+    Untouched,
+    /// The subtree kept its shape; the key may have been inserted or
+    /// replaced without a split.
+    Unchanged,
+    /// The node split; the sibling still has to be written.
+    Split(K, Node<K, V>),
+    /// The key was removed; `true` if the node underflowed.
+    Removed(bool),
 }
 
-impl Node {
-    pub(crate) fn is_empty(&self) -> bool {
+/// Where an overflowing node splits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitBias {
+    /// Split every node at its midpoint, leaving both halves half full.
+    #[default]
+    Middle,
+    /// Split a node that overflowed at its end on the right edge of the
+    /// tree so that it stays full and the new sibling starts with a single
+    /// key. Keys inserted in ascending order then fill the nodes instead of
+    /// leaving every one of them half empty. Nodes on the right edge may
+    /// hold fewer keys than the minimum as a result; splits anywhere else
+    /// still happen at the midpoint.
+    Append,
+}
+
+impl SplitBias {
+    /// Number of keys an overflowing node of `len` keys keeps when it
+    /// splits. An internal node also gives up the key it promotes.
+    pub(crate) fn keys_kept(self, len: usize, internal: bool, at_right_edge: bool) -> usize {
         match self {
-            Node::Internal(payload) => {
-                payload.keys.is_empty() && payload.children.is_empty()
-            },
-            Node::Leaf(payload) => payload.keys.is_empty() && payload.values.is_empty(),
+            SplitBias::Append if at_right_edge => len - 1 - usize::from(internal),
+            _ => len / 2,
         }
     }
 
+    /// Bias for the child at `position` of a node with `children` children.
+    /// Only the last child stays on the right edge.
+    pub(crate) fn for_child(self, position: usize, children: usize) -> SplitBias {
+        match position + 1 == children {
+            true => self,
+            false => SplitBias::Middle,
+        }
+    }
+}
+
+/// Fewest keys a node other than the root may hold. Internal nodes keep at
+/// least `ceil(degree / 2)` children and leaves follow the same bound, so a
+/// split always leaves both halves at or above it and a merge of an
+/// underflowing node with a minimal sibling always fits in one node.
+pub(crate) fn min_keys(degree: usize) -> usize {
+    degree.div_ceil(2) - 1
+}
+
+impl<K: Ord + Clone, V: Clone> Node<K, V> {
+    /// Whether the node can lend a key to a sibling without underflowing.
     pub(crate) fn can_borrow(&self, degree: usize) -> bool {
         match self {
-            Node::Leaf(leaf_node) => leaf_node.keys.len() >= (degree / 2),
-            Node::Internal(internal_node) => internal_node.keys.len() >= (degree / 2),
+            Node::Leaf(leaf_node) => leaf_node.keys.len() > min_keys(degree),
+            Node::Internal(internal_node) => internal_node.keys.len() > min_keys(degree),
+        }
+    }
+
+    /// Writes a sibling split off during this operation to the page the
+    /// split allocated for it and returns that offset.
+    pub(crate) fn write_sibling(&self, pager: &mut dyn PageOperator<K, V>) -> anyhow::Result<Offset> {
+        let offset = match self {
+            Node::Leaf(leaf_node) => leaf_node.offset,
+            Node::Internal(internal_node) => internal_node.offset,
+        };
+        let offset = offset.ok_or_else(|| anyhow::anyhow!("split sibling has no page"))?;
+        pager.write_at(self, offset)?;
+        Ok(offset)
+    }
+
+    /// Whether the node holds fewer keys than a node other than the root
+    /// may.
+    pub(crate) fn underflows(&self, degree: usize) -> bool {
+        match self {
+            Node::Leaf(leaf_node) => leaf_node.keys.len() < min_keys(degree),
+            Node::Internal(internal_node) => internal_node.keys.len() < min_keys(degree),
+        }
+    }
+
+    pub(crate) fn into_leaf(self) -> anyhow::Result<LeafNode<K, V>> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node),
+            Node::Internal(internal_node) => {
+                anyhow::bail!("expected leaf node at offset {:?}", internal_node.offset)
+            },
         }
     }
 
+    /// Moves the node to a fresh page, releases the page it was read from
+    /// and returns the new offset. The copy isn't written here: callers
+    /// write the node once they are done changing it.
+    pub(crate) fn relocate(&mut self, pager: &mut dyn PageOperator<K, V>) -> anyhow::Result<Offset> {
+        let offset = pager.allocate();
+        let previous_offset = match self {
+            Node::Leaf(leaf_node) => leaf_node.offset.replace(offset),
+            Node::Internal(internal_node) => internal_node.offset.replace(offset),
+        };
+
+        if let Some(previous_offset) = previous_offset {
+            pager.free(previous_offset);
+        }
+
+        Ok(offset)
+    }
+
     pub(crate) fn insert(
         &mut self,
-        pager: &mut Box<dyn PageOperator>,
-        key: Key,
-        value: Value,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        value: V,
         degree: usize,
-    ) -> anyhow::Result<Option<(Key, Node)>> {
+        bias: SplitBias,
+    ) -> anyhow::Result<Option<(K, Node<K, V>)>> {
         match self {
-            Node::Leaf(leaf_node) => match leaf_node.insert(pager, key, value, degree) {
+            Node::Leaf(leaf_node) => match leaf_node.insert(pager, cmp, key, value, degree, bias)? {
                 None => Ok(None),
                 Some(new_item) => Ok(Some((new_item.0, Node::Leaf(new_item.1)))),
             },
             Node::Internal(internal_node) => {
-                match internal_node.insert(pager, key, value, degree)? {
+                match internal_node.insert(pager, cmp, key, value, degree, bias)? {
                     None => Ok(None),
                     Some(new_item) => Ok(Some((new_item.0, new_item.1))),
                 }
@@ -51,24 +166,154 @@ impl Node {
         }
     }
 
-    pub(crate) fn remove(&mut self, pager: &mut Box<dyn PageOperator>, key: Key, degree: usize) -> anyhow::Result<Option<bool>> {
+    pub(crate) fn insert_batch(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        pairs: Vec<(K, V)>,
+        degree: usize,
+    ) -> anyhow::Result<Vec<(K, Offset)>> {
         match self {
-            Node::Leaf(leaf_node) => Ok(leaf_node.remove(key, degree)),
-            Node::Internal(internal_node) => internal_node.remove(pager, key, degree),
+            Node::Leaf(leaf_node) => leaf_node.insert_batch(pager, cmp, pairs, degree),
+            Node::Internal(internal_node) => internal_node.insert_batch(pager, cmp, pairs, degree),
         }
     }
 
-    pub(crate) fn search(&self, pager: &mut Box<dyn PageOperator>, key: Key) -> anyhow::Result<Option<Value>> {
+    /// Applies the change `f` picks for the current value of `key`, in a
+    /// single descent. Nodes are only copied on the way back up once the
+    /// leaf has changed.
+    pub(crate) fn update<F>(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        f: F,
+        degree: usize,
+        bias: SplitBias,
+    ) -> anyhow::Result<Update<K, V>>
+    where
+        F: FnOnce(Option<&V>) -> Change<V>,
+    {
         match self {
-            Node::Leaf(leaf_node) => Ok(leaf_node.search(key)),
-            Node::Internal(internal_node) => internal_node.search(pager, key),
+            Node::Leaf(leaf_node) => leaf_node.update(pager, cmp, key, f, degree, bias),
+            Node::Internal(internal_node) => internal_node.update(pager, cmp, key, f, degree, bias),
+        }
+    }
+
+    /// Removes `key` from the subtree, returning the removed value and
+    /// whether this node underflowed, or `None` if the key was absent.
+    pub(crate) fn remove(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+        degree: usize,
+    ) -> anyhow::Result<Option<(V, bool)>> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node.remove(cmp, key, degree)),
+            Node::Internal(internal_node) => internal_node.remove(pager, cmp, key, degree),
+        }
+    }
+
+    /// Drops every key of `keys`, which are in ascending order, from the
+    /// subtree and returns how many were dropped; the caller moves a node
+    /// that had any dropped. A leaf may be left short or empty, for its
+    /// parent to rebalance.
+    pub(crate) fn remove_sorted(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        keys: &[K],
+        degree: usize,
+    ) -> anyhow::Result<usize> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node.remove_sorted(cmp, keys)),
+            Node::Internal(internal_node) => internal_node.remove_sorted(pager, cmp, keys, degree),
+        }
+    }
+
+    /// Drops every key in `[start, end)`, or from `start` on without an
+    /// `end`, from the subtree and returns how many were dropped; the
+    /// caller moves a node that had any dropped. A leaf may be left short
+    /// or empty, for its parent to rebalance.
+    pub(crate) fn remove_range(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        start: &K,
+        end: Option<&K>,
+        degree: usize,
+    ) -> anyhow::Result<usize> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node.remove_range(cmp, start, end)),
+            Node::Internal(internal_node) => internal_node.remove_range(pager, cmp, start, end, degree),
+        }
+    }
+
+    /// Drops every key below `key` from the subtree, releasing the
+    /// subtrees that lie below it whole. Returns the number of keys dropped
+    /// and whether the node changed; the caller moves a changed node. The
+    /// nodes left along the left edge may underflow, see
+    /// [`InternalNode::fix_first_child`].
+    pub(crate) fn truncate_below(
+        &mut self,
+        pager: &mut dyn PageOperator<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: &K,
+    ) -> anyhow::Result<(usize, bool)> {
+        match self {
+            Node::Leaf(leaf_node) => {
+                let removed = leaf_node.truncate_below(cmp, key);
+                Ok((removed, removed > 0))
+            },
+            Node::Internal(internal_node) => internal_node.truncate_below(pager, cmp, key),
+        }
+    }
+
+    pub(crate) fn search(
+        &self,
+        pager: &dyn PageRead<K, V>,
+        cmp: &dyn KeyComparator<K>,
+        key: K,
+    ) -> anyhow::Result<Option<V>> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node.search(cmp, key)),
+            Node::Internal(internal_node) => internal_node.search(pager, cmp, key),
+        }
+    }
+
+    /// Descends through the first child of every internal node to the
+    /// leftmost leaf.
+    pub(crate) fn first_leaf(self, pager: &dyn PageRead<K, V>) -> anyhow::Result<LeafNode<K, V>> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node),
+            Node::Internal(internal_node) => {
+                let child_node = pager.read_at(internal_node.child(0)?)?;
+                child_node.first_leaf(pager)
+            },
+        }
+    }
+
+    /// Descends through the last child of every internal node to the
+    /// rightmost leaf.
+    pub(crate) fn last_leaf(self, pager: &dyn PageRead<K, V>) -> anyhow::Result<LeafNode<K, V>> {
+        match self {
+            Node::Leaf(leaf_node) => Ok(leaf_node),
+            Node::Internal(internal_node) => {
+                let child_node = pager.read_at(internal_node.last_child()?)?;
+                child_node.last_leaf(pager)
+            },
         }
     }
 
-    pub(crate) fn debug_print(&self, pager: &mut Box<dyn PageOperator>, level: usize) -> anyhow::Result<()> {
+    pub(crate) fn debug_print(&self, pager: &dyn PageRead<K, V>, out: &mut dyn Write, level: usize) -> anyhow::Result<()>
+    where
+        K: Debug,
+        V: Debug,
+    {
         match self {
-            Node::Leaf(leaf_node) => Ok(leaf_node.debug_print(level)),
-            Node::Internal(internal_node) => internal_node.debug_print(pager, level),
+            Node::Leaf(leaf_node) => leaf_node.debug_print(out, level),
+            Node::Internal(internal_node) => internal_node.debug_print(pager, out, level),
         }
     }
 }
@@ -0,0 +1,53 @@
+use std::ops::{Deref, DerefMut};
+
+/// Alignment of the buffers storage is read into and written from with the
+/// `direct-io` feature. `O_DIRECT` needs buffers, offsets and lengths
+/// aligned to the logical block size of the device, which this covers.
+pub(crate) const ALIGNMENT: usize = 4096;
+
+/// Zeroed buffer whose first byte sits on an `ALIGNMENT` boundary. It uses
+/// the aligned part of a slightly larger `Vec`, which is never resized, so
+/// the allocation stays put.
+pub(crate) struct AlignedBuf {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    pub(crate) fn zeroed(len: usize) -> Self {
+        let data = vec![0x00; len + ALIGNMENT];
+        let start = data.as_ptr().align_offset(ALIGNMENT);
+        Self { data, start, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.start + self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_start_aligned() {
+        for len in [0, 1, 512, 4096, 10_000] {
+            let mut buffer = AlignedBuf::zeroed(len);
+            assert_eq!(buffer.as_ptr() as usize % ALIGNMENT, 0);
+            assert_eq!(buffer.len(), len);
+            assert!(buffer.iter().all(|byte| *byte == 0x00));
+            buffer.fill(0xff);
+        }
+    }
+}
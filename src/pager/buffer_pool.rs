@@ -0,0 +1,81 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::Offset;
+
+/// Write-back buffer of encoded pages, keyed by offset.
+///
+/// Pages written by an operation are held here instead of going to storage
+/// straight away, so a page written several times before the next commit
+/// reaches storage once, and a page freed before it gets there not at all.
+/// Once more than `capacity` pages are held, the one written longest ago
+/// is handed back to be written out. A capacity of zero disables the
+/// buffer.
+pub(crate) struct BufferPool {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<Offset, (Vec<u8>, u64)>,
+    age: BTreeMap<u64, Offset>,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            age: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Returns the page at `offset` if it hasn't been written out yet.
+    pub(crate) fn get(&self, offset: Offset) -> Option<&[u8]> {
+        self.pages.get(&offset).map(|(data, _)| data.as_slice())
+    }
+
+    /// Holds `data` as the current content of the page at `offset` and
+    /// returns the pages that no longer fit, oldest first, to be written
+    /// out.
+    pub(crate) fn put(&mut self, offset: Offset, data: Vec<u8>) -> Vec<(Offset, Vec<u8>)> {
+        self.tick += 1;
+        if let Some((_, tick)) = self.pages.insert(offset, (data, self.tick)) {
+            self.age.remove(&tick);
+        }
+        self.age.insert(self.tick, offset);
+
+        let mut evicted = Vec::new();
+        while self.pages.len() > self.capacity {
+            let Some((_, offset)) = self.age.pop_first() else {
+                break;
+            };
+            if let Some((data, _)) = self.pages.remove(&offset) {
+                evicted.push((offset, data));
+            }
+        }
+        evicted
+    }
+
+    /// Drops the page at `offset` without writing it out.
+    pub(crate) fn discard(&mut self, offset: Offset) {
+        if let Some((_, tick)) = self.pages.remove(&offset) {
+            self.age.remove(&tick);
+        }
+    }
+
+    /// Empties the buffer, returning every page it held in offset order so
+    /// they are written out front to back.
+    pub(crate) fn drain(&mut self) -> Vec<(Offset, Vec<u8>)> {
+        self.age.clear();
+        let mut pages = self.pages.drain().map(|(offset, (data, _))| (offset, data)).collect::<Vec<_>>();
+        pages.sort_unstable_by_key(|(offset, _)| *offset);
+        pages
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pages.clear();
+        self.age.clear();
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::Offset;
+
+/// Hit and miss counters of a page cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Least-recently-used cache of encoded pages, keyed by offset.
+///
+/// Every access stamps the page with a new tick; the page with the oldest
+/// tick is evicted once `capacity` pages are held. A capacity of zero
+/// disables caching.
+pub(crate) struct PageCache {
+    capacity: usize,
+    tick: u64,
+    pages: HashMap<Offset, (Vec<u8>, u64)>,
+    recency: BTreeMap<u64, Offset>,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Returns the cached page at `offset`, counting the lookup as a hit or
+    /// a miss.
+    pub(crate) fn get(&mut self, offset: Offset) -> Option<&[u8]> {
+        self.tick += 1;
+        match self.pages.get_mut(&offset) {
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+            Some((data, tick)) => {
+                self.stats.hits += 1;
+                self.recency.remove(tick);
+                self.recency.insert(self.tick, offset);
+                *tick = self.tick;
+                Some(data.as_slice())
+            },
+        }
+    }
+
+    /// Caches `data` as the current content of the page at `offset`, and
+    /// hands back a buffer the cache let go of for reuse: the page it
+    /// replaced or evicted, or `data` itself if caching is disabled.
+    pub(crate) fn put(&mut self, offset: Offset, data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return Some(data);
+        }
+
+        self.tick += 1;
+        let mut released = None;
+        if let Some((replaced, tick)) = self.pages.insert(offset, (data, self.tick)) {
+            self.recency.remove(&tick);
+            released = Some(replaced);
+        }
+        self.recency.insert(self.tick, offset);
+
+        while self.pages.len() > self.capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            released = self.pages.remove(&evicted).map(|(data, _)| data);
+        }
+        released
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pages.clear();
+        self.recency.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_hands_back_dropped_pages() {
+        let mut cache = PageCache::new(2);
+        assert_eq!(cache.put(0, vec![0]), None);
+        assert_eq!(cache.put(1, vec![1]), None);
+        assert_eq!(cache.put(1, vec![2]), Some(vec![1]));
+        // Page 0 is the least recently used.
+        assert_eq!(cache.put(2, vec![3]), Some(vec![0]));
+        assert_eq!(cache.get(2), Some(&[3][..]));
+
+        let mut disabled = PageCache::new(0);
+        assert_eq!(disabled.put(0, vec![4]), Some(vec![4]));
+        assert_eq!(disabled.get(0), None);
+    }
+}
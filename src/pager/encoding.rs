@@ -0,0 +1,123 @@
+use bincode::config::{self, Configuration};
+use bincode::enc::write::SizeWriter;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+
+/// Byte order of the integers in an encoded node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Width of the integers in an encoded node.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub enum IntEncoding {
+    /// Small values take fewer bytes, as in bincode's standard
+    /// configuration.
+    #[default]
+    Variable,
+    /// Every integer takes the full width of its type.
+    Fixed,
+}
+
+/// Bincode configuration the node pages are encoded with. The choice is
+/// stored in the header, so a reopened tree decodes its pages the way they
+/// were written. The header itself always uses the default, bincode's
+/// standard configuration.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct Encoding {
+    pub endian: Endian,
+    pub int_encoding: IntEncoding,
+}
+
+impl Encoding {
+    /// Fixed-width big-endian integers, readable without a varint decoder.
+    pub fn fixed_big_endian() -> Self {
+        Self {
+            endian: Endian::Big,
+            int_encoding: IntEncoding::Fixed,
+        }
+    }
+
+    pub(crate) fn encode<T: Encode>(&self, value: &T) -> Result<Vec<u8>, EncodeError> {
+        let mut out = Vec::new();
+        self.encode_into(value, &mut out)?;
+        Ok(out)
+    }
+
+    /// Appends the encoding of `value` to `out`, so a buffer can be reused
+    /// from one value to the next.
+    pub(crate) fn encode_into<T: Encode>(&self, value: &T, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let standard = config::standard();
+        match (self.endian, self.int_encoding) {
+            (Endian::Little, IntEncoding::Variable) => bincode::encode_into_std_write(value, out, standard),
+            (Endian::Little, IntEncoding::Fixed) => {
+                bincode::encode_into_std_write(value, out, standard.with_fixed_int_encoding())
+            },
+            (Endian::Big, IntEncoding::Variable) => bincode::encode_into_std_write(value, out, standard.with_big_endian()),
+            (Endian::Big, IntEncoding::Fixed) => {
+                bincode::encode_into_std_write(value, out, standard.with_big_endian().with_fixed_int_encoding())
+            },
+        }?;
+        Ok(())
+    }
+
+    /// Number of bytes `value` encodes to, measured without encoding it
+    /// into a buffer.
+    pub(crate) fn encoded_len<T: Encode>(&self, value: &T) -> Result<usize, EncodeError> {
+        let mut size = SizeWriter::default();
+        let standard = config::standard();
+        match (self.endian, self.int_encoding) {
+            (Endian::Little, IntEncoding::Variable) => bincode::encode_into_writer(value, &mut size, standard),
+            (Endian::Little, IntEncoding::Fixed) => {
+                bincode::encode_into_writer(value, &mut size, standard.with_fixed_int_encoding())
+            },
+            (Endian::Big, IntEncoding::Variable) => bincode::encode_into_writer(value, &mut size, standard.with_big_endian()),
+            (Endian::Big, IntEncoding::Fixed) => {
+                bincode::encode_into_writer(value, &mut size, standard.with_big_endian().with_fixed_int_encoding())
+            },
+        }?;
+        Ok(size.bytes_written)
+    }
+
+    /// Decodes a value from the start of `data`, returning it with the
+    /// number of bytes it took.
+    pub(crate) fn decode<T: Decode>(&self, data: &[u8]) -> Result<(T, usize), DecodeError> {
+        let standard: Configuration = config::standard();
+        match (self.endian, self.int_encoding) {
+            (Endian::Little, IntEncoding::Variable) => bincode::decode_from_slice(data, standard),
+            (Endian::Little, IntEncoding::Fixed) => bincode::decode_from_slice(data, standard.with_fixed_int_encoding()),
+            (Endian::Big, IntEncoding::Variable) => bincode::decode_from_slice(data, standard.with_big_endian()),
+            (Endian::Big, IntEncoding::Fixed) => {
+                bincode::decode_from_slice(data, standard.with_big_endian().with_fixed_int_encoding())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings_differ_and_round_trip() -> anyhow::Result<()> {
+        let value = (300u32, "key".to_string());
+        let standard = Encoding::default().encode(&value)?;
+        let fixed = Encoding::fixed_big_endian().encode(&value)?;
+        assert_eq!(standard, bincode::encode_to_vec(&value, config::standard())?);
+        assert_eq!(&fixed[..4], &300u32.to_be_bytes());
+
+        for endian in [Endian::Little, Endian::Big] {
+            for int_encoding in [IntEncoding::Variable, IntEncoding::Fixed] {
+                let encoding = Encoding { endian, int_encoding };
+                let data = encoding.encode(&value)?;
+                assert_eq!(encoding.encoded_len(&value)?, data.len());
+                assert_eq!(encoding.decode::<(u32, String)>(&data)?, (value.clone(), data.len()));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+use super::Offset;
+
+/// Pages that no longer belong to the tree and can be handed out again.
+///
+/// A page is only reused once no header a crash could leave on disk points
+/// at it. Freed pages are held back until the header write that commits
+/// the operation abandoning them. A page the last synced header may point
+/// at is then held back further, until a sync makes the header freeing it
+/// durable, as that synced header is the one a crash leaves until then.
+/// Pages handed out since the last sync aren't in it, so they are reusable
+/// straight after the commit. While snapshots are pinned freed pages are
+/// retained past the commit, as older versions may still read them.
+#[derive(Default)]
+pub(crate) struct FreeList {
+    pages: Vec<Offset>,
+    pending: Vec<Offset>,
+    /// Freed by a header written since the last sync, and possibly still
+    /// used by the header synced before it.
+    unsynced: Vec<Offset>,
+    retained: Vec<Offset>,
+    /// Pages popped since the last sync.
+    popped: HashSet<Offset>,
+    /// End of the allocated pages at the last sync. Pages past it were
+    /// allocated since.
+    synced_cursor: Offset,
+}
+
+impl FreeList {
+    /// A list restored from a header read from storage, which stands for
+    /// the last sync.
+    pub(crate) fn new(pages: Vec<Offset>, retained: Vec<Offset>, cursor: Offset) -> Self {
+        Self {
+            pages,
+            retained,
+            synced_cursor: cursor,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn peek(&self) -> Option<Offset> {
+        self.pages.last().copied()
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Offset> {
+        let offset = self.pages.pop()?;
+        self.popped.insert(offset);
+        Some(offset)
+    }
+
+    pub(crate) fn free(&mut self, offset: Offset) {
+        self.pending.push(offset);
+    }
+
+    /// Makes the pages freed since the last commit reusable, or sets those
+    /// the last synced header may use aside until the next sync, and
+    /// returns all free pages, both kinds, so they can be persisted. With
+    /// `retain` set the pages are set aside until a commit without it
+    /// releases them all instead.
+    pub(crate) fn commit(&mut self, retain: bool) -> Vec<Offset> {
+        if retain {
+            self.retained.append(&mut self.pending);
+        } else {
+            let freed = self.retained.drain(..).chain(self.pending.drain(..)).collect::<Vec<_>>();
+            for offset in freed {
+                if offset >= self.synced_cursor || self.popped.contains(&offset) {
+                    self.pages.push(offset);
+                } else {
+                    self.unsynced.push(offset);
+                }
+            }
+        }
+        self.pages.iter().chain(&self.unsynced).copied().collect()
+    }
+
+    /// Makes the pages freed by the commits written so far reusable, once
+    /// a sync has made the last of their headers durable. `cursor` is the
+    /// end of the allocated pages.
+    pub(crate) fn synced(&mut self, cursor: Offset) {
+        self.pages.append(&mut self.unsynced);
+        self.popped.clear();
+        self.synced_cursor = cursor;
+    }
+
+    /// Forgets the pages freed since the last commit. The operation that
+    /// freed them was abandoned, so the committed tree still uses them.
+    pub(crate) fn rollback(&mut self) {
+        self.pending.clear();
+    }
+
+    pub(crate) fn retained(&self) -> &[Offset] {
+        &self.retained
+    }
+}
@@ -0,0 +1,192 @@
+//! LZ4 block format, used to compress node pages.
+//!
+//! A block is a series of sequences, each a run of literals followed by a
+//! match that copies earlier output. The compressor is the plain greedy
+//! single-probe variant; its output can be read by any LZ4 block decoder.
+
+const MIN_MATCH: usize = 4;
+/// The last bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+/// No match may start within this many bytes of the end of the input.
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 0xFFFF;
+const HASH_LOG: u32 = 12;
+
+/// Appends the compressed block of `input` to `out`.
+pub(crate) fn compress(input: &[u8], out: &mut Vec<u8>) {
+    // Last position + 1 of every hashed 4-byte sequence, zero when unseen.
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MF_LIMIT < input.len() {
+        let sequence = read_u32(input, pos);
+        let slot = &mut table[hash(sequence)];
+        let candidate = slot.checked_sub(1);
+        *slot = pos + 1;
+
+        match candidate {
+            Some(candidate) if pos - candidate <= MAX_OFFSET && read_u32(input, candidate) == sequence => {
+                let match_limit = input.len() - LAST_LITERALS;
+                let mut len = MIN_MATCH;
+                while pos + len < match_limit && input[candidate + len] == input[pos + len] {
+                    len += 1;
+                }
+
+                write_sequence(out, &input[anchor..pos], Some((pos - candidate, len)));
+                pos += len;
+                anchor = pos;
+            },
+            _ => pos += 1,
+        }
+    }
+
+    write_sequence(out, &input[anchor..], None);
+}
+
+/// Decodes a block that expands to exactly `size` bytes.
+pub(crate) fn decompress(input: &[u8], size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+
+    loop {
+        let Some(&token) = input.get(pos) else {
+            anyhow::bail!("lz4: block ends without final literals");
+        };
+        pos += 1;
+
+        let literals = read_length(input, &mut pos, (token >> 4) as usize)?;
+        let Some(literal_bytes) = input.get(pos..pos + literals) else {
+            anyhow::bail!("lz4: literals run past the end of the block");
+        };
+        out.extend_from_slice(literal_bytes);
+        pos += literals;
+        if pos == input.len() {
+            break;
+        }
+
+        let Some(offset) = input.get(pos..pos + 2) else {
+            anyhow::bail!("lz4: match offset runs past the end of the block");
+        };
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        if offset == 0 || offset > out.len() {
+            anyhow::bail!("lz4: match offset {offset} points before the start of the output");
+        }
+
+        let len = read_length(input, &mut pos, (token & 0x0F) as usize)? + MIN_MATCH;
+        if out.len() + len > size {
+            anyhow::bail!("lz4: block expands past {size} bytes");
+        }
+        // The match may overlap the bytes it produces, so copy one at a time.
+        let start = out.len() - offset;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+
+    if out.len() != size {
+        anyhow::bail!("lz4: block expands to {} bytes, expected {size}", out.len());
+    }
+    Ok(out)
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([input[pos], input[pos + 1], input[pos + 2], input[pos + 3]])
+}
+
+/// Writes the literals and, unless this is the last sequence, the match
+/// given as `(offset, length)`.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_nibble = matched.map_or(0, |(_, len)| (len - MIN_MATCH).min(15));
+    out.push(((literals.len().min(15) as u8) << 4) | match_nibble as u8);
+    write_length(out, literals.len());
+    out.extend_from_slice(literals);
+
+    if let Some((offset, len)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        write_length(out, len - MIN_MATCH);
+    }
+}
+
+/// Writes the part of a length that doesn't fit in its 4-bit token field.
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn read_length(input: &[u8], pos: &mut usize, nibble: usize) -> anyhow::Result<usize> {
+    let mut len = nibble;
+    if nibble < 15 {
+        return Ok(len);
+    }
+
+    loop {
+        let Some(&byte) = input.get(*pos) else {
+            anyhow::bail!("lz4: length runs past the end of the block");
+        };
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() -> anyhow::Result<()> {
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(200);
+        let mut noise = Vec::new();
+        let mut state = 0x1234_5678u32;
+        for _ in 0..5000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            noise.push(state as u8);
+        }
+
+        for input in [Vec::new(), b"short".to_vec(), vec![7; 10000], text.into_bytes(), noise] {
+            let mut compressed = vec![0xff];
+            compress(&input, &mut compressed);
+            assert_eq!(decompress(&compressed[1..], input.len())?, input);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compresses_repetitive_input() {
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(200);
+        let mut compressed = Vec::new();
+        compress(text.as_bytes(), &mut compressed);
+        assert!(compressed.len() < text.len() / 10);
+    }
+
+    #[test]
+    fn decodes_reference_block() -> anyhow::Result<()> {
+        // "abcabcabcabcabcabcab" as written by the reference implementation:
+        // three literals, then a match of 12 at offset 3, then five literals.
+        let block = [0x38, b'a', b'b', b'c', 0x03, 0x00, 0x50, b'a', b'b', b'c', b'a', b'b'];
+        assert_eq!(decompress(&block, 20)?, b"abcabcabcabcabcabcab");
+        assert!(decompress(&block, 19).is_err());
+        assert!(decompress(&block[..5], 20).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use super::{free_list::FreeList, Offset, PageOperator, PageRead, DEFAULT_PAGE_SIZE, STARTUP_OFFSET};
+use crate::header::Header;
+use crate::node::Node;
+
+/// Page operator that keeps every page in memory, for tests and trees that
+/// don't need to outlive the process.
+pub struct MemoryPager<K, V> {
+    pages: HashMap<Offset, Node<K, V>>,
+    header: Option<Header>,
+    cursor: usize,
+    free_list: FreeList,
+}
+
+impl<K, V> MemoryPager<K, V> {
+    pub fn new() -> Self {
+        Self {
+            pages: HashMap::new(),
+            header: None,
+            cursor: STARTUP_OFFSET,
+            free_list: FreeList::default(),
+        }
+    }
+}
+
+impl<K, V> Default for MemoryPager<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone, V: Clone> PageRead<K, V> for MemoryPager<K, V> {
+    fn read_at(&self, offset: usize) -> anyhow::Result<Node<K, V>> {
+        match self.pages.get(&offset) {
+            None => anyhow::bail!("no page at offset {offset}"),
+            Some(node) => Ok(node.clone()),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> PageOperator<K, V> for MemoryPager<K, V> {
+    fn next_offset(&self) -> usize {
+        self.free_list.peek().unwrap_or(self.cursor)
+    }
+
+    fn allocate(&mut self) -> usize {
+        match self.free_list.pop() {
+            Some(offset) => offset,
+            None => {
+                self.cursor += DEFAULT_PAGE_SIZE;
+                self.cursor - DEFAULT_PAGE_SIZE
+            },
+        }
+    }
+
+    fn write_at(&mut self, node: &Node<K, V>, offset: usize) -> anyhow::Result<()> {
+        self.pages.insert(offset, node.clone());
+        Ok(())
+    }
+
+    fn free(&mut self, offset: usize) {
+        self.free_list.free(offset);
+    }
+
+    fn rollback(&mut self) {
+        self.free_list.rollback();
+    }
+
+    fn size_pages(&self) -> usize {
+        self.cursor.div_ceil(DEFAULT_PAGE_SIZE)
+    }
+
+    fn read_header(&mut self) -> anyhow::Result<Header> {
+        match &self.header {
+            None => anyhow::bail!("pager does not contain a tree header"),
+            Some(header) => {
+                self.cursor = header.cursor;
+                self.free_list = FreeList::new(header.free_pages.clone(), header.retained_pages.clone(), header.cursor);
+                Ok(header.clone())
+            },
+        }
+    }
+
+    fn write_header(&mut self, header: &Header) -> anyhow::Result<()> {
+        self.header = Some(Header {
+            cursor: self.cursor,
+            free_pages: self.free_list.commit(!header.snapshots.is_empty()),
+            retained_pages: self.free_list.retained().to_vec(),
+            ..header.clone()
+        });
+        // Nothing outlives the process to recover from, so the pages are
+        // reusable straight away.
+        self.free_list.synced(self.cursor);
+        Ok(())
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        self.pages.clear();
+        self.cursor = STARTUP_OFFSET;
+        self.free_list = FreeList::default();
+        Ok(())
+    }
+}
@@ -0,0 +1,926 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bincode::{Decode, Encode};
+
+use crate::error::BPTreeError;
+use crate::header::{Header, FORMAT_VERSION, MAGIC};
+use crate::node::{leaf::LeafNode, Node};
+use crate::tree::TreeOptions;
+use buffer_pool::BufferPool;
+use cache::{CacheStats, PageCache};
+pub use encoding::{Encoding, Endian, IntEncoding};
+use free_list::FreeList;
+use shared::{Gate, SharedPager};
+pub use storage::{IoStats, ReadWriteSeek};
+use storage::Storage;
+use value_pages::ValueIndex;
+
+#[cfg(feature = "direct-io")]
+pub(crate) mod aligned;
+pub(crate) mod buffer_pool;
+pub(crate) mod cache;
+pub(crate) mod crc32;
+pub(crate) mod encoding;
+pub(crate) mod free_list;
+#[cfg(feature = "lz4")]
+pub(crate) mod lz4;
+pub(crate) mod memory;
+pub(crate) mod shared;
+pub(crate) mod storage;
+pub(crate) mod value_pages;
+
+/// Size of a node page unless configured otherwise.
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+/// Smallest supported node page. Anything smaller can't hold a node with a
+/// useful number of keys.
+pub const MIN_PAGE_SIZE: usize = 512;
+/// The header page keeps this size whatever the node page size is, so the
+/// header can be read before the page size stored in it is known.
+pub(crate) const HEADER_SIZE: usize = 4096;
+/// Header writes alternate between this many header pages at the start of
+/// the file. A write torn by a crash leaves the other one intact, holding
+/// the commit before.
+pub(crate) const HEADER_SLOTS: usize = 2;
+/// Node pages follow the header pages directly, so the file is the header
+/// pages and a whole number of node pages.
+pub(crate) const STARTUP_OFFSET: usize = HEADER_SLOTS * HEADER_SIZE;
+/// Pages kept in memory by a file-backed pager unless told otherwise.
+pub(crate) const DEFAULT_CACHE_PAGES: usize = 256;
+/// Every node page starts with a CRC32 of the encoded node that follows it.
+const CHECKSUM_SIZE: usize = 4;
+/// The length of the encoded node follows the checksum, so the checksum is
+/// verified over exactly the stored bytes before any of them are decoded. A
+/// compressed page stores the compressed length after it as well.
+const LENGTH_SIZE: usize = 4;
+
+pub type Offset = usize;
+
+/// How node pages are compressed before they are written. The choice is
+/// stored in the header, so a reopened tree keeps the one it was created
+/// with. There is no Zstd scheme: the crate has no Zstd codec to build it
+/// on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub enum Compression {
+    #[default]
+    None,
+    /// LZ4 block format. Nodes with long or repetitive values then fit in
+    /// pages they would overflow otherwise. Needs the `lz4` feature; a
+    /// build without it refuses to create or open a tree using it.
+    Lz4,
+}
+
+#[cfg(not(feature = "lz4"))]
+const LZ4_UNSUPPORTED: &str = "LZ4 compression needs the `lz4` feature";
+
+impl Compression {
+    /// Fails if this build can't read and write pages compressed this way.
+    pub(crate) fn check_supported(self) -> anyhow::Result<()> {
+        match self {
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => anyhow::bail!(LZ4_UNSUPPORTED),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a header page holds, see [`HEADER_SLOTS`].
+enum HeaderSlot {
+    /// No header, as in a file that was never written.
+    Empty,
+    /// A header whose checksum doesn't match, left by a torn write.
+    Torn,
+    Valid(Header),
+}
+
+/// Where leaves keep their values. The choice is stored in the header, so
+/// a reopened tree keeps the one it was created with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub enum ValueStorage {
+    /// Values are encoded in the leaf page next to their keys.
+    #[default]
+    Inline,
+    /// Leaf pages hold only the keys and a reference to each value, and the
+    /// values go to value pages of their own. A leaf then fits as many keys
+    /// as its page holds however large the values are, and a value may be
+    /// larger than a page. Reading a leaf reads its value pages too, and
+    /// writing it rewrites them unless its values are unchanged.
+    OutOfLine,
+}
+
+/// Read access to the pages of a tree.
+pub trait PageRead<K, V> {
+    /// Reads the node stored at `offset`. Takes `&self` so any number of
+    /// readers can share the operator.
+    fn read_at(&self, offset: usize) -> anyhow::Result<Node<K, V>>;
+}
+
+/// Read access that readers on other threads share with the operator the
+/// tree writes through, see [`PageOperator::share`].
+pub trait SharedPages<K, V>: PageRead<K, V> + Send + Sync {
+    /// Waits until no operation is rewriting pages and returns the root of
+    /// the last commit. Pages stay as of that commit until `end_read`.
+    fn begin_read(&self) -> Option<Offset>;
+    fn end_read(&self);
+}
+
+/// Storage backend the tree reads and writes its pages through. The pages
+/// are the crate's [`Node`](crate::Node)s and the header a
+/// [`Header`](crate::Header), so an operator outside the crate can store
+/// them however it likes.
+pub trait PageOperator<K, V>: PageRead<K, V> {
+    /// Offset the next call to `write` will use.
+    fn next_offset(&self) -> usize;
+    /// Reserves a page, reusing a freed one before extending the storage.
+    fn allocate(&mut self) -> usize;
+    fn write_at(&mut self, node: &Node<K, V>, offset: usize) -> anyhow::Result<()>;
+
+    fn write(&mut self, node: &Node<K, V>) -> anyhow::Result<usize> {
+        let offset = self.allocate();
+        self.write_at(node, offset)?;
+        Ok(offset)
+    }
+
+    /// Marks a page as no longer referenced by the tree. It may be reused
+    /// once the next header write is durable: the header before it still
+    /// points at the page until then.
+    fn free(&mut self, offset: usize);
+    /// Abandons the pages freed since the last header write after a failed
+    /// operation. Pages written meanwhile are leaked rather than reused.
+    fn rollback(&mut self);
+    /// Number of pages the storage spans, including the header page.
+    fn size_pages(&self) -> usize;
+    /// Reads the header page and restores the allocation state stored in it.
+    fn read_header(&mut self) -> anyhow::Result<Header>;
+    /// Writes the header page, filling in the pager's allocation state.
+    fn write_header(&mut self, header: &Header) -> anyhow::Result<()>;
+
+    /// Drops every page and rewinds allocation to the startup offset. The
+    /// header is left for the caller to rewrite.
+    fn clear(&mut self) -> anyhow::Result<()>;
+
+    /// Forces everything written so far to durable storage, the last
+    /// header included.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Largest encoded key and value an entry may have unless the tree
+    /// sets limits of its own. Operators without pages accept any size.
+    fn entry_limits(&self) -> (usize, usize) {
+        (usize::MAX, usize::MAX)
+    }
+
+    /// Configuration keys and values are encoded with, to measure them
+    /// against the entry limits.
+    fn encoding(&self) -> Encoding {
+        Encoding::default()
+    }
+
+    /// `options` with the page layout of this operator set: page size,
+    /// compression, encoding and value storage, so that a tree built from
+    /// them stores pages the way this one does. Operators without pages
+    /// leave `options` as they are.
+    fn page_options(&self, options: TreeOptions) -> TreeOptions {
+        options
+    }
+
+    /// Reads the bytes of the node page at `offset` as stored, bypassing
+    /// the page cache. Fails for an offset that isn't the start of a node
+    /// page or lies beyond the end of the storage, and for operators that
+    /// don't store pages as bytes.
+    fn page_bytes(&self, offset: usize) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("page operator has no raw page at offset {offset}")
+    }
+
+    /// Decodes the node page at `offset` as stored, like `page_bytes` and
+    /// failing where it does, from a single read of the page.
+    fn page_node(&self, offset: usize) -> anyhow::Result<Node<K, V>> {
+        anyhow::bail!("page operator has no raw page at offset {offset}")
+    }
+
+    /// Hit and miss counters of the page cache, if the operator has one.
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+
+    /// Counters of the reads and writes made against the storage, if the
+    /// operator has any.
+    fn io_stats(&self) -> IoStats {
+        IoStats::default()
+    }
+
+    /// Returns a handle other threads can read committed pages through
+    /// while this operator keeps writing.
+    fn share(&self) -> anyhow::Result<Arc<dyn SharedPages<K, V>>> {
+        anyhow::bail!("page operator can't be shared with other threads")
+    }
+}
+
+/// Page operator a tree holds: `dyn PageOperator<K, V>`, which any
+/// operator boxes into, or `dyn PageOperator<K, V> + Send + Sync`, which
+/// leaves the tree `Send + Sync` too, see
+/// [`SharedBPTree`](crate::SharedBPTree). Sealed, as the tree knows these
+/// two only.
+pub trait DynOperator<K, V>: PageOperator<K, V> + sealed::Sealed<K, V> {
+    fn operator(&self) -> &dyn PageOperator<K, V>;
+    fn operator_mut(&mut self) -> &mut dyn PageOperator<K, V>;
+}
+
+impl<K, V> DynOperator<K, V> for dyn PageOperator<K, V> {
+    fn operator(&self) -> &dyn PageOperator<K, V> {
+        self
+    }
+
+    fn operator_mut(&mut self) -> &mut dyn PageOperator<K, V> {
+        self
+    }
+}
+
+impl<K, V> DynOperator<K, V> for dyn PageOperator<K, V> + Send + Sync {
+    fn operator(&self) -> &dyn PageOperator<K, V> {
+        self
+    }
+
+    fn operator_mut(&mut self) -> &mut dyn PageOperator<K, V> {
+        self
+    }
+}
+
+pub(crate) mod sealed {
+    use super::{PageOperator, Pager};
+
+    pub trait Sealed<K, V> {
+        /// Boxes the pager of a file-backed tree.
+        fn from_pager(pager: Pager) -> Box<Self>
+        where
+            Pager: PageOperator<K, V>;
+    }
+
+    impl<K, V> Sealed<K, V> for dyn PageOperator<K, V> {
+        fn from_pager(pager: Pager) -> Box<Self>
+        where
+            Pager: PageOperator<K, V>,
+        {
+            Box::new(pager)
+        }
+    }
+
+    impl<K, V> Sealed<K, V> for dyn PageOperator<K, V> + Send + Sync {
+        fn from_pager(pager: Pager) -> Box<Self>
+        where
+            Pager: PageOperator<K, V>,
+        {
+            Box::new(pager)
+        }
+    }
+}
+
+/// Page operator of a file-backed tree. Public only so the sealed half of
+/// [`DynOperator`] can name it; the crate doesn't export it.
+pub struct Pager {
+    storage: Arc<Storage>,
+    startup_offset: usize,
+    page_size: usize,
+    compression: Compression,
+    cursor: usize,
+    free_list: FreeList,
+    cache: Arc<Mutex<PageCache>>,
+    /// Pages written since the last header write that haven't reached the
+    /// storage yet.
+    buffer: BufferPool,
+    encoding: Encoding,
+    value_storage: ValueStorage,
+    /// Reused by compressed page writes to encode the node before it is
+    /// compressed.
+    #[cfg(feature = "lz4")]
+    scratch: Vec<u8>,
+    /// A page buffer the cache let go of, which the next page write is
+    /// framed into instead of a new one.
+    spare: Option<Vec<u8>>,
+    /// Pages start on a multiple of the page size, so the file suits
+    /// direct I/O.
+    direct_io: bool,
+    /// Pages allocated since the last header write, tracked with
+    /// out-of-line values.
+    fresh: HashSet<Offset>,
+    /// Fresh pages written since the last header write. With out-of-line
+    /// values, the value index of a fresh page is only trusted once it has
+    /// been written, as a reused page still holds whatever was freed there.
+    written: HashSet<Offset>,
+    /// Keeps shared readers off the pages while an operation rewrites them.
+    gate: Arc<Gate>,
+    /// Sequence number of the last header read or written. The next one
+    /// goes to the other header slot.
+    sequence: u64,
+}
+
+impl Pager {
+    /// Creates a pager over `storage` with pages of `page_size` bytes that
+    /// keeps up to `cache_pages` recently used pages in memory. Reading the
+    /// header replaces the page size with the one the file was created with.
+    pub(crate) fn new(
+        storage: Box<dyn ReadWriteSeek>,
+        startup_offset: usize,
+        page_size: usize,
+        cache_pages: usize,
+    ) -> Self {
+        Self {
+            storage: Arc::new(Storage::new(storage)),
+            startup_offset,
+            page_size,
+            compression: Compression::None,
+            cursor: startup_offset,
+            free_list: FreeList::default(),
+            cache: Arc::new(Mutex::new(PageCache::new(cache_pages))),
+            buffer: BufferPool::new(0),
+            encoding: Encoding::default(),
+            value_storage: ValueStorage::Inline,
+            #[cfg(feature = "lz4")]
+            scratch: Vec::new(),
+            spare: None,
+            direct_io: false,
+            fresh: HashSet::new(),
+            written: HashSet::new(),
+            gate: Arc::new(Gate::default()),
+            sequence: 0,
+        }
+    }
+
+    /// Compresses the pages written from now on with `compression`. Reading
+    /// the header replaces it with the one the file was created with.
+    pub(crate) fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encodes the pages written from now on with `encoding`. Reading the
+    /// header replaces it with the one the file was created with.
+    pub(crate) fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Stores the values of the leaves written from now on as
+    /// `value_storage` says. Reading the header replaces it with the one the
+    /// file was created with.
+    pub(crate) fn with_value_storage(mut self, value_storage: ValueStorage) -> Self {
+        self.value_storage = value_storage;
+        self
+    }
+
+    /// Lays out the pages from now on for direct I/O: the first node page
+    /// moves up to a multiple of the page size. Reading the header replaces
+    /// the choice with the one the file was created with.
+    #[cfg(feature = "direct-io")]
+    pub(crate) fn with_direct_io(mut self) -> Self {
+        self.direct_io = true;
+        self.startup_offset = self.startup_offset.next_multiple_of(self.page_size);
+        self.cursor = self.startup_offset;
+        self
+    }
+
+    /// Holds up to `buffer_pages` written pages back until the next header
+    /// write, writing out the oldest when more are written.
+    pub(crate) fn with_buffer_pool(mut self, buffer_pages: usize) -> Self {
+        self.buffer = BufferPool::new(buffer_pages);
+        self
+    }
+
+    /// Whether the storage starts with a tree header in either slot, as
+    /// opposed to being empty or holding something else.
+    pub(crate) fn has_header(&self) -> std::io::Result<bool> {
+        for slot in 0..HEADER_SLOTS {
+            let mut magic = [0x00; MAGIC.len()];
+            let read = self.storage.read_at(&mut magic, (slot * HEADER_SIZE) as u64)?;
+            if read == MAGIC.len() && magic == MAGIC {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reads the header page of `slot`. The checksum in its last bytes
+    /// covers the rest of the page. A header of another format version is
+    /// refused before the checksum is looked at, as that version may not
+    /// have one.
+    fn read_header_slot(&self, slot: usize) -> anyhow::Result<HeaderSlot> {
+        let mut buffer: [u8; HEADER_SIZE] = [0x00; HEADER_SIZE];
+        self.storage.read_at(&mut buffer, (slot * HEADER_SIZE) as u64)?;
+        if !buffer.starts_with(&MAGIC) {
+            return Ok(HeaderSlot::Empty);
+        }
+
+        let ((_, format_version), _): (([u8; 4], u16), usize) = Encoding::default().decode(&buffer)?;
+        if format_version > FORMAT_VERSION {
+            return Err(BPTreeError::UnsupportedFormat { version: format_version }.into());
+        }
+        if format_version < FORMAT_VERSION {
+            return Err(BPTreeError::OutdatedFormat { version: format_version }.into());
+        }
+
+        let (data, stored) = buffer.split_at(HEADER_SIZE - CHECKSUM_SIZE);
+        if crc32::checksum(data).to_le_bytes() != stored {
+            return Ok(HeaderSlot::Torn);
+        }
+        let (header, _): (Header, usize) = Encoding::default().decode(data)?;
+        Ok(HeaderSlot::Valid(header))
+    }
+
+    fn cache(&self) -> MutexGuard<'_, PageCache> {
+        lock_cache(&self.cache)
+    }
+
+    /// Encodes `page` straight into its frame behind the checksum. The
+    /// frame is a buffer the cache let go of where there is one, so once
+    /// the cache is full, writing pages allocates none.
+    fn encode_page<T: Encode>(&mut self, page: &T) -> anyhow::Result<Vec<u8>> {
+        let mut data = self.spare.take().unwrap_or_else(|| Vec::with_capacity(self.page_size));
+        data.clear();
+        data.extend([0x00; CHECKSUM_SIZE]);
+        match self.compression {
+            Compression::None => {
+                data.extend([0x00; LENGTH_SIZE]);
+                self.encoding.encode_into(page, &mut data)?;
+                let encoded_length = (data.len() - CHECKSUM_SIZE - LENGTH_SIZE) as u32;
+                data[CHECKSUM_SIZE..CHECKSUM_SIZE + LENGTH_SIZE].copy_from_slice(&encoded_length.to_le_bytes());
+            },
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => anyhow::bail!(LZ4_UNSUPPORTED),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                self.scratch.clear();
+                self.encoding.encode_into(page, &mut self.scratch)?;
+                data.extend((self.scratch.len() as u32).to_le_bytes());
+                data.extend([0x00; LENGTH_SIZE]);
+                lz4::compress(&self.scratch, &mut data);
+                let compressed_length = (data.len() - CHECKSUM_SIZE - 2 * LENGTH_SIZE) as u32;
+                data[CHECKSUM_SIZE + LENGTH_SIZE..CHECKSUM_SIZE + 2 * LENGTH_SIZE].copy_from_slice(&compressed_length.to_le_bytes());
+            },
+        }
+        let checksum = crc32::checksum(&data[CHECKSUM_SIZE..]);
+        data[..CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        Ok(data)
+    }
+
+    /// Whether the page at `offset` holds a node written by this tree, so
+    /// its value index can be trusted.
+    fn is_written(&self, offset: Offset) -> bool {
+        !self.fresh.contains(&offset) || self.written.contains(&offset)
+    }
+
+    /// Reads the value index of the node page at `offset`.
+    fn value_index<K: Decode + 'static, V: Decode + 'static>(&self, offset: Offset) -> anyhow::Result<ValueIndex> {
+        let (index, _): (ValueIndex, Node<K, V>) = match self.buffer.get(offset) {
+            Some(data) => decode_page(data, data.len(), offset, self.compression, self.encoding)?,
+            None => read_page(&self.storage, &self.cache, offset, self.page_size, self.compression, self.encoding)?,
+        };
+        Ok(index)
+    }
+
+    /// Writes out every page the buffer pool holds.
+    fn write_back(&mut self) -> anyhow::Result<()> {
+        for (offset, data) in self.buffer.drain() {
+            self.storage.write_at(&data, offset as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the values of `leaf_node`, which is about to be written at
+    /// `offset`, to value pages and returns their index. The value pages
+    /// of the leaf written there before are kept if they hold the same
+    /// bytes, as they do when one operation writes a leaf more than once
+    /// without changing its values, and freed otherwise.
+    fn write_values<K: Decode + 'static, V: Encode + Decode + 'static>(
+        &mut self,
+        leaf_node: &LeafNode<K, V>,
+        offset: Offset,
+    ) -> anyhow::Result<ValueIndex> {
+        let (data, refs) = value_pages::encode_values(&leaf_node.values, self.encoding)?;
+
+        if self.is_written(offset) {
+            let previous = self.value_index::<K, V>(offset)?;
+            if previous.refs == refs && value_pages::read_bytes(&self.storage, &previous, self.page_size)? == data {
+                return Ok(previous);
+            }
+            for page in previous.pages {
+                self.free_list.free(page);
+            }
+        }
+
+        let pages = (0..data.len().div_ceil(self.page_size)).map(|_| self.allocate_page()).collect::<Vec<_>>();
+        let mut data = data;
+        data.resize(pages.len() * self.page_size, 0x00);
+        self.gate.begin_write();
+        value_pages::write_bytes(&self.storage, &pages, self.page_size, &data)?;
+        Ok(ValueIndex { pages, refs })
+    }
+
+    /// Reads the node page at `offset` from storage, bypassing the page
+    /// cache, and returns it with the number of bytes read. Node pages end
+    /// at the cursor, so any page starts a whole number of pages before
+    /// it, wherever the first page was put.
+    fn stored_page(&self, offset: usize) -> anyhow::Result<(Vec<u8>, usize)> {
+        if offset >= self.cursor {
+            anyhow::bail!("offset {offset} is beyond the end of the file at {}", self.cursor);
+        }
+        if offset < HEADER_SLOTS * HEADER_SIZE || !(self.cursor - offset).is_multiple_of(self.page_size) {
+            anyhow::bail!("offset {offset} is not the start of a page");
+        }
+
+        let mut buffer = vec![0x00; self.page_size];
+        let read = self.storage.read_at(&mut buffer, offset as u64)?;
+        if read == 0 {
+            anyhow::bail!("offset {offset} is beyond the end of the file");
+        }
+        Ok((buffer, read))
+    }
+
+    fn allocate_page(&mut self) -> Offset {
+        let offset = match self.free_list.pop() {
+            Some(offset) => offset,
+            None => {
+                self.cursor += self.page_size;
+                self.cursor - self.page_size
+            },
+        };
+
+        if self.value_storage == ValueStorage::OutOfLine {
+            self.fresh.insert(offset);
+        }
+        offset
+    }
+}
+
+fn lock_cache(cache: &Mutex<PageCache>) -> MutexGuard<'_, PageCache> {
+    // The cache holds no invariant a panicking reader could break, so a
+    // poisoned lock is still safe to use.
+    cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Reads the node at `offset` through `cache`, loading the page from
+/// `storage` on a miss, and the values of a leaf from its value pages if
+/// they are stored out of line.
+fn read_node<K: Decode + 'static, V: Decode + 'static>(
+    storage: &Storage,
+    cache: &Mutex<PageCache>,
+    offset: usize,
+    page_size: usize,
+    compression: Compression,
+    encoding: Encoding,
+    value_storage: ValueStorage,
+) -> anyhow::Result<Node<K, V>> {
+    if value_storage == ValueStorage::Inline {
+        return read_page(storage, cache, offset, page_size, compression, encoding);
+    }
+
+    let page = read_page(storage, cache, offset, page_size, compression, encoding)?;
+    read_values(storage, page, page_size, encoding)
+}
+
+/// Fills in the values of a leaf read from a page that stores them out of
+/// line, from the value pages its index points at.
+fn read_values<K, V: Decode + 'static>(
+    storage: &Storage,
+    (index, mut node): (ValueIndex, Node<K, V>),
+    page_size: usize,
+    encoding: Encoding,
+) -> anyhow::Result<Node<K, V>> {
+    if let Node::Leaf(leaf_node) = &mut node {
+        leaf_node.values = value_pages::read_values(storage, &index, page_size, encoding)?;
+    }
+    Ok(node)
+}
+
+/// Reads the page at `offset` through `cache`, loading it from `storage`
+/// on a miss.
+fn read_page<T: Decode>(
+    storage: &Storage,
+    cache: &Mutex<PageCache>,
+    offset: usize,
+    page_size: usize,
+    compression: Compression,
+    encoding: Encoding,
+) -> anyhow::Result<T> {
+    if let Some(data) = lock_cache(cache).get(offset) {
+        return decode_page(data, data.len(), offset, compression, encoding);
+    }
+
+    let mut buffer = vec![0x00; page_size];
+    let read = storage.read_at(&mut buffer, offset as u64)?;
+    let page = decode_page(&buffer, read, offset, compression, encoding)?;
+    lock_cache(cache).put(offset, buffer);
+    Ok(page)
+}
+
+/// Decodes the page stored in `buffer`, of which only the first
+/// `available` bytes were read from storage. A page that needs more than
+/// that was cut short, which is reported as an unexpected end of file
+/// rather than a checksum mismatch.
+fn decode_page<T: Decode>(
+    buffer: &[u8],
+    available: usize,
+    offset: usize,
+    compression: Compression,
+    encoding: Encoding,
+) -> anyhow::Result<T> {
+    let mut stored = [0x00; CHECKSUM_SIZE];
+    stored.copy_from_slice(&buffer[..CHECKSUM_SIZE]);
+    let verify = |length: usize| -> anyhow::Result<()> {
+        let end = CHECKSUM_SIZE + length;
+        if end > available && available < buffer.len() {
+            let message = format!("page at offset {offset} ends after {available} bytes");
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message).into());
+        }
+
+        let checksum = buffer.get(CHECKSUM_SIZE..end).map(crc32::checksum);
+        match checksum == Some(u32::from_le_bytes(stored)) {
+            true => Ok(()),
+            false => Err(BPTreeError::Corrupt { offset }.into()),
+        }
+    };
+
+    match compression {
+        Compression::None => {
+            let length = &buffer[CHECKSUM_SIZE..CHECKSUM_SIZE + LENGTH_SIZE];
+            let encoded_length = u32::from_le_bytes(length.try_into()?) as usize;
+            verify(LENGTH_SIZE + encoded_length)?;
+
+            let start = CHECKSUM_SIZE + LENGTH_SIZE;
+            let (node, _) = encoding.decode(&buffer[start..start + encoded_length])?;
+            Ok(node)
+        },
+        #[cfg(not(feature = "lz4"))]
+        Compression::Lz4 => anyhow::bail!(LZ4_UNSUPPORTED),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            let lengths = &buffer[CHECKSUM_SIZE..CHECKSUM_SIZE + 2 * LENGTH_SIZE];
+            let encoded_length = u32::from_le_bytes(lengths[..LENGTH_SIZE].try_into()?) as usize;
+            let compressed_length = u32::from_le_bytes(lengths[LENGTH_SIZE..].try_into()?) as usize;
+            verify(2 * LENGTH_SIZE + compressed_length)?;
+
+            let start = CHECKSUM_SIZE + 2 * LENGTH_SIZE;
+            let encoded = lz4::decompress(&buffer[start..start + compressed_length], encoded_length)?;
+            let (node, _) = encoding.decode(&encoded)?;
+            Ok(node)
+        },
+    }
+}
+
+impl<K: Decode + 'static, V: Decode + 'static> PageRead<K, V> for Pager {
+    fn read_at(&self, offset: usize) -> anyhow::Result<Node<K, V>> {
+        if let Some(data) = self.buffer.get(offset) {
+            return match self.value_storage {
+                ValueStorage::Inline => decode_page(data, data.len(), offset, self.compression, self.encoding),
+                ValueStorage::OutOfLine => {
+                    let page = decode_page(data, data.len(), offset, self.compression, self.encoding)?;
+                    read_values(&self.storage, page, self.page_size, self.encoding)
+                },
+            };
+        }
+        read_node(&self.storage, &self.cache, offset, self.page_size, self.compression, self.encoding, self.value_storage)
+    }
+}
+
+impl<K: Clone + Encode + Decode + 'static, V: Encode + Decode + 'static> PageOperator<K, V> for Pager {
+    fn next_offset(&self) -> usize {
+        self.free_list.peek().unwrap_or(self.cursor)
+    }
+
+    fn allocate(&mut self) -> usize {
+        self.allocate_page()
+    }
+
+    fn write_at(&mut self, node: &Node<K, V>, offset: usize) -> anyhow::Result<()> {
+        let mut data = match (self.value_storage, node) {
+            (ValueStorage::Inline, _) => self.encode_page(node)?,
+            (ValueStorage::OutOfLine, Node::Internal(_)) => self.encode_page(&(ValueIndex::default(), node))?,
+            (ValueStorage::OutOfLine, Node::Leaf(leaf_node)) => {
+                let index = self.write_values(leaf_node, offset)?;
+                let keys_only = Node::Leaf(LeafNode::<K, V> {
+                    keys: leaf_node.keys.clone(),
+                    values: Vec::new(),
+                    offset: leaf_node.offset,
+                });
+                self.encode_page(&(index, keys_only))?
+            },
+        };
+        if data.len() > self.page_size {
+            let size = data.len();
+            return Err(BPTreeError::PageOverflow { size, page_size: self.page_size }.into());
+        }
+        // Pages are written whole, so the file is a clean array of pages
+        // and a read never takes in bytes left over from another write.
+        data.resize(self.page_size, 0x00);
+
+        self.gate.begin_write();
+
+        if self.buffer.is_enabled() {
+            let released = self.cache().put(offset, data.clone());
+            self.spare = released;
+            for (offset, data) in self.buffer.put(offset, data) {
+                self.storage.write_at(&data, offset as u64)?;
+                self.spare = Some(data);
+            }
+        } else {
+            self.storage.write_at(&data, offset as u64)?;
+            let released = self.cache().put(offset, data);
+            self.spare = released;
+        }
+        if self.fresh.contains(&offset) {
+            self.written.insert(offset);
+        }
+        Ok(())
+    }
+
+    fn free(&mut self, offset: usize) {
+        if self.value_storage == ValueStorage::OutOfLine && self.is_written(offset) {
+            // A page that can't be read back leaks its value pages rather
+            // than failing the operation that released it.
+            if let Ok(index) = self.value_index::<K, V>(offset) {
+                for page in index.pages {
+                    self.free_list.free(page);
+                }
+            }
+        }
+        // The tree no longer refers to the page, so whatever was last
+        // written there needn't reach the storage.
+        self.buffer.discard(offset);
+        self.free_list.free(offset);
+    }
+
+    fn rollback(&mut self) {
+        // The buffer only holds copies the failed operation wrote, none of
+        // which the committed tree refers to.
+        self.buffer.clear();
+        self.free_list.rollback();
+        self.gate.end_write(None);
+    }
+
+    fn size_pages(&self) -> usize {
+        self.cursor.div_ceil(self.page_size)
+    }
+
+    /// Picks the newest of the header slots that holds a valid header.
+    fn read_header(&mut self) -> anyhow::Result<Header> {
+        let mut newest: Option<Header> = None;
+        let mut torn = None;
+        for slot in 0..HEADER_SLOTS {
+            match self.read_header_slot(slot)? {
+                HeaderSlot::Empty => {},
+                HeaderSlot::Torn => torn = Some(slot * HEADER_SIZE),
+                HeaderSlot::Valid(header) => {
+                    if newest.as_ref().is_none_or(|newest| header.sequence > newest.sequence) {
+                        newest = Some(header);
+                    }
+                },
+            }
+        }
+        let header = match (newest, torn) {
+            (Some(header), _) => header,
+            (None, Some(offset)) => return Err(BPTreeError::Corrupt { offset }.into()),
+            (None, None) => anyhow::bail!("file does not contain a tree header"),
+        };
+
+        self.sequence = header.sequence;
+        self.page_size = header.page_size;
+        header.compression.check_supported()?;
+        self.compression = header.compression;
+        self.encoding = header.encoding;
+        self.value_storage = header.value_storage;
+        self.direct_io = header.direct_io;
+        if self.direct_io {
+            self.startup_offset = self.startup_offset.next_multiple_of(self.page_size);
+        }
+        self.cursor = header.cursor;
+        self.free_list = FreeList::new(header.free_pages.clone(), header.retained_pages.clone(), header.cursor);
+        // Another writer may have reused the cached pages since they were
+        // read.
+        self.cache().clear();
+        self.gate.end_write(Some(header.root_node));
+        Ok(header)
+    }
+
+    fn write_header(&mut self, header: &Header) -> anyhow::Result<()> {
+        let mut header = Header {
+            cursor: self.cursor,
+            free_pages: self.free_list.commit(!header.snapshots.is_empty()),
+            retained_pages: self.free_list.retained().to_vec(),
+            page_size: self.page_size,
+            compression: self.compression,
+            encoding: self.encoding,
+            value_storage: self.value_storage,
+            direct_io: self.direct_io,
+            magic: MAGIC,
+            format_version: FORMAT_VERSION,
+            sequence: self.sequence + 1,
+            ..header.clone()
+        };
+
+        let mut data: Vec<u8> = Encoding::default().encode(&header)?;
+        while data.len() > HEADER_SIZE - CHECKSUM_SIZE {
+            // Pages that don't fit in the header are leaked rather than
+            // overflowing into the first node page.
+            if header.free_pages.pop().is_none() && header.retained_pages.pop().is_none() {
+                anyhow::bail!("header does not fit in {HEADER_SIZE} bytes");
+            }
+            data = Encoding::default().encode(&header)?;
+        }
+        data.resize(HEADER_SIZE - CHECKSUM_SIZE, 0x00);
+        data.extend(crc32::checksum(&data).to_le_bytes());
+        let slot = header.sequence as usize % HEADER_SLOTS;
+
+        // The pages of the commit are written before the header that points
+        // at them. Making them durable first is up to the tree, which
+        // flushes before a header it is about to sync.
+        self.write_back()?;
+        self.storage.write_at(&data, (slot * HEADER_SIZE) as u64)?;
+        self.sequence = header.sequence;
+
+        self.fresh.clear();
+        self.written.clear();
+        self.gate.end_write(Some(header.root_node));
+        Ok(())
+    }
+
+    fn clear(&mut self) -> anyhow::Result<()> {
+        self.gate.begin_write();
+        self.cursor = self.startup_offset;
+        self.free_list = FreeList::default();
+        self.fresh.clear();
+        self.written.clear();
+        self.buffer.clear();
+        self.cache().clear();
+        self.storage.set_len((HEADER_SLOTS * HEADER_SIZE) as u64)?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_back()?;
+        self.storage.sync()?;
+        self.free_list.synced(self.cursor);
+        Ok(())
+    }
+
+    /// A key takes at most an eighth of a page and a value a third, so a
+    /// leaf always has room for a couple of entries. Values are only
+    /// limited while they are stored uncompressed in the leaf page.
+    fn entry_limits(&self) -> (usize, usize) {
+        let max_value_len = match (self.value_storage, self.compression) {
+            (ValueStorage::Inline, Compression::None) => self.page_size / 3,
+            _ => usize::MAX,
+        };
+        (self.page_size / 8, max_value_len)
+    }
+
+    fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    fn page_options(&self, options: TreeOptions) -> TreeOptions {
+        TreeOptions {
+            page_size: self.page_size,
+            compression: self.compression,
+            encoding: self.encoding,
+            value_storage: self.value_storage,
+            #[cfg(feature = "direct-io")]
+            direct_io: self.direct_io,
+            ..options
+        }
+    }
+
+    fn page_bytes(&self, offset: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(self.stored_page(offset)?.0)
+    }
+
+    fn page_node(&self, offset: usize) -> anyhow::Result<Node<K, V>> {
+        let (buffer, read) = self.stored_page(offset)?;
+        match self.value_storage {
+            ValueStorage::Inline => decode_page(&buffer, read, offset, self.compression, self.encoding),
+            ValueStorage::OutOfLine => {
+                let page = decode_page(&buffer, read, offset, self.compression, self.encoding)?;
+                read_values(&self.storage, page, self.page_size, self.encoding)
+            },
+        }
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache().stats()
+    }
+
+    fn io_stats(&self) -> IoStats {
+        self.storage.stats()
+    }
+
+    fn share(&self) -> anyhow::Result<Arc<dyn SharedPages<K, V>>> {
+        Ok(Arc::new(SharedPager {
+            storage: Arc::clone(&self.storage),
+            cache: Arc::clone(&self.cache),
+            page_size: self.page_size,
+            compression: self.compression,
+            encoding: self.encoding,
+            value_storage: self.value_storage,
+            gate: Arc::clone(&self.gate),
+        }))
+    }
+}
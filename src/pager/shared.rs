@@ -0,0 +1,94 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use bincode::Decode;
+
+use super::cache::PageCache;
+use super::storage::Storage;
+use super::{read_node, Compression, Encoding, ValueStorage, Offset, PageRead, SharedPages};
+use crate::node::Node;
+
+/// Hands the pages of a `Pager` back and forth between its writer and the
+/// readers sharing it. The writer holds the gate from its first page write
+/// until the next header write or rollback, so it can span any number of
+/// calls; readers hold it for the length of a read.
+#[derive(Default)]
+pub(crate) struct Gate {
+    state: Mutex<GateState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct GateState {
+    writing: bool,
+    readers: usize,
+    /// Root of the last commit, the version readers see.
+    root_node: Option<Offset>,
+}
+
+impl Gate {
+    fn state(&self) -> MutexGuard<'_, GateState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Waits for the readers to finish, unless the writer already holds
+    /// the gate.
+    pub(crate) fn begin_write(&self) {
+        let mut state = self.state();
+        while !state.writing && state.readers > 0 {
+            state = self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.writing = true;
+    }
+
+    /// Lets readers back in, at `root_node` if a new version was committed.
+    pub(crate) fn end_write(&self, root_node: Option<Option<Offset>>) {
+        let mut state = self.state();
+        if let Some(root_node) = root_node {
+            state.root_node = root_node;
+        }
+        state.writing = false;
+        self.changed.notify_all();
+    }
+
+    fn begin_read(&self) -> Option<Offset> {
+        let mut state = self.state();
+        while state.writing {
+            state = self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        state.readers += 1;
+        state.root_node
+    }
+
+    fn end_read(&self) {
+        let mut state = self.state();
+        state.readers -= 1;
+        self.changed.notify_all();
+    }
+}
+
+/// Read side of a `Pager`, sharing its storage and page cache.
+pub(crate) struct SharedPager {
+    pub(crate) storage: Arc<Storage>,
+    pub(crate) cache: Arc<Mutex<PageCache>>,
+    pub(crate) page_size: usize,
+    pub(crate) compression: Compression,
+    pub(crate) encoding: Encoding,
+    pub(crate) value_storage: ValueStorage,
+    pub(crate) gate: Arc<Gate>,
+}
+
+impl<K: Decode + 'static, V: Decode + 'static> PageRead<K, V> for SharedPager {
+    fn read_at(&self, offset: usize) -> anyhow::Result<Node<K, V>> {
+        read_node(&self.storage, &self.cache, offset, self.page_size, self.compression, self.encoding, self.value_storage)
+    }
+}
+
+impl<K: Decode + 'static, V: Decode + 'static> SharedPages<K, V> for SharedPager {
+    fn begin_read(&self) -> Option<Offset> {
+        self.gate.begin_read()
+    }
+
+    fn end_read(&self) {
+        self.gate.end_read()
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "direct-io")]
+use super::aligned::{AlignedBuf, ALIGNMENT};
+
+/// Storage a file-backed tree keeps its pages in: a `File`, or anything
+/// else that reads, writes and seeks, such as a `Cursor<Vec<u8>>` or a
+/// memory-mapped buffer.
+///
+/// The provided methods cover what `Read + Write + Seek` can't express.
+/// Storage that is durable once written and never needs to shrink can
+/// implement the trait without overriding them.
+pub trait ReadWriteSeek: Read + Write + Seek + Send {
+    /// Makes everything written so far durable.
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+
+    /// Truncates or extends the storage to `len` bytes. Storage that can't
+    /// change its size keeps it, leaving the dropped pages in place unused.
+    fn set_len(&mut self, _len: u64) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// The file underneath, if the storage is a plain file. Pages are then
+    /// read from it with positional reads, which let readers on several
+    /// threads read at once instead of taking turns to seek.
+    fn as_file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl ReadWriteSeek for File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.sync_data()
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    #[cfg(any(unix, windows))]
+    fn as_file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl ReadWriteSeek for Cursor<Vec<u8>> {
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.get_mut().resize(len as usize, 0x00);
+        Ok(())
+    }
+}
+
+impl<S: ReadWriteSeek + ?Sized> ReadWriteSeek for Box<S> {
+    fn sync(&mut self) -> std::io::Result<()> {
+        (**self).sync()
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        (**self).set_len(len)
+    }
+
+    fn as_file(&self) -> Option<&File> {
+        (**self).as_file()
+    }
+}
+
+/// Reads and writes a pager made against its storage, the header page
+/// included. Reads served by the page cache are not counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Storage of a pager, counting the I/O made through it. Writes take turns
+/// on a lock, and so do reads unless the storage is a file, which a second
+/// handle reads without moving the cursor the writes seek.
+pub(crate) struct Storage {
+    inner: Mutex<Box<dyn ReadWriteSeek>>,
+    file: Option<File>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Storage {
+    pub(crate) fn new(inner: Box<dyn ReadWriteSeek>) -> Self {
+        // Without a second handle every read goes through the lock.
+        let file = inner.as_file().and_then(|file| file.try_clone().ok());
+        Self {
+            inner: Mutex::new(inner),
+            file,
+            reads: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> IoStats {
+        IoStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Makes everything written so far durable.
+    pub(crate) fn sync(&self) -> std::io::Result<()> {
+        self.lock().sync()
+    }
+
+    pub(crate) fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.lock().set_len(len)
+    }
+
+    /// Fills `buffer` from `offset` and returns how many bytes were read,
+    /// fewer than requested only where the storage ends. Short reads are
+    /// retried until the buffer is full.
+    #[cfg(not(feature = "direct-io"))]
+    pub(crate) fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        self.read_into(buffer, offset)
+    }
+
+    /// Like the plain read, going through an aligned buffer rounded up to
+    /// whole blocks so the storage may be opened for direct I/O. The bytes
+    /// past `buffer` are read but dropped.
+    #[cfg(feature = "direct-io")]
+    pub(crate) fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let mut aligned = AlignedBuf::zeroed(buffer.len().next_multiple_of(ALIGNMENT));
+        let filled = self.read_into(&mut aligned, offset)?.min(buffer.len());
+        buffer[..filled].copy_from_slice(&aligned[..filled]);
+        Ok(filled)
+    }
+
+    /// Writes `data` at `offset`.
+    #[cfg(not(feature = "direct-io"))]
+    pub(crate) fn write_at(&self, data: &[u8], offset: u64) -> std::io::Result<()> {
+        self.write_from(data, offset)
+    }
+
+    /// Writes `data` at `offset` from an aligned copy, so the storage may be
+    /// opened for direct I/O. Callers pad `data` to whole blocks.
+    #[cfg(feature = "direct-io")]
+    pub(crate) fn write_at(&self, data: &[u8], offset: u64) -> std::io::Result<()> {
+        let mut aligned = AlignedBuf::zeroed(data.len());
+        aligned.copy_from_slice(data);
+        self.write_from(&aligned, offset)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn ReadWriteSeek>> {
+        // Every access seeks before it reads or writes, so a panic part way
+        // through one leaves nothing the next would trip over.
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_into(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let filled = match &self.file {
+            Some(file) => fill(buffer, |buffer, filled| read_file_at(file, buffer, offset + filled as u64))?,
+            None => {
+                let mut inner = self.lock();
+                inner.seek(SeekFrom::Start(offset))?;
+                fill(buffer, |buffer, _| inner.read(buffer))?
+            },
+        };
+
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(filled as u64, Ordering::Relaxed);
+        Ok(filled)
+    }
+
+    fn write_from(&self, data: &[u8], offset: u64) -> std::io::Result<()> {
+        let mut inner = self.lock();
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.write_all(data)?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Calls `read` with the unfilled rest of `buffer` and the number of bytes
+/// filled so far until the buffer is full or `read` reaches the end.
+fn fill(
+    buffer: &mut [u8],
+    mut read: impl FnMut(&mut [u8], usize) -> std::io::Result<usize>,
+) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match read(&mut buffer[filled..], filled) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(unix)]
+fn read_file_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+    file.read_at(buffer, offset)
+}
+
+#[cfg(windows)]
+fn read_file_at(file: &File, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    use std::os::windows::fs::FileExt;
+    file.seek_read(buffer, offset)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_file_at(_file: &File, _buffer: &mut [u8], _offset: u64) -> std::io::Result<usize> {
+    unreachable!("as_file is only provided on unix and windows")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_read_in_place() -> anyhow::Result<()> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open("/tmp/storage_positional.ldb")?;
+        let storage = Storage::new(Box::new(file));
+        assert_eq!(storage.file.is_some(), cfg!(any(unix, windows)));
+        let cursor = Storage::new(Box::new(Cursor::new(Vec::new())));
+        assert!(cursor.file.is_none());
+
+        for storage in [&storage, &cursor] {
+            storage.write_at(&[0x01; 4096], 0)?;
+            storage.write_at(&[0x02; 4096], 4096)?;
+
+            let mut buffer = vec![0x00; 4096];
+            assert_eq!(storage.read_at(&mut buffer, 4096)?, 4096);
+            assert!(buffer.iter().all(|byte| *byte == 0x02));
+            // A read must not move where the next write lands.
+            storage.write_at(&[0x03; 4096], 8192)?;
+            assert_eq!(storage.read_at(&mut buffer, 0)?, 4096);
+            assert!(buffer.iter().all(|byte| *byte == 0x01));
+            assert_eq!(storage.read_at(&mut buffer, 8192)?, 4096);
+            assert!(buffer.iter().all(|byte| *byte == 0x03));
+            assert_eq!(storage.read_at(&mut buffer, 12288)?, 0);
+        }
+
+        std::thread::scope(|scope| {
+            for page in 0..3u8 {
+                let storage = &storage;
+                scope.spawn(move || {
+                    let mut buffer = vec![0x00; 4096];
+                    for _ in 0..100 {
+                        storage.read_at(&mut buffer, page as u64 * 4096).unwrap();
+                        assert!(buffer.iter().all(|byte| *byte == page + 1));
+                    }
+                });
+            }
+        });
+        assert_eq!(storage.stats().reads, 4 + 300);
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use bincode::{Decode, Encode};
+
+use super::storage::Storage;
+use super::{crc32, Encoding, Offset};
+use crate::error::BPTreeError;
+
+/// Where one value sits in the bytes of its leaf's value pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub(crate) struct ValueRef {
+    pub(crate) offset: usize,
+    pub(crate) len: usize,
+    pub(crate) checksum: u32,
+}
+
+/// Stored in front of every node page of a tree with out-of-line values:
+/// the pages holding a leaf's values, in order, and a reference to each
+/// value. Internal nodes have neither.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub(crate) struct ValueIndex {
+    pub(crate) pages: Vec<Offset>,
+    pub(crate) refs: Vec<ValueRef>,
+}
+
+impl ValueIndex {
+    /// Number of value bytes the index covers.
+    pub(crate) fn len(&self) -> usize {
+        self.refs.last().map_or(0, |last| last.offset + last.len)
+    }
+}
+
+/// Encodes `values` back to back, returning the bytes and a reference to
+/// each value.
+pub(crate) fn encode_values<V: Encode>(values: &[V], encoding: Encoding) -> anyhow::Result<(Vec<u8>, Vec<ValueRef>)> {
+    let mut data = Vec::new();
+    let mut refs = Vec::with_capacity(values.len());
+    for value in values {
+        let encoded = encoding.encode(value)?;
+        refs.push(ValueRef {
+            offset: data.len(),
+            len: encoded.len(),
+            checksum: crc32::checksum(&encoded),
+        });
+        data.extend(encoded);
+    }
+    Ok((data, refs))
+}
+
+/// Writes `data` across `pages`, one page-sized chunk each.
+pub(crate) fn write_bytes(storage: &Storage, pages: &[Offset], page_size: usize, data: &[u8]) -> std::io::Result<()> {
+    for (page, chunk) in pages.iter().zip(data.chunks(page_size)) {
+        storage.write_at(chunk, *page as u64)?;
+    }
+    Ok(())
+}
+
+/// Reads back the bytes of the value pages of `index`.
+pub(crate) fn read_bytes(storage: &Storage, index: &ValueIndex, page_size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0x00; index.len()];
+    for (page, chunk) in index.pages.iter().zip(data.chunks_mut(page_size)) {
+        let read = storage.read_at(chunk, *page as u64)?;
+        if read < chunk.len() {
+            let message = format!("value page at offset {page} ends after {read} bytes");
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message).into());
+        }
+    }
+    Ok(data)
+}
+
+/// Reads and decodes the values `index` refers to, checking each against
+/// its checksum.
+pub(crate) fn read_values<V: Decode>(
+    storage: &Storage,
+    index: &ValueIndex,
+    page_size: usize,
+    encoding: Encoding,
+) -> anyhow::Result<Vec<V>> {
+    let data = read_bytes(storage, index, page_size)?;
+    index
+        .refs
+        .iter()
+        .map(|value_ref| {
+            let encoded = &data[value_ref.offset..value_ref.offset + value_ref.len];
+            if crc32::checksum(encoded) != value_ref.checksum {
+                let offset = index.pages[value_ref.offset / page_size];
+                return Err(BPTreeError::Corrupt { offset }.into());
+            }
+            Ok(encoding.decode(encoded)?.0)
+        })
+        .collect()
+}
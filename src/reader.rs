@@ -0,0 +1,169 @@
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bincode::{Decode, Encode};
+
+use super::compare::KeyComparator;
+use super::error::Result;
+use super::iter::TreeIter;
+use super::pager::{Offset, SharedPages};
+use super::tree::{walk_range, Key, Value};
+
+/// Read-only handle to a tree that can be cloned and sent to other
+/// threads, see [`BPTree::reader`](super::tree::BPTree::reader).
+///
+/// Every read sees the tree as of its last commit: a read waits for an
+/// operation that is rewriting pages to commit or roll back, and the tree
+/// waits for running reads before it starts rewriting pages. Changes made
+/// inside an unfinished [`BPTree::transaction`](super::tree::BPTree::transaction)
+/// are never visible.
+pub struct TreeReader<K = Key, V = Value> {
+    pages: Arc<dyn SharedPages<K, V>>,
+    comparator: Arc<dyn KeyComparator<K> + Send + Sync>,
+}
+
+impl<K, V> Clone for TreeReader<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            pages: Arc::clone(&self.pages),
+            comparator: Arc::clone(&self.comparator),
+        }
+    }
+}
+
+impl<K, V> TreeReader<K, V>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+{
+    pub(crate) fn new(pages: Arc<dyn SharedPages<K, V>>, comparator: Arc<dyn KeyComparator<K> + Send + Sync>) -> Self {
+        Self { pages, comparator }
+    }
+
+    pub fn search(&self, key: K) -> Result<Option<V>> {
+        let read = ReadGuard::new(self.pages.as_ref());
+        match read.root_node {
+            None => Ok(None),
+            Some(root_offset) => {
+                let root_node = self.pages.read_at(root_offset)?;
+                Ok(root_node.search(self.pages.as_ref(), self.comparator.as_ref(), key)?)
+            },
+        }
+    }
+
+    /// Returns every key/value pair between `start` and `end` in ascending
+    /// key order.
+    pub fn range(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>> {
+        let read = ReadGuard::new(self.pages.as_ref());
+        let mut result = Vec::new();
+        walk_range(self.pages.as_ref(), self.comparator.as_ref(), read.root_node, start, end, |key, value| {
+            result.push((key.clone(), value.clone()));
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Returns a lazy iterator over every key/value pair in ascending key
+    /// order. The tree can't start rewriting pages until the iterator is
+    /// dropped, so a thread must not write to the tree while holding one.
+    pub fn iter(&self) -> ReaderIter<'_, K, V> {
+        let read = ReadGuard::new(self.pages.as_ref());
+        let inner = TreeIter::new(self.pages.as_ref(), read.root_node);
+        ReaderIter { inner, _read: read }
+    }
+}
+
+/// Iterator returned by [`TreeReader::iter`].
+pub struct ReaderIter<'a, K, V> {
+    inner: TreeIter<'a, K, V>,
+    _read: ReadGuard<'a, K, V>,
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for ReaderIter<'_, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// Holds the pages as of the last commit until dropped.
+struct ReadGuard<'a, K, V> {
+    pages: &'a dyn SharedPages<K, V>,
+    root_node: Option<Offset>,
+}
+
+impl<'a, K, V> ReadGuard<'a, K, V> {
+    fn new(pages: &'a dyn SharedPages<K, V>) -> Self {
+        let root_node = pages.begin_read();
+        Self { pages, root_node }
+    }
+}
+
+impl<K, V> Drop for ReadGuard<'_, K, V> {
+    fn drop(&mut self) {
+        self.pages.end_read();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::thread;
+
+    use crate::pager::STARTUP_OFFSET;
+    use crate::tree::BPTree;
+
+    use super::*;
+
+    #[test]
+    fn readers_see_committed_versions() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/readers_see_committed_versions.ldb")?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), b"old".to_vec())?;
+        }
+
+        let reader = tree.reader()?;
+        let threads = (0..4)
+            .map(|_| {
+                let reader = reader.clone();
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        assert_eq!(reader.search("050".to_string())?, Some(b"old".to_vec()));
+                        let pairs = reader.iter().collect::<Result<Vec<_>>>()?;
+                        assert!(pairs.len() >= 100 && pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 100..300 {
+            tree.insert(format!("{i:03}"), b"new".to_vec())?;
+        }
+        for handle in threads {
+            handle.join().unwrap()?;
+        }
+
+        let result = tree.transaction(|txn| {
+            txn.insert("300".to_string(), b"new".to_vec())?;
+            anyhow::bail!("abort");
+        });
+        assert!(result.is_err());
+
+        assert_eq!(reader.search("300".to_string())?, None);
+        assert_eq!(reader.search("299".to_string())?, Some(b"new".to_vec()));
+        let range = reader.range(Bound::Included("098".to_string()), Bound::Excluded("102".to_string()))?;
+        assert_eq!(range.len(), 4);
+        assert_eq!(reader.iter().count(), 300);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::pager::{DynOperator, PageOperator, Pager, ReadWriteSeek, DEFAULT_CACHE_PAGES, DEFAULT_PAGE_SIZE, STARTUP_OFFSET};
+use super::tree::{BPTree, Key, TreeOptions, Value};
+
+/// A tree behind a reader-writer lock, so an `Arc<SharedBPTree>` can be
+/// handed to any number of threads.
+///
+/// Any number of threads may hold a [`read`](SharedBPTree::read) guard at
+/// once and run the `&self` methods of the tree, such as lookups, range
+/// scans and iterators, side by side. Pages they have to load from storage
+/// are still read one at a time. A [`write`](SharedBPTree::write) guard is
+/// exclusive: it waits for every read guard to be dropped, and readers
+/// wait for it in turn, so a long write blocks every reader. Readers never
+/// see a write in progress; everything done through a write guard is
+/// visible once it is dropped.
+///
+/// A tree can only be shared if its page operator is `Send + Sync`, which
+/// the trees [`BPTree`]'s own constructors return don't promise, as they
+/// take any operator. The constructors here build trees that hold a
+/// `dyn PageOperator<K, V> + Send + Sync` instead.
+///
+/// For readers that should keep going while a write is in progress, see
+/// [`BPTree::reader`] instead.
+pub struct SharedBPTree<K = Key, V = Value, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V> + Send + Sync> {
+    tree: RwLock<BPTree<K, V, P>>,
+}
+
+impl<K, V> SharedBPTree<K, V>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + Send + Sync + 'static,
+{
+    /// Creates or reopens a tree over `file` like [`BPTree::with_options`].
+    pub fn with_options(file: impl ReadWriteSeek + 'static, options: TreeOptions) -> Result<Self> {
+        Ok(Self::new(BPTree::from_options(file, options)?))
+    }
+
+    /// Reopens a tree previously written to `file` like [`BPTree::open`].
+    pub fn open(file: impl ReadWriteSeek + 'static) -> Result<Self> {
+        let pager = Pager::new(Box::new(file), STARTUP_OFFSET, DEFAULT_PAGE_SIZE, DEFAULT_CACHE_PAGES);
+        Ok(Self::new(BPTree::open_pager(pager)?))
+    }
+
+    /// Creates an empty tree over a page operator that can be shared with
+    /// other threads, like [`BPTree::with_pager`].
+    pub fn with_pager(degree: usize, pager: Box<dyn PageOperator<K, V> + Send + Sync>) -> Result<Self> {
+        Ok(Self::new(BPTree::from_operator(degree, pager)?))
+    }
+}
+
+impl<K, V, P: ?Sized + DynOperator<K, V>> SharedBPTree<K, V, P> {
+    pub fn new(tree: BPTree<K, V, P>) -> Self
+    where
+        BPTree<K, V, P>: Send + Sync,
+    {
+        Self { tree: RwLock::new(tree) }
+    }
+
+    /// Waits until no writer holds the tree and returns shared access.
+    pub fn read(&self) -> RwLockReadGuard<'_, BPTree<K, V, P>> {
+        // Operations roll the tree back when they panic, so a poisoned lock
+        // still guards a consistent tree.
+        self.tree.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Waits until no reader or writer holds the tree and returns exclusive
+    /// access.
+    pub fn write(&self) -> RwLockWriteGuard<'_, BPTree<K, V, P>> {
+        self.tree.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Returns the tree once no other thread shares it.
+    pub fn into_inner(self) -> BPTree<K, V, P> {
+        self.tree.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<K, V, P: ?Sized + DynOperator<K, V>> From<BPTree<K, V, P>> for SharedBPTree<K, V, P>
+where
+    BPTree<K, V, P>: Send + Sync,
+{
+    fn from(tree: BPTree<K, V, P>) -> Self {
+        Self::new(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::sync::Arc;
+    use std::thread;
+
+    use crate::error::Result;
+    use crate::pager::memory::MemoryPager;
+
+    use super::*;
+
+    #[test]
+    fn readers_and_a_writer_share_the_tree() -> anyhow::Result<()> {
+        let shared = Arc::new(SharedBPTree::with_pager(4, Box::new(MemoryPager::new()))?);
+        for i in 0..100u32 {
+            shared.write().insert(i, i)?;
+        }
+
+        let readers = (0..4)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || -> Result<()> {
+                    for _ in 0..50 {
+                        let tree = shared.read();
+                        assert_eq!(tree.search(50)?, Some(50));
+                        let keys = tree.keys().collect::<Result<Vec<_>>>()?;
+                        assert!(keys.len() >= 100 && keys.windows(2).all(|pair| pair[0] < pair[1]));
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 100..300u32 {
+            shared.write().insert(i, i)?;
+        }
+        for handle in readers {
+            handle.join().unwrap()?;
+        }
+
+        let tree = Arc::into_inner(shared).unwrap().into_inner();
+        tree.validate()?;
+        assert_eq!(tree.len()?, 300);
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_backed_trees_are_shared() -> anyhow::Result<()> {
+        let path = "/tmp/shared_file_backed.ldb";
+        let file = OpenOptions::new().create(true).read(true).write(true).truncate(true).open(path)?;
+        let shared = Arc::new(SharedBPTree::with_options(file, TreeOptions::new(4))?);
+        thread::scope(|scope| -> Result<()> {
+            let writers = (0..4u32)
+                .map(|part| {
+                    let shared = &shared;
+                    scope.spawn(move || -> Result<()> {
+                        for i in (part * 100)..(part + 1) * 100 {
+                            shared.write().insert(i, i)?;
+                            assert_eq!(shared.read().search(i)?, Some(i));
+                        }
+                        Ok(())
+                    })
+                })
+                .collect::<Vec<_>>();
+            writers.into_iter().try_for_each(|handle| handle.join().unwrap())
+        })?;
+        let mut tree = Arc::into_inner(shared).unwrap().into_inner();
+        tree.flush()?;
+        drop(tree);
+
+        let shared = SharedBPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        let tree = shared.read();
+        tree.validate()?;
+        assert_eq!(tree.len()?, 400);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::iter::SnapshotIter;
+use super::pager::{DynOperator, Offset};
+use super::tree::BPTree;
+
+/// Read-only view of the tree as it was when [`BPTree::snapshot`] was
+/// called. The snapshot only records the root of that version; reads go
+/// through the tree it was taken from, which may be modified in between.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    root_node: Option<Offset>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(root_node: Option<Offset>) -> Self {
+        Self { root_node }
+    }
+
+    pub(crate) fn root_node(&self) -> Option<Offset> {
+        self.root_node
+    }
+
+    /// Looks `key` up in the pinned version of `tree`.
+    pub fn search<K, V, P>(&self, tree: &BPTree<K, V, P>, key: K) -> Result<Option<V>>
+    where
+        K: Ord + Clone + Encode + Decode + 'static,
+        V: Clone + Encode + Decode + 'static,
+        P: ?Sized + DynOperator<K, V>,
+    {
+        tree.search_from(self.root_node, key)
+    }
+
+    /// Iterates the pinned version of `tree` in ascending key order.
+    pub fn iter<'a, K, V, P>(&self, tree: &'a BPTree<K, V, P>) -> SnapshotIter<'a, K, V>
+    where
+        K: Ord + Clone + Encode + Decode + 'static,
+        V: Clone + Encode + Decode + 'static,
+        P: ?Sized + DynOperator<K, V>,
+    {
+        tree.iter_from(self.root_node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::pager::STARTUP_OFFSET;
+    use crate::tree::{Key, Value};
+
+    use super::*;
+
+    #[test]
+    fn snapshot_reads_pinned_version() -> anyhow::Result<()> {
+        let path = "/tmp/snapshot_reads_pinned_version.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..200 {
+            tree.insert(format!("{i:03}"), b"old".to_vec())?;
+        }
+        let pinned = tree.iter().collect::<Result<Vec<_>>>()?;
+        let snapshot = tree.snapshot()?;
+
+        for i in (0..200).step_by(2) {
+            tree.delete(format!("{i:03}"))?;
+        }
+        for i in 200..300 {
+            tree.insert(format!("{i:03}"), b"new".to_vec())?;
+        }
+        tree.insert("001".to_string(), b"new".to_vec())?;
+        tree.validate()?;
+
+        assert_eq!(snapshot.iter(&tree).collect::<Result<Vec<_>>>()?, pinned);
+        assert_eq!(snapshot.search(&tree, "000".to_string())?, Some(b"old".to_vec()));
+        assert_eq!(snapshot.search(&tree, "001".to_string())?, Some(b"old".to_vec()));
+        assert_eq!(snapshot.search(&tree, "250".to_string())?, None);
+        assert_eq!(tree.search("000".to_string())?, None);
+        assert_eq!(tree.search("001".to_string())?, Some(b"new".to_vec()));
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTree::<Key, Value>::open(file)?;
+        let snapshots = tree.snapshots();
+        assert_eq!(snapshots, vec![snapshot]);
+        assert_eq!(snapshots[0].iter(&tree).collect::<Result<Vec<_>>>()?, pinned);
+
+        assert!(tree.clear().is_err());
+        for snapshot in snapshots {
+            tree.release(snapshot)?;
+        }
+
+        // Once the release is synced, the pages the snapshot held are
+        // reused.
+        tree.insert("300".to_string(), b"new".to_vec())?;
+        tree.flush()?;
+        let size_pages = tree.file_size_pages();
+        for i in 0..200 {
+            tree.insert(format!("{i:03}"), b"newer".to_vec())?;
+        }
+        assert_eq!(tree.file_size_pages(), size_pages);
+        tree.validate()?;
+
+        Ok(())
+    }
+}
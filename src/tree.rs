@@ -1,331 +1,6301 @@
-use std::fs::File;
-use super::node::{Node, leaf::LeafNode, internal::InternalNode};
-use super::pager::{Pager, PageOperator, Offset};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashSet;
+use std::fmt::{Debug, Write as _};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
 
-pub(crate) type Key = String;
-pub(crate) type Value = Vec<u8>;
+use bincode::{Decode, Encode};
 
-pub struct BPTree {
+use super::error::{BPTreeError, Result};
+use super::compare::{self, KeyComparator, OrdComparator};
+use super::node::{min_keys, Change, Node, SplitBias, Update, cursor::LeafCursor, leaf::LeafNode, internal::InternalNode};
+use super::header::Header;
+use super::snapshot::Snapshot;
+use super::txn::Txn;
+use super::entry::Entry;
+use super::reader::TreeReader;
+use super::iter::{Keys, RangeIter, SnapshotIter, TreeIter, TreeIterRev, Values};
+use super::pager::{
+    cache::CacheStats, Compression, DynOperator, Encoding, IoStats, Pager, ReadWriteSeek, PageOperator, PageRead, Offset, ValueStorage, DEFAULT_CACHE_PAGES, DEFAULT_PAGE_SIZE, MIN_PAGE_SIZE, STARTUP_OFFSET,
+};
+#[cfg(feature = "direct-io")]
+use super::pager::aligned::ALIGNMENT;
+
+pub type Key = String;
+pub type Value = Vec<u8>;
+
+/// Offsets of the nodes from the root down to a leaf, and the leaf.
+type LeafPath<K, V> = (Vec<Offset>, Option<LeafNode<K, V>>);
+
+/// Smallest supported degree. Lower degrees leave a node with no key to
+/// promote when it splits.
+pub const MIN_DEGREE: usize = 3;
+
+/// Shape of a tree, as reported by [`BPTree::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TreeStats {
+    /// Number of levels, counting the leaves. Zero for a tree without a root.
+    pub height: usize,
+    pub internal_nodes: usize,
+    pub leaf_nodes: usize,
+    pub total_keys: usize,
+    /// Mean fraction of the `degree - 1` key slots used per leaf.
+    pub avg_leaf_fill: f64,
+}
+
+/// Space reclaimed by [`BPTree::compact`], in pages of the storage, the
+/// header included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactStats {
+    pub pages_before: usize,
+    pub pages_after: usize,
+}
+
+/// Random descents [`BPTree::estimate_len`] averages over.
+const ESTIMATE_SAMPLES: usize = 16;
+const ESTIMATE_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// When a tree syncs its storage on its own, on top of explicit calls to
+/// [`BPTree::flush`]. A write here is one committed operation, such as an
+/// insert, a delete or a whole transaction.
+///
+/// Writes in between syncs are not synced at all: a power loss drops every
+/// write since the last sync, and the tree reopens as of that sync. A write
+/// the policy syncs has its pages synced before its header is written and
+/// the header synced after, so the header never reaches the storage ahead
+/// of the pages it points at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only sync on [`BPTree::flush`] and when the tree is dropped.
+    #[default]
+    Never,
+    /// Sync after every write, so each one survives a crash once it returns.
+    EveryWrite,
+    /// Sync after every `n` writes, bounding how many a crash can lose.
+    /// `EveryN(0)` behaves like `EveryN(1)`.
+    EveryN(usize),
+}
+
+impl FlushPolicy {
+    fn is_due(self, unsynced: usize) -> bool {
+        match self {
+            FlushPolicy::Never => false,
+            FlushPolicy::EveryWrite => true,
+            FlushPolicy::EveryN(n) => unsynced >= n.max(1),
+        }
+    }
+}
+
+/// How [`BPTree::delete`] treats the leaf it removes a key from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeleteMode {
+    /// Borrow for or merge a leaf as soon as a delete leaves it short of
+    /// the minimum, so the tree stays balanced after every delete.
+    #[default]
+    Rebalance,
+    /// Drop the key from its leaf and leave the leaf short, rebalancing
+    /// later in bulk with [`BPTree::gc`]. A delete then copies only the
+    /// pages on the path to its leaf and never reads or writes a sibling,
+    /// at the cost of leaves that fill worse and take more pages until the
+    /// next `gc`. Lookups and scans see a deleted key gone straight away.
+    /// A delete that would empty its leaf still rebalances. Suits bursts
+    /// of deletes, such as expiring entries, followed by a `gc` once the
+    /// burst is over or space runs low. Whether short leaves are left is
+    /// stored in the header, and the first delete after switching back to
+    /// `Rebalance` runs the `gc` first.
+    Deferred,
+}
+
+/// Settings for a new file-backed tree, see [`BPTree::with_options`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeOptions {
+    /// Maximum number of children of an internal node, at least
+    /// [`MIN_DEGREE`].
+    pub degree: usize,
+    /// Size of a node page in bytes, at least [`MIN_PAGE_SIZE`]. Larger
+    /// pages fit wider keys and larger values.
+    pub page_size: usize,
+    /// Number of recently used pages kept in memory.
+    pub cache_pages: usize,
+    /// Number of written pages held back from the storage until the
+    /// operation commits, so a page an operation writes several times is
+    /// written out once, and one it frees again not at all. The oldest is
+    /// written out early once more are held. Zero writes every page
+    /// straight away. Not stored in the header.
+    pub buffer_pages: usize,
+    /// Compression of the node pages.
+    pub compression: Compression,
+    /// Bincode configuration the node pages are encoded with.
+    pub encoding: Encoding,
+    /// Where leaves keep their values.
+    pub value_storage: ValueStorage,
+    /// Where overflowing nodes split. Not stored in the header; set it
+    /// again with [`BPTree::with_split_bias`] after reopening. Whether
+    /// appending splits left short nodes is stored, so that
+    /// [`BPTree::validate`] accepts them whatever the bias of a later run.
+    pub split_bias: SplitBias,
+    /// How deletes treat the leaf they remove a key from. Not stored in
+    /// the header; set it again with [`BPTree::with_delete_mode`] after
+    /// reopening. Whether deferred deletes left short leaves is stored.
+    pub delete_mode: DeleteMode,
+    /// When writes are synced. Not stored in the header; set it again with
+    /// [`BPTree::with_flush_policy`] after reopening.
+    pub flush_policy: FlushPolicy,
+    /// Largest encoded key an entry may have. `None` keeps a limit derived
+    /// from the page size, see [`BPTree::with_max_key_len`].
+    pub max_key_len: Option<usize>,
+    /// Largest encoded value an entry may have. `None` keeps a limit
+    /// derived from the page size, see [`BPTree::with_max_value_len`].
+    pub max_value_len: Option<usize>,
+    /// Lay the file out for direct I/O: pages start on a multiple of the
+    /// page size, which has to be a multiple of 4096, and all I/O goes
+    /// through aligned buffers. Open the file with `O_DIRECT` yourself to
+    /// bypass the page cache of the OS. Stored in the header.
+    #[cfg(feature = "direct-io")]
+    pub direct_io: bool,
+}
+
+impl TreeOptions {
+    pub fn new(degree: usize) -> Self {
+        Self {
+            degree,
+            page_size: DEFAULT_PAGE_SIZE,
+            cache_pages: DEFAULT_CACHE_PAGES,
+            buffer_pages: 0,
+            compression: Compression::None,
+            encoding: Encoding::default(),
+            value_storage: ValueStorage::Inline,
+            split_bias: SplitBias::Middle,
+            delete_mode: DeleteMode::Rebalance,
+            flush_policy: FlushPolicy::Never,
+            max_key_len: None,
+            max_value_len: None,
+            #[cfg(feature = "direct-io")]
+            direct_io: false,
+        }
+    }
+}
+
+/// A B+tree kept in pages of a file or of any other page operator.
+///
+/// Writes are copy-on-write: an operation writes every node it changes to
+/// a page the committed tree doesn't use, and commits by writing a header
+/// that points at the new root into the older of two checksummed slots.
+/// That header write is the one atomic point, and a commit survives a
+/// power loss once a sync after it returned, see [`FlushPolicy`]. Pages
+/// the last synced commit uses are not written over until the next sync,
+/// so a crash at any point leaves that commit whole, and a header torn by
+/// the crash fails its checksum, so the other slot is read instead.
+/// Nothing has to be replayed or rolled back on open.
+pub struct BPTree<K = Key, V = Value, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V>> {
     degree: usize,
-    pager: Box<dyn PageOperator>,
+    pager: Box<P>,
     root_node: Option<Offset>,
+    /// Roots pinned by live snapshots.
+    snapshots: Vec<Offset>,
+    comparator: Arc<dyn KeyComparator<K> + Send + Sync>,
+    split_bias: SplitBias,
+    delete_mode: DeleteMode,
+    flush_policy: FlushPolicy,
+    /// Whether nodes on the right edge may be short of the minimum, as
+    /// appending splits leave them. Stored in the header.
+    short_right_edge: bool,
+    /// Whether deferred deletes may have left leaves short of the minimum
+    /// until the next gc. Stored in the header.
+    short_leaves: bool,
+    /// Header writes since the storage was last synced.
+    unsynced: usize,
+    /// Whether the root or the snapshots changed since the header was last
+    /// written or read, by a change not committed yet or a header write
+    /// that failed. Flushing only writes the header then.
+    dirty: bool,
+    max_key_len: Option<usize>,
+    max_value_len: Option<usize>,
+    values: PhantomData<V>,
+}
+
+impl<K, V> BPTree<K, V>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+{
+    /// Creates an empty tree over `file`. Nodes are written starting at
+    /// `startup_offset`, which is moved past the header page if it would
+    /// overlap it. A `file` that already holds a tree is reopened instead,
+    /// and has to have been written with the same `degree`.
+    ///
+    /// `degree` is the maximum number of children of an internal node and
+    /// must be at least [`MIN_DEGREE`].
+    pub fn new(degree: usize, startup_offset: usize, file: impl ReadWriteSeek + 'static) -> Result<Self> {
+        Self::new_with_cache(degree, startup_offset, file, DEFAULT_CACHE_PAGES)
+    }
+
+    /// Like [`BPTree::new`], keeping up to `cache_pages` recently used pages
+    /// in memory. Zero disables the page cache.
+    pub fn new_with_cache(
+        degree: usize,
+        startup_offset: usize,
+        file: impl ReadWriteSeek + 'static,
+        cache_pages: usize,
+    ) -> Result<Self> {
+        let pager = Pager::new(Box::new(file), startup_offset.max(STARTUP_OFFSET), DEFAULT_PAGE_SIZE, cache_pages);
+        Self::create_or_reopen(degree, pager)
+    }
+
+    /// Creates an empty tree over `file` with the given options. The page
+    /// size, compression, encoding and value storage are stored in the
+    /// header, so [`BPTree::open`] picks them up again. A `file` that
+    /// already holds a tree is reopened with the settings it was written
+    /// with, and has to have been written with the same degree.
+    pub fn with_options(file: impl ReadWriteSeek + 'static, options: TreeOptions) -> Result<Self> {
+        Self::from_options(file, options)
+    }
+
+    /// Creates an empty tree over any page operator, e.g. a `MemoryPager`
+    /// for trees that never touch the filesystem.
+    pub fn with_pager(degree: usize, pager: Box<dyn PageOperator<K, V>>) -> Result<Self> {
+        Self::from_operator(degree, pager)
+    }
+
+    /// Builds a tree over `file` from pairs in ascending key order. Leaves
+    /// and internal nodes are filled bottom-up to capacity instead of being
+    /// split on the way, which makes loading a sorted export much faster and
+    /// the resulting file much smaller.
+    pub fn bulk_load(degree: usize, file: impl ReadWriteSeek + 'static, sorted: impl Iterator<Item = (K, V)>) -> Result<Self> {
+        Ok(Self::new(degree, STARTUP_OFFSET, file)?.load_sorted(sorted.map(Ok))?)
+    }
+
+    /// Builds a tree over `file` from the records [`BPTree::export`] wrote
+    /// to `reader`, bulk-loading them like [`BPTree::bulk_load`] as they are
+    /// read.
+    pub fn import(degree: usize, file: impl ReadWriteSeek + 'static, mut reader: impl std::io::Read) -> Result<Self> {
+        let mut count = 0;
+        let pairs = std::iter::from_fn(|| {
+            let pair = (|| {
+                let Some(key) = read_record(&mut reader)? else {
+                    return Ok(None);
+                };
+                let Some(value) = read_record(&mut reader)? else {
+                    anyhow::bail!("import: record {} has a key but no value", count);
+                };
+                let (key, _) = Encoding::default().decode(&key)?;
+                let (value, _) = Encoding::default().decode(&value)?;
+                count += 1;
+                Ok(Some((key, value)))
+            })();
+            pair.transpose()
+        });
+        Ok(Self::new(degree, STARTUP_OFFSET, file)?.load_sorted(pairs)?)
+    }
+
+    /// Reopens a tree previously written to `file`, restoring its root,
+    /// degree and write cursor from the header page.
+    pub fn open(file: impl ReadWriteSeek + 'static) -> Result<Self> {
+        Self::open_with_cache(file, DEFAULT_CACHE_PAGES)
+    }
+
+    /// Like [`BPTree::open`], keeping up to `cache_pages` recently used pages
+    /// in memory. Zero disables the page cache.
+    pub fn open_with_cache(file: impl ReadWriteSeek + 'static, cache_pages: usize) -> Result<Self> {
+        Self::open_pager(Pager::new(Box::new(file), STARTUP_OFFSET, DEFAULT_PAGE_SIZE, cache_pages))
+    }
 }
 
-impl BPTree {
-    pub fn new(degree: usize, startup_offset: usize, file: File) -> Self {
-        Self {
-            degree,
-            pager: Box::new(Pager::new(file, startup_offset)),
-            root_node: None,
+impl<K, V, P> BPTree<K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    /// Orders keys with `comparator` instead of their `Ord` implementation.
+    /// Set it before the first insert, and again with the same comparator
+    /// whenever the tree is reopened.
+    pub fn with_comparator(mut self, comparator: impl KeyComparator<K> + Send + Sync + 'static) -> Self {
+        self.comparator = Arc::new(comparator);
+        self
+    }
+
+    /// Chooses where overflowing nodes split. [`SplitBias::Append`] packs
+    /// nodes when keys mostly arrive in ascending order.
+    pub fn with_split_bias(mut self, split_bias: SplitBias) -> Self {
+        self.split_bias = split_bias;
+        self
+    }
+
+    /// Chooses whether deletes rebalance straight away or leave it to
+    /// [`BPTree::gc`], see [`DeleteMode`].
+    pub fn with_delete_mode(mut self, delete_mode: DeleteMode) -> Self {
+        self.delete_mode = delete_mode;
+        self
+    }
+
+    /// Chooses when writes are synced without calling [`BPTree::flush`],
+    /// trading throughput for durability.
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Rejects keys whose encoding takes more than `max_key_len` bytes.
+    /// By default a key of a file-backed tree may take an eighth of a page,
+    /// so that every leaf has room for a couple of entries; memory-backed
+    /// trees accept any size. Not stored in the header.
+    pub fn with_max_key_len(mut self, max_key_len: usize) -> Self {
+        self.max_key_len = Some(max_key_len);
+        self
+    }
+
+    /// Rejects values whose encoding takes more than `max_value_len` bytes.
+    /// By default a value stored uncompressed in its leaf page may take a
+    /// third of a page, and other values any size. Not stored in the
+    /// header.
+    pub fn with_max_value_len(mut self, max_value_len: usize) -> Self {
+        self.max_value_len = Some(max_value_len);
+        self
+    }
+
+    /// Writes every pair to `out` in ascending key order and returns how
+    /// many were written. Each record is the key and then the value, each
+    /// encoded with bincode's standard configuration and preceded by its
+    /// length as a little-endian `u32`, so the export doesn't depend on the
+    /// page layout and [`BPTree::import`] can read it back.
+    pub fn export(&self, mut out: impl Write) -> Result<usize> {
+        let mut count = 0;
+        self.for_each_in_range(Bound::Unbounded, Bound::Unbounded, |key, value| {
+            write_record(&mut out, &Encoding::default().encode(key)?)?;
+            write_record(&mut out, &Encoding::default().encode(value)?)?;
+            count += 1;
+            Ok(())
+        })?;
+        out.flush()?;
+        Ok(count)
+    }
+
+    /// Fills an empty tree bottom-up from pairs in ascending order of its
+    /// comparator, see [`BPTree::bulk_load`]. Pairs are taken one at a time
+    /// and leaves written as they fill, so only the last two leaves and the
+    /// largest key of every written one are held in memory.
+    fn load_sorted(mut self, sorted: impl Iterator<Item = anyhow::Result<(K, V)>>) -> anyhow::Result<Self> {
+        if self.root_node.is_some() {
+            anyhow::bail!("bulk_load: the file already holds a tree");
+        }
+        let degree = self.degree;
+        let limits = self.entry_limits();
+
+        // Every entry of a level is a written node and the largest key of
+        // its subtree, which becomes the separator in front of its sibling.
+        let mut level = Vec::new();
+        // A full leaf is held back until the next one fills, so that a short
+        // last leaf can share its keys with it instead of underflowing.
+        let mut held: Option<(Vec<K>, Vec<V>)> = None;
+        let (mut keys, mut values) = (Vec::with_capacity(degree - 1), Vec::with_capacity(degree - 1));
+        for pair in sorted {
+            let (key, value) = pair?;
+            let last = keys.last().or_else(|| held.as_ref().and_then(|(keys, _)| keys.last()));
+            if last.is_some_and(|last| self.comparator.compare(last, &key) != Ordering::Less) {
+                anyhow::bail!("bulk_load: keys must be in strictly ascending order");
+            }
+            limits.check(limits.key_len(&key)?, &value)?;
+            keys.push(key);
+            values.push(value);
+
+            if keys.len() == degree - 1 {
+                let full = (std::mem::replace(&mut keys, Vec::with_capacity(degree - 1)), std::mem::replace(&mut values, Vec::with_capacity(degree - 1)));
+                if let Some((keys, values)) = held.replace(full) {
+                    level.push(self.write_loaded_leaf(keys, values)?);
+                }
+            }
+        }
+
+        if let Some((mut held_keys, mut held_values)) = held {
+            if !keys.is_empty() && keys.len() < min_keys(degree) {
+                held_keys.append(&mut keys);
+                held_values.append(&mut values);
+                let half = held_keys.len() - held_keys.len() / 2;
+                keys = held_keys.split_off(half);
+                values = held_values.split_off(half);
+            }
+            level.push(self.write_loaded_leaf(held_keys, held_values)?);
+        }
+        if !keys.is_empty() {
+            level.push(self.write_loaded_leaf(keys, values)?);
+        }
+
+        while level.len() > 1 {
+            let sizes = packed_sizes(level.len(), degree, min_keys(degree) + 1);
+            let mut entries = level.into_iter();
+
+            level = Vec::with_capacity(sizes.len());
+            for size in sizes {
+                let offset = self.pager.allocate();
+                let mut internal_node = InternalNode {
+                    keys: Vec::with_capacity(size - 1),
+                    children: Vec::with_capacity(size),
+                    offset: Some(offset),
+                };
+
+                let mut max_key = None;
+                for (child_offset, child_max_key) in entries.by_ref().take(size) {
+                    if let Some(separator) = max_key.replace(child_max_key) {
+                        internal_node.keys.push(separator);
+                    }
+                    internal_node.children.push(child_offset);
+                }
+
+                let Some(max_key) = max_key else {
+                    anyhow::bail!("bulk_load: empty internal node at offset {}", offset);
+                };
+                self.pager.write_at(&Node::Internal(internal_node), offset)?;
+                level.push((offset, max_key));
+            }
+        }
+
+        self.root_node = level.pop().map(|(offset, _)| offset);
+        self.flush_header()?;
+
+        Ok(self)
+    }
+
+    /// Writes a leaf of [`BPTree::load_sorted`] and returns its offset and
+    /// largest key.
+    fn write_loaded_leaf(&mut self, keys: Vec<K>, values: Vec<V>) -> anyhow::Result<(Offset, K)> {
+        let offset = self.pager.allocate();
+        let max_key = keys[keys.len() - 1].clone();
+        self.pager.write_at(&Node::Leaf(LeafNode { keys, values, offset: Some(offset) }), offset)?;
+        Ok((offset, max_key))
+    }
+
+    /// Writes the live contents of the tree to `out` as a freshly
+    /// bulk-loaded tree with the same degree and returns it, along with the
+    /// page counts of both trees. Only reachable nodes are written, packed
+    /// to capacity, and pairs are streamed from a leaf walk of `self`
+    /// straight into the new leaves. Snapshots are not carried over; the
+    /// comparator and the rest of the configuration are, see
+    /// [`BPTree::options`].
+    pub fn compact(&self, out: impl ReadWriteSeek + 'static) -> Result<(Self, CompactStats)> {
+        let tree = self.empty_copy(out)?;
+        let tree = tree.load_sorted(self.iter().map(|pair| pair.map_err(anyhow::Error::from)))?;
+        let stats = CompactStats { pages_before: self.file_size_pages(), pages_after: tree.file_size_pages() };
+        Ok((tree, stats))
+    }
+
+    /// Moves every entry at or after `key` into a new tree over `out` with
+    /// the configuration and comparator of this one, see
+    /// [`BPTree::options`], leaving the smaller keys in `self`. The moved
+    /// entries are streamed from one leaf walk into the new tree, then
+    /// removed from `self` like [`BPTree::delete_range`] does, releasing
+    /// whole subtrees under a single header write. Both trees are valid
+    /// and committed once this returns; if the removal fails, `self` is
+    /// left as it was.
+    pub fn split_off(&mut self, key: K, out: impl ReadWriteSeek + 'static) -> Result<Self> {
+        let other = self.empty_copy(out)?;
+        let other = other.load_sorted(self.range_iter(key.clone()..).map(|pair| pair.map_err(anyhow::Error::from)))?;
+        self.remove_from(&key, None)?;
+        Ok(other)
+    }
+
+    /// Options that recreate the configuration of this tree: its degree,
+    /// the page layout of its operator, see [`PageOperator::page_options`],
+    /// and the split bias, delete mode, flush policy and entry limits set
+    /// on it. Page operators without pages lay out a file tree with the
+    /// default page size.
+    pub fn options(&self) -> TreeOptions {
+        TreeOptions {
+            split_bias: self.split_bias,
+            delete_mode: self.delete_mode,
+            flush_policy: self.flush_policy,
+            max_key_len: self.max_key_len,
+            max_value_len: self.max_value_len,
+            ..self.pager.page_options(TreeOptions::new(self.degree))
+        }
+    }
+
+    /// Creates an empty tree over `out` with the options and comparator of
+    /// this one, to move entries into.
+    fn empty_copy(&self, out: impl ReadWriteSeek + 'static) -> Result<Self> {
+        let mut tree = Self::from_options(out, self.options())?;
+        tree.comparator = Arc::clone(&self.comparator);
+        Ok(tree)
+    }
+
+    /// Creates or reopens a tree over `file` with the given options, see
+    /// [`BPTree::with_options`].
+    pub(crate) fn from_options(file: impl ReadWriteSeek + 'static, options: TreeOptions) -> Result<Self> {
+        if options.page_size < MIN_PAGE_SIZE {
+            return Err(anyhow::anyhow!("page size must be at least {MIN_PAGE_SIZE}, got {}", options.page_size).into());
+        }
+
+        options.compression.check_supported()?;
+        let pager = Pager::new(Box::new(file), STARTUP_OFFSET, options.page_size, options.cache_pages)
+            .with_buffer_pool(options.buffer_pages)
+            .with_compression(options.compression)
+            .with_encoding(options.encoding)
+            .with_value_storage(options.value_storage);
+        #[cfg(feature = "direct-io")]
+        let pager = match options.direct_io {
+            true if !options.page_size.is_multiple_of(ALIGNMENT) => {
+                let page_size = options.page_size;
+                return Err(anyhow::anyhow!("direct I/O needs a page size that is a multiple of {ALIGNMENT}, got {page_size}").into());
+            },
+            true => pager.with_direct_io(),
+            false => pager,
+        };
+        let tree = Self::create_or_reopen(options.degree, pager)?;
+        let mut tree = tree
+            .with_split_bias(options.split_bias)
+            .with_delete_mode(options.delete_mode)
+            .with_flush_policy(options.flush_policy);
+        tree.max_key_len = options.max_key_len;
+        tree.max_value_len = options.max_value_len;
+        Ok(tree)
+    }
+
+    /// Creates an empty tree over `pager`, see [`BPTree::with_pager`].
+    pub(crate) fn from_operator(degree: usize, pager: Box<P>) -> Result<Self> {
+        if degree < MIN_DEGREE {
+            return Err(BPTreeError::InvalidDegree(degree));
+        }
+
+        let mut tree = Self {
+            degree,
+            pager,
+            root_node: None,
+            snapshots: Vec::new(),
+            short_right_edge: false,
+            short_leaves: false,
+            comparator: Arc::new(OrdComparator),
+            split_bias: SplitBias::default(),
+            delete_mode: DeleteMode::default(),
+            flush_policy: FlushPolicy::default(),
+            unsynced: 0,
+            dirty: false,
+            max_key_len: None,
+            max_value_len: None,
+            values: PhantomData,
+        };
+        // The header of the empty tree isn't one of the writes the flush
+        // policy counts.
+        tree.flush_header()?;
+        tree.unsynced = 0;
+
+        Ok(tree)
+    }
+
+    /// Creates an empty tree over `pager`, or reopens the one its storage
+    /// already holds if that was written with `degree`.
+    fn create_or_reopen(degree: usize, mut pager: Pager) -> Result<Self> {
+        if degree < MIN_DEGREE {
+            return Err(BPTreeError::InvalidDegree(degree));
+        }
+        if !pager.has_header()? {
+            return Self::from_operator(degree, P::from_pager(pager));
+        }
+
+        let header = PageOperator::<K, V>::read_header(&mut pager)?;
+        if header.degree != degree {
+            return Err(BPTreeError::DegreeMismatch { stored: header.degree, requested: degree });
+        }
+        Self::from_header(pager, header)
+    }
+
+    pub(crate) fn open_pager(mut pager: Pager) -> Result<Self> {
+        let header = PageOperator::<K, V>::read_header(&mut pager)?;
+        Self::from_header(pager, header)
+    }
+
+    fn from_header(pager: Pager, header: Header) -> Result<Self> {
+        if header.degree < MIN_DEGREE {
+            return Err(BPTreeError::InvalidDegree(header.degree));
+        }
+
+        Ok(Self {
+            degree: header.degree,
+            pager: P::from_pager(pager),
+            root_node: header.root_node,
+            snapshots: header.snapshots,
+            short_right_edge: header.short_right_edge,
+            short_leaves: header.short_leaves,
+            comparator: Arc::new(OrdComparator),
+            split_bias: SplitBias::default(),
+            delete_mode: DeleteMode::default(),
+            flush_policy: FlushPolicy::default(),
+            unsynced: 0,
+            dirty: false,
+            max_key_len: None,
+            max_value_len: None,
+            values: PhantomData,
+        })
+    }
+
+    /// Writes the header if the tree changed since it was last committed
+    /// and syncs the underlying storage, so everything inserted or deleted
+    /// so far survives a crash. Individual writes are only synced on their
+    /// own as the [`FlushPolicy`] says.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.dirty {
+            self.commit_header(true)?;
+        } else {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Re-reads the header from storage and moves the tree to the commit it
+    /// records, such as one another process made to the same file since
+    /// this tree was opened. The page cache is dropped, as the other writer
+    /// may have reused the pages it holds.
+    ///
+    /// The tree then reads exactly the state of that commit: header writes
+    /// alternate between two checksummed slots, so a header the other
+    /// writer is still writing is passed over for the one before it, and
+    /// the pages a commit points at are written before its header. Commits
+    /// made after the reload stay invisible until the next one. Reads may
+    /// still fail or see newer pages if the other writer reuses pages of
+    /// the loaded commit in the meantime; have it pin a snapshot, or reload
+    /// and retry. Only one tree may write to a file: a tree that only reads
+    /// and reloads never writes its header, not even when flushed or
+    /// dropped, but any write through it commits over the other writer.
+    pub fn reload(&mut self) -> Result<()> {
+        let header = self.pager.read_header()?;
+        if header.degree != self.degree {
+            return Err(BPTreeError::DegreeMismatch { stored: header.degree, requested: self.degree });
+        }
+
+        self.root_node = header.root_node;
+        self.snapshots = header.snapshots;
+        self.short_right_edge = header.short_right_edge;
+        self.short_leaves = header.short_leaves;
+        self.unsynced = 0;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Number of pages the underlying storage spans, including the header.
+    /// Pages released by deletes and copy-on-write updates are reused before
+    /// the file is extended, those the last synced commit still uses once
+    /// the storage is synced again.
+    pub fn file_size_pages(&self) -> usize {
+        self.pager.size_pages()
+    }
+
+    /// Page cache hit and miss counters since the tree was created or
+    /// opened.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pager.cache_stats()
+    }
+
+    /// Reads and writes made against the storage since the tree was created
+    /// or opened. Compare them before and after an operation to see how
+    /// many pages it touched.
+    pub fn io_stats(&self) -> IoStats {
+        self.pager.io_stats()
+    }
+
+    /// Returns the number of keys stored in the tree by walking the leaves.
+    pub fn len(&self) -> Result<usize> {
+        let mut len = 0;
+        let (mut cursor, mut leaf) = LeafCursor::first(self.pager.operator(), self.root_node)?;
+
+        while let Some(leaf_node) = leaf {
+            len += leaf_node.keys.len();
+            leaf = cursor.next(self.pager.operator())?;
+        }
+
+        Ok(len)
+    }
+
+    /// Estimates the number of keys without reading every leaf, for when
+    /// [`BPTree::len`] is too slow. A few descents each pick a child at
+    /// random on every level and scale the keys of the leaf they reach by
+    /// the fanout they passed through; the estimate is their average. It is
+    /// approximate, close on a balanced tree with evenly filled leaves, and
+    /// costs a handful of reads per level.
+    pub fn estimate_len(&self) -> Result<usize> {
+        let Some(root_offset) = self.root_node else {
+            return Ok(0);
+        };
+
+        let mut state = ESTIMATE_SEED;
+        let mut total = 0.0;
+        for _ in 0..ESTIMATE_SAMPLES {
+            let mut scale = 1.0;
+            let mut node = self.pager.read_at(root_offset)?;
+            loop {
+                match node {
+                    Node::Leaf(leaf_node) => {
+                        total += scale * leaf_node.keys.len() as f64;
+                        break;
+                    },
+                    Node::Internal(internal_node) => {
+                        // xorshift64, enough to spread the descents.
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        let children = internal_node.children.len();
+                        if children == 0 {
+                            return Err(anyhow::anyhow!("estimate_len: internal node {:?} has no children", internal_node.offset).into());
+                        }
+                        scale *= children as f64;
+                        node = self.pager.read_at(internal_node.children[state as usize % children])?;
+                    },
+                }
+            }
+        }
+
+        Ok((total / ESTIMATE_SAMPLES as f64).round() as usize)
+    }
+
+    /// Counts the keys in `[start, end)` without collecting them. Leaves
+    /// lying entirely inside the range are counted whole, so only the two
+    /// leaves at its edges are searched.
+    pub fn count_range(&self, start: K, end: K) -> Result<usize> {
+        let cmp = self.comparator.as_ref();
+        if cmp.compare(&start, &end) != Ordering::Less {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        let (mut cursor, mut leaf) = LeafCursor::seek(self.pager.operator(), cmp, self.root_node, &start)?;
+        let mut first = true;
+        while let Some(leaf_node) = leaf {
+            let from = match first {
+                true => compare::search(cmp, &leaf_node.keys, &start).unwrap_or_else(|pos| pos),
+                false => 0,
+            };
+            match leaf_node.keys.last() {
+                Some(last) if cmp.compare(last, &end) == Ordering::Less => count += leaf_node.keys.len() - from,
+                Some(_) => {
+                    let to = compare::search(cmp, &leaf_node.keys, &end).unwrap_or_else(|pos| pos);
+                    return Ok(count + to.saturating_sub(from));
+                },
+                None => (),
+            }
+
+            first = false;
+            leaf = cursor.next(self.pager.operator())?;
+        }
+
+        Ok(count)
+    }
+
+    /// Returns `true` if any key lies in `[start, end)`. Only the least key
+    /// at or after `start` is looked at, so the cost is one descent however
+    /// wide the range is.
+    pub fn any_in_range(&self, start: K, end: K) -> Result<bool> {
+        let ceiling = self.ceiling(start)?;
+        Ok(ceiling.is_some_and(|(key, _)| self.comparator.compare(&key, &end) == Ordering::Less))
+    }
+
+    /// Returns `true` when `len` would be zero, stopping at the first leaf
+    /// that holds a key.
+    pub fn is_empty(&self) -> Result<bool> {
+        let (mut cursor, mut leaf) = LeafCursor::first(self.pager.operator(), self.root_node)?;
+
+        while let Some(leaf_node) = leaf {
+            if !leaf_node.keys.is_empty() {
+                return Ok(false);
+            }
+            leaf = cursor.next(self.pager.operator())?;
+        }
+
+        Ok(true)
+    }
+
+    fn first_leaf(&self) -> anyhow::Result<Option<LeafNode<K, V>>> {
+        match self.root_node {
+            None => Ok(None),
+            Some(root_offset) => {
+                let root_node = self.pager.read_at(root_offset)?;
+                Ok(Some(root_node.first_leaf(self.pager.operator())?))
+            },
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        self.mutate(|tree| {
+            tree.put(key, value)?;
+            tree.flush_header()
+        })
+    }
+
+    /// Inserts `key` without committing the new root.
+    pub(crate) fn put(&mut self, key: K, value: V) -> anyhow::Result<()> {
+        let limits = self.entry_limits();
+        limits.check(limits.key_len(&key)?, &value)?;
+        self.dirty = true;
+        self.short_right_edge |= self.split_bias == SplitBias::Append;
+
+        match self.root_node {
+            None => {
+                let root_node = Node::Leaf(LeafNode {
+                    keys: vec![key],
+                    values: vec![value],
+                    offset: Some(self.pager.next_offset()),
+                });
+                let root_offset = self.pager.write(&root_node)?;
+                self.root_node = Some(root_offset);
+            },
+            Some(root_offset) => {
+                let mut root_node = self.pager.read_at(root_offset)?;
+                let root_copy_offset = root_node.relocate(self.pager.operator_mut())?;
+
+                match root_node.insert(self.pager.operator_mut(), self.comparator.as_ref(), key, value, self.degree, self.split_bias)? {
+                    None => {
+                        self.pager.write_at(&root_node, root_copy_offset)?;
+                        self.root_node = Some(root_copy_offset);
+                    },
+                    Some((mid_key, sibling)) => {
+                        let sibling_offset = sibling.write_sibling(self.pager.operator_mut())?;
+                        self.grow_root(&root_node, root_copy_offset, mid_key, sibling_offset)?;
+                    },
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Inserts or replaces the value of `key`, returning `true` if the key
+    /// was not present before.
+    pub fn upsert(&mut self, key: K, value: V) -> Result<bool> {
+        let mut inserted = false;
+        self.update_with(key, |current| {
+            inserted = current.is_none();
+            Some(value)
+        })?;
+        Ok(inserted)
+    }
+
+    /// Runs `f` against a transaction and commits everything it changed
+    /// with one header write. If `f` or one of its operations fails or
+    /// panics nothing is committed, and the tree stays as it was before the
+    /// call.
+    pub fn transaction<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Txn<'_, K, V, P>) -> anyhow::Result<()>,
+    {
+        self.mutate(|tree| {
+            f(&mut Txn::new(tree))?;
+            tree.flush_header()
+        })
+    }
+
+    /// Applies `f` to the current value of `key` (`None` if absent) and
+    /// stores what it returns: `Some` inserts or replaces the value, `None`
+    /// removes the key. The path to the leaf is read and copied only once.
+    pub fn update_with<F>(&mut self, key: K, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&V>) -> Option<V>,
+    {
+        self.mutate(|tree| {
+            tree.change(key, |current| match f(current) {
+                Some(value) => Change::Put(value),
+                None => Change::Remove,
+            })
+        })
+    }
+
+    /// Returns the value of `key`, first inserting what `f` returns if the
+    /// key is absent. Both cases take a single descent, and a key that is
+    /// already present leaves every page untouched.
+    pub fn get_or_insert_with(&mut self, key: K, f: impl FnOnce() -> V) -> Result<V> {
+        self.mutate(|tree| {
+            let mut found = None;
+            tree.change(key, |current| match current {
+                Some(value) => {
+                    found = Some(value.clone());
+                    Change::Keep
+                },
+                None => {
+                    let value = f();
+                    found = Some(value.clone());
+                    Change::Put(value)
+                },
+            })?;
+            found.ok_or_else(|| anyhow::anyhow!("get_or_insert_with: the leaf was never reached"))
+        })
+    }
+
+    /// Looks up `key` for reading or changing its value in place, like the
+    /// `entry` API of the standard maps. The lookup descends once and the
+    /// entry keeps the pages it went through, so reading the value,
+    /// replacing it or inserting into a leaf with room don't descend
+    /// again.
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, K, V, P>> {
+        let (path, leaf_node) = self.leaf_path(&key)?;
+        Ok(Entry::new(self, key, path, leaf_node))
+    }
+
+    /// Descends to the leaf that would hold `key`, returning the offsets of
+    /// the nodes visited from the root down and the leaf itself, or an
+    /// empty path for an empty tree.
+    fn leaf_path(&self, key: &K) -> anyhow::Result<LeafPath<K, V>> {
+        let mut path = Vec::new();
+        let mut next = self.root_node;
+        while let Some(offset) = next {
+            path.push(offset);
+            next = match self.pager.read_at(offset)? {
+                Node::Leaf(leaf_node) => return Ok((path, Some(leaf_node))),
+                Node::Internal(internal_node) => {
+                    let position = compare::search(self.comparator.as_ref(), &internal_node.keys, key).unwrap_or_else(|pos| pos);
+                    Some(internal_node.child(position)?)
+                },
+            };
+        }
+
+        Ok((path, None))
+    }
+
+    pub(crate) fn degree(&self) -> usize {
+        self.degree
+    }
+
+    /// Position of `key` in `leaf_node`, or where it would be inserted.
+    pub(crate) fn search_leaf(&self, leaf_node: &LeafNode<K, V>, key: &K) -> std::result::Result<usize, usize> {
+        compare::search(self.comparator.as_ref(), &leaf_node.keys, key)
+    }
+
+    /// Fails if `key` and `value` exceed the entry limits.
+    pub(crate) fn check_entry(&self, key: &K, value: &V) -> anyhow::Result<()> {
+        let limits = self.entry_limits();
+        limits.check(limits.key_len(key)?, value)
+    }
+
+    /// Commits `leaf_node`, changed without changing shape, in place of
+    /// the leaf at the end of `path`, copying every page of the path from
+    /// the root down. Returns where the path was copied to.
+    pub(crate) fn write_leaf_path(&mut self, path: &[Offset], leaf_node: &mut LeafNode<K, V>) -> Result<Vec<Offset>> {
+        self.mutate(|tree| {
+            let copies = tree.copy_leaf_path(path, leaf_node)?;
+            tree.flush_header()?;
+            Ok(copies)
+        })
+    }
+
+    /// Does the copying of [`BPTree::write_leaf_path`] without writing the
+    /// header.
+    fn copy_leaf_path(&mut self, path: &[Offset], leaf_node: &mut LeafNode<K, V>) -> anyhow::Result<Vec<Offset>> {
+        let mut copies = Vec::with_capacity(path.len());
+        let mut node = Node::Leaf(leaf_node.clone());
+        for (depth, &offset) in path.iter().enumerate().rev() {
+            let copy_offset = node.relocate(self.pager.operator_mut())?;
+            self.pager.write_at(&node, copy_offset)?;
+            copies.push(copy_offset);
+            if let Node::Leaf(copy) = &node {
+                leaf_node.offset = copy.offset;
+            }
+
+            if depth == 0 {
+                break;
+            }
+            let parent_offset = path[depth - 1];
+            let Node::Internal(mut parent) = self.pager.read_at(parent_offset)? else {
+                anyhow::bail!("leaf path: node {parent_offset} on the path is a leaf");
+            };
+            let Some(position) = parent.children.iter().position(|child| *child == offset) else {
+                anyhow::bail!("leaf path: node {offset} is no longer a child of {parent_offset}");
+            };
+            parent.children[position] = copy_offset;
+            node = Node::Internal(parent);
+        }
+
+        copies.reverse();
+        self.root_node = copies.first().copied();
+        Ok(copies)
+    }
+
+    /// Inserts `value` only if `key` is absent and returns whether it did.
+    /// An existing value is never overwritten. The check and the insert
+    /// happen in the same descent, and a key that is already present
+    /// leaves every page untouched.
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Result<bool> {
+        self.mutate(|tree| {
+            let mut inserted = false;
+            tree.change(key, |current| match current {
+                Some(_) => Change::Keep,
+                None => {
+                    inserted = true;
+                    Change::Put(value)
+                },
+            })?;
+            Ok(inserted)
+        })
+    }
+
+    /// Applies the change `f` picks for the current value of `key` and
+    /// commits it, unless nothing changed.
+    fn change<F>(&mut self, key: K, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Option<&V>) -> Change<V>,
+    {
+        // An entry over the limits is turned away before the leaf changes,
+        // leaving every page untouched.
+        let limits = self.entry_limits();
+        let key_len = limits.key_len(&key)?;
+        let mut rejected = None;
+        let f = |current: Option<&V>| match f(current) {
+            Change::Put(value) => match limits.check(key_len, &value) {
+                Ok(()) => Change::Put(value),
+                Err(err) => {
+                    rejected = Some(err);
+                    Change::Keep
+                },
+            },
+            change => change,
+        };
+
+        let Some(root_offset) = self.root_node else {
+            return match f(None) {
+                Change::Put(value) => {
+                    self.put(key, value)?;
+                    self.flush_header()
+                },
+                Change::Keep | Change::Remove => rejected.map_or(Ok(()), Err),
+            };
+        };
+        let root_offset = self.settle_short_leaves()?.unwrap_or(root_offset);
+
+        self.short_right_edge |= self.split_bias == SplitBias::Append;
+        let mut root_node = self.pager.read_at(root_offset)?;
+        match root_node.update(self.pager.operator_mut(), self.comparator.as_ref(), key, f, self.degree, self.split_bias)? {
+            Update::Untouched => return rejected.map_or(Ok(()), Err),
+            Update::Unchanged => {
+                let root_copy_offset = root_node.relocate(self.pager.operator_mut())?;
+                self.pager.write_at(&root_node, root_copy_offset)?;
+                self.root_node = Some(root_copy_offset);
+            },
+            Update::Split(mid_key, sibling) => {
+                let sibling_offset = sibling.write_sibling(self.pager.operator_mut())?;
+                let root_copy_offset = root_node.relocate(self.pager.operator_mut())?;
+                self.grow_root(&root_node, root_copy_offset, mid_key, sibling_offset)?;
+            },
+            Update::Removed(need_rebalance) => {
+                let root_copy_offset = root_node.relocate(self.pager.operator_mut())?;
+                self.pager.write_at(&root_node, root_copy_offset)?;
+                self.root_node = Some(self.shrink_root(root_node, root_copy_offset, need_rebalance));
+            },
+        }
+
+        self.flush_header()
+    }
+
+    /// Limits entries are checked against, the tree's own or else the
+    /// pager's defaults.
+    fn entry_limits(&self) -> EntryLimits {
+        let (max_key_len, max_value_len) = self.pager.entry_limits();
+        EntryLimits {
+            max_key_len: self.max_key_len.unwrap_or(max_key_len),
+            max_value_len: self.max_value_len.unwrap_or(max_value_len),
+            encoding: self.pager.encoding(),
+        }
+    }
+
+    /// Runs a mutation of the tree. If it fails or panics part way, the
+    /// root and the pages freed so far are put back as they were at the
+    /// last commit, so the handle keeps working on the committed tree.
+    fn mutate<T>(&mut self, f: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> Result<T> {
+        let committed = (self.root_node, self.dirty, self.short_right_edge, self.short_leaves);
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(err)) => {
+                self.restore(committed)?;
+                Err(err.into())
+            },
+            Err(payload) => {
+                let _ = self.restore(committed);
+                panic::resume_unwind(payload)
+            },
+        }
+    }
+
+    fn restore(
+        &mut self,
+        (root_node, dirty, short_right_edge, short_leaves): (Option<Offset>, bool, bool, bool),
+    ) -> anyhow::Result<()> {
+        self.root_node = root_node;
+        self.dirty = dirty;
+        self.short_right_edge = short_right_edge;
+        self.short_leaves = short_leaves;
+        self.pager.rollback();
+        Ok(())
+    }
+
+    /// Writes the root and puts it under a new root next to the sibling it
+    /// split into, which is already written at `sibling_offset`.
+    fn grow_root(&mut self, root_node: &Node<K, V>, root_offset: Offset, mid_key: K, sibling_offset: Offset) -> anyhow::Result<()> {
+        self.pager.write_at(root_node, root_offset)?;
+
+        let new_root = Node::Internal(InternalNode {
+            keys: vec![mid_key],
+            children: vec![root_offset, sibling_offset],
+            offset: Some(self.pager.next_offset()),
+        });
+
+        let new_root_offset = self.pager.write(&new_root)?;
+        self.root_node = Some(new_root_offset);
+        Ok(())
+    }
+
+    /// Returns the offset of the root after a removal, dropping an internal
+    /// root whose last key was merged away.
+    fn shrink_root(&mut self, root_node: Node<K, V>, root_offset: Offset, need_rebalance: bool) -> Offset {
+        match root_node {
+            Node::Internal(payload) if need_rebalance && payload.keys.is_empty() => {
+                self.pager.free(root_offset);
+                payload.children[0]
+            },
+            _ => root_offset,
+        }
+    }
+
+    /// Inserts every pair in one pass. The pairs are sorted first so that
+    /// each touched page is copied and written only once; when a key
+    /// appears more than once the last value wins.
+    pub fn insert_many(&mut self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<()> {
+        self.mutate(|tree| {
+            let unique = sort_dedup(tree.comparator.as_ref(), pairs);
+            if unique.is_empty() {
+                return Ok(());
+            }
+            let limits = tree.entry_limits();
+            for (key, value) in &unique {
+                limits.check(limits.key_len(key)?, value)?;
+            }
+
+            let (mut root_node, root_offset) = match tree.root_node {
+                None => {
+                    let offset = tree.pager.allocate();
+                    let root_node = Node::Leaf(LeafNode {
+                        keys: Vec::new(),
+                        values: Vec::new(),
+                        offset: Some(offset),
+                    });
+                    (root_node, offset)
+                },
+                Some(root_offset) => {
+                    let mut root_node = tree.pager.read_at(root_offset)?;
+                    let root_copy_offset = root_node.relocate(tree.pager.operator_mut())?;
+                    (root_node, root_copy_offset)
+                },
+            };
+
+            let mut siblings = root_node.insert_batch(tree.pager.operator_mut(), tree.comparator.as_ref(), unique, tree.degree)?;
+            tree.pager.write_at(&root_node, root_offset)?;
+
+            let mut root_offset = root_offset;
+            while !siblings.is_empty() {
+                let offset = tree.pager.allocate();
+                let mut new_root = InternalNode {
+                    keys: Vec::with_capacity(siblings.len()),
+                    children: vec![root_offset],
+                    offset: Some(offset),
+                };
+                for (separator, sibling_offset) in siblings {
+                    new_root.keys.push(separator);
+                    new_root.children.push(sibling_offset);
+                }
+
+                siblings = if new_root.keys.len() > tree.degree - 1 {
+                    new_root.split_many(tree.pager.operator_mut(), tree.degree)?
+                } else {
+                    Vec::new()
+                };
+                tree.pager.write_at(&Node::Internal(new_root), offset)?;
+                root_offset = offset;
+            }
+
+            tree.root_node = Some(root_offset);
+            tree.flush_header()
+        })
+    }
+
+    /// Same as [`BPTree::insert_many`].
+    pub fn extend(&mut self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<()> {
+        self.insert_many(pairs)
+    }
+
+    /// Moves every entry of `other` into `self` and clears `other`. Where
+    /// both trees hold a key, the value from `other` wins.
+    ///
+    /// The trees keep their pages in separate storage, so `other`'s leaves
+    /// can't be grafted in as they are. Its entries are read with one leaf
+    /// walk and inserted as a single sorted batch instead: when they all
+    /// sort after the keys of `self`, only the rightmost path is copied and
+    /// the new leaves are filled in order, otherwise each touched leaf of
+    /// `self` is copied once.
+    ///
+    /// Fails without changing either tree if `other` can't be cleared, as
+    /// while it has snapshots pinned.
+    pub fn append(&mut self, other: &mut Self) -> Result<()> {
+        other.check_clear()?;
+        let pairs = other.iter().collect::<Result<Vec<_>>>()?;
+        if pairs.is_empty() {
+            return Ok(());
+        }
+
+        self.insert_many(pairs)?;
+        other.clear()
+    }
+
+    /// Removes every key at once, truncating the storage back to the header
+    /// page instead of deleting keys one by one.
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_clear()?;
+
+        self.root_node = None;
+        // No node is left short, whatever left them short before.
+        self.short_right_edge = false;
+        self.short_leaves = false;
+        self.pager.clear()?;
+        Ok(self.flush_header()?)
+    }
+
+    /// Fails if [`BPTree::clear`] would: pinned snapshots still read the
+    /// pages it would truncate.
+    fn check_clear(&self) -> anyhow::Result<()> {
+        if !self.snapshots.is_empty() {
+            anyhow::bail!("clear: {} snapshots are still pinned", self.snapshots.len());
+        }
+        Ok(())
+    }
+
+    /// Removes `key` from the tree and returns its value, or `None` if the
+    /// key was not present. Removing an absent key writes nothing.
+    pub fn delete(&mut self, key: K) -> Result<Option<V>> {
+        self.mutate(|tree| {
+            let removed = tree.take(key)?;
+            if removed.is_some() {
+                tree.flush_header()?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Same as [`BPTree::delete`].
+    pub fn remove(&mut self, key: K) -> Result<Option<V>> {
+        self.delete(key)
+    }
+
+    /// Removes every key in `[start, end)` and returns how many were
+    /// removed. Subtrees lying entirely inside the range are released
+    /// whole, and only the nodes along the two bounds are copied and
+    /// rebalanced, under a single header write.
+    pub fn delete_range(&mut self, start: K, end: K) -> Result<usize> {
+        if self.comparator.compare(&start, &end) != Ordering::Less {
+            return Ok(0);
+        }
+        self.remove_from(&start, Some(&end))
+    }
+
+    /// Removes every key in `[start, end)`, or from `start` on without an
+    /// `end`, see [`BPTree::delete_range`].
+    fn remove_from(&mut self, start: &K, end: Option<&K>) -> Result<usize> {
+        let Some(root_offset) = self.root_node else {
+            return Ok(0);
+        };
+
+        self.mutate(|tree| {
+            let mut root_node = tree.pager.read_at(root_offset)?;
+            let removed = root_node.remove_range(tree.pager.operator_mut(), tree.comparator.as_ref(), start, end, tree.degree)?;
+            if removed == 0 {
+                return Ok(0);
+            }
+
+            tree.root_node = Some(tree.replace_root(root_node)?);
+            tree.flush_header()?;
+            Ok(removed)
+        })
+    }
+
+    /// Removes every key below `key` and returns how many were removed, e.g.
+    /// to drop the entries of a log below a watermark. Subtrees lying
+    /// entirely below `key` are released whole, and only the nodes along
+    /// the new left edge of the tree are copied and rebalanced.
+    pub fn truncate_below(&mut self, key: K) -> Result<usize> {
+        let Some(root_offset) = self.root_node else {
+            return Ok(0);
+        };
+
+        self.mutate(|tree| {
+            let mut root_node = tree.pager.read_at(root_offset)?;
+            let (removed, changed) = root_node.truncate_below(tree.pager.operator_mut(), tree.comparator.as_ref(), &key)?;
+            if !changed {
+                return Ok(0);
+            }
+
+            let mut root_offset = root_node.relocate(tree.pager.operator_mut())?;
+            loop {
+                match &mut root_node {
+                    Node::Internal(internal_node) if internal_node.children.len() == 1 => {
+                        // The only child can be a page of the committed
+                        // tree a merge left in place, so it is copied
+                        // before it is fixed up as the root.
+                        let child_offset = internal_node.children[0];
+                        tree.pager.free(root_offset);
+                        root_node = tree.pager.read_at(child_offset)?;
+                        root_offset = root_node.relocate(tree.pager.operator_mut())?;
+                    },
+                    Node::Internal(internal_node) => {
+                        internal_node.fix_first_child(tree.pager.operator_mut(), tree.degree)?;
+                        if internal_node.children.len() > 1 {
+                            break;
+                        }
+                    },
+                    Node::Leaf(_) => break,
+                }
+            }
+
+            tree.pager.write_at(&root_node, root_offset)?;
+            tree.root_node = Some(root_offset);
+            tree.flush_header()?;
+            Ok(removed)
+        })
+    }
+
+    /// Rebalances every leaf that deletes with [`DeleteMode::Deferred`]
+    /// left short of the minimum, and every node [`SplitBias::Append`] left
+    /// short on the right edge, merging nodes and releasing the pages they
+    /// free, and returns whether the tree changed. All of it is
+    /// committed under a single header write, and only the nodes that
+    /// change are copied, so a tree without short leaves is left as it is.
+    ///
+    /// `gc` walks the whole tree, so run it once a burst of deletes is over
+    /// rather than after each of them, or when the file grows more than
+    /// the number of keys suggests. Lookups and scans are correct whether
+    /// or not it has run; until it does, short leaves only cost space and
+    /// the extra pages a scan reads.
+    pub fn gc(&mut self) -> Result<bool> {
+        self.mutate(|tree| {
+            // A tree without short nodes may still have the header say it
+            // could have some.
+            let flagged = tree.short_right_edge || tree.short_leaves;
+            let changed = tree.fix_short_nodes()?;
+            if changed || flagged {
+                tree.flush_header()?;
+            }
+            Ok(changed)
+        })
+    }
+
+    /// Rebalances every node short of the minimum without committing the
+    /// new root, and returns whether the tree changed.
+    fn fix_short_nodes(&mut self) -> anyhow::Result<bool> {
+        self.short_right_edge = false;
+        self.short_leaves = false;
+        let Some(root_offset) = self.root_node else {
+            return Ok(false);
+        };
+
+        let mut root_node = self.pager.read_at(root_offset)?;
+        let Node::Internal(internal_node) = &mut root_node else {
+            return Ok(false);
+        };
+        if !internal_node.fix_underflows(self.pager.operator_mut(), self.degree)? {
+            return Ok(false);
+        }
+
+        self.root_node = Some(self.replace_root(root_node)?);
+        Ok(true)
+    }
+
+    /// Moves a root that changed, and drops the roots with a single child
+    /// that merges below it left on top of the tree. Returns the offset of
+    /// the new root.
+    fn replace_root(&mut self, mut root_node: Node<K, V>) -> anyhow::Result<Offset> {
+        let mut root_offset = root_node.relocate(self.pager.operator_mut())?;
+        self.pager.write_at(&root_node, root_offset)?;
+        // Merges may leave a chain of roots with a single child, whose
+        // children were all written by the merges that emptied them.
+        while let Node::Internal(internal_node) = &root_node {
+            if internal_node.children.len() > 1 {
+                break;
+            }
+            self.pager.free(root_offset);
+            root_offset = internal_node.children[0];
+            root_node = self.pager.read_at(root_offset)?;
+        }
+        Ok(root_offset)
+    }
+
+    /// Removes every key of `keys`, which must be in strictly ascending
+    /// order of the comparator, and returns how many were present. The keys
+    /// are routed down the tree together, so each node above them is
+    /// descended through once, absent keys cost no write, and the nodes
+    /// left short are rebalanced once all keys are gone, under a single
+    /// header write. Short leaves are rebalanced whatever the
+    /// [`DeleteMode`].
+    pub fn delete_sorted(&mut self, keys: &[K]) -> Result<usize> {
+        let cmp = self.comparator.as_ref();
+        if keys.windows(2).any(|pair| cmp.compare(&pair[0], &pair[1]) != Ordering::Less) {
+            return Err(anyhow::anyhow!("delete_sorted: keys must be in strictly ascending order").into());
+        }
+        let Some(root_offset) = self.root_node else {
+            return Ok(0);
+        };
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        self.mutate(|tree| {
+            let mut root_node = tree.pager.read_at(root_offset)?;
+            let removed = root_node.remove_sorted(tree.pager.operator_mut(), tree.comparator.as_ref(), keys, tree.degree)?;
+            if removed == 0 {
+                return Ok(0);
+            }
+
+            tree.root_node = Some(tree.replace_root(root_node)?);
+            tree.flush_header()?;
+            Ok(removed)
+        })
+    }
+
+    /// Removes `key` without committing the new root.
+    pub(crate) fn take(&mut self, key: K) -> anyhow::Result<Option<V>> {
+        let Some(root_offset) = self.root_node else {
+            return Ok(None);
+        };
+        // The tree only needs a new header once something changed, so that
+        // removing an absent key leaves it clean.
+        if self.delete_mode == DeleteMode::Deferred {
+            if let Some(removed) = self.take_from_leaf(&key)? {
+                self.dirty |= removed.is_some();
+                return Ok(removed);
+            }
+        }
+        let flags = (self.short_right_edge, self.short_leaves);
+        let settled = self.settle_short_leaves()?;
+        self.dirty |= settled.is_some() || flags != (self.short_right_edge, self.short_leaves);
+        let root_offset = settled.unwrap_or(root_offset);
+
+        let mut root_node = self.pager.read_at(root_offset)?;
+        let Some((value, need_rebalance)) = root_node.remove(self.pager.operator_mut(), self.comparator.as_ref(), key, self.degree)? else {
+            return Ok(None);
+        };
+        self.dirty = true;
+
+        let root_copy_offset = root_node.relocate(self.pager.operator_mut())?;
+        self.pager.write_at(&root_node, root_copy_offset)?;
+        self.root_node = Some(self.shrink_root(root_node, root_copy_offset, need_rebalance));
+        Ok(Some(value))
+    }
+
+    /// Removes `key` from its leaf without rebalancing, see
+    /// [`DeleteMode::Deferred`]. Returns `None` when the key is the last
+    /// one of its leaf, which is left to a regular removal so that no leaf
+    /// is ever empty.
+    fn take_from_leaf(&mut self, key: &K) -> anyhow::Result<Option<Option<V>>> {
+        let (path, Some(mut leaf_node)) = self.leaf_path(key)? else {
+            return Ok(Some(None));
+        };
+        let Ok(position) = self.search_leaf(&leaf_node, key) else {
+            return Ok(Some(None));
+        };
+        if leaf_node.keys.len() == 1 {
+            return Ok(None);
+        }
+
+        leaf_node.keys.remove(position);
+        let value = leaf_node.values.remove(position);
+        self.short_leaves |= leaf_node.keys.len() < min_keys(self.degree);
+        self.copy_leaf_path(&path, &mut leaf_node)?;
+        Ok(Some(Some(value)))
+    }
+
+    /// Rebalances the leaves deferred deletes left short before a delete
+    /// that rebalances, which only borrows one key for a leaf and so can't
+    /// fix one further below the minimum. The whole tree is fixed at once,
+    /// like [`BPTree::gc`] does, as the header only records that some leaf
+    /// may be short. Returns the new root if the tree changed.
+    fn settle_short_leaves(&mut self) -> anyhow::Result<Option<Offset>> {
+        if !self.short_leaves || self.delete_mode != DeleteMode::Rebalance {
+            return Ok(None);
+        }
+        Ok(if self.fix_short_nodes()? { self.root_node } else { None })
+    }
+
+    pub fn search(&self, key: K) -> Result<Option<V>> {
+        self.search_from(self.root_node, key)
+    }
+
+    pub(crate) fn search_from(&self, root_node: Option<Offset>, key: K) -> Result<Option<V>> {
+        match root_node {
+            None => Ok(None),
+            Some(root_offset) => {
+                let root_node = self.pager.read_at(root_offset)?;
+                Ok(root_node.search(self.pager.operator(), self.comparator.as_ref(), key)?)
+            },
+        }
+    }
+
+    /// Returns the offsets of the nodes a lookup of `key` visits, from the
+    /// root down to the leaf that would hold it. Its length is the depth of
+    /// the tree, and empty for an empty tree.
+    pub fn search_path(&self, key: K) -> Result<Vec<Offset>> {
+        let mut path = Vec::new();
+        let mut next = self.root_node;
+        while let Some(offset) = next {
+            path.push(offset);
+            next = match self.pager.read_at(offset)? {
+                Node::Leaf(_) => None,
+                Node::Internal(internal_node) => {
+                    let position = compare::search(self.comparator.as_ref(), &internal_node.keys, &key).unwrap_or_else(|pos| pos);
+                    Some(internal_node.child(position)?)
+                },
+            };
+        }
+
+        Ok(path)
+    }
+
+    /// Returns the offset of the leaf holding `key` and the position of the
+    /// key within it, or `None` if the key is absent. The location is only
+    /// valid until the next mutation: copy-on-write moves every page an
+    /// insert or delete touches, so the leaf may be freed and reused.
+    pub fn locate(&self, key: K) -> Result<Option<(Offset, usize)>> {
+        let Some(leaf_offset) = self.search_path(key.clone())?.pop() else {
+            return Ok(None);
+        };
+
+        let leaf_node = self.pager.read_at(leaf_offset)?.into_leaf()?;
+        Ok(compare::search(self.comparator.as_ref(), &leaf_node.keys, &key)
+            .ok()
+            .map(|position| (leaf_offset, position)))
+    }
+
+    /// Looks up every key of `keys` and returns their values in the same
+    /// order. The keys are matched in sorted order leaf by leaf, so the
+    /// tree is descended only once for the whole batch.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let mut values = vec![None; keys.len()];
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| self.comparator.compare(&keys[a], &keys[b]));
+
+        let Some(&first) = order.first() else {
+            return Ok(values);
+        };
+
+        let (mut cursor, mut leaf) = LeafCursor::seek(self.pager.operator(), self.comparator.as_ref(), self.root_node, &keys[first])?;
+
+        for index in order {
+            let key = &keys[index];
+            while let Some(leaf_node) = &leaf {
+                match leaf_node.keys.last() {
+                    Some(last) if self.comparator.compare(last, key) == Ordering::Less => {
+                        leaf = cursor.next(self.pager.operator())?
+                    },
+                    _ => break,
+                }
+            }
+
+            let Some(leaf_node) = &leaf else {
+                break;
+            };
+            if let Ok(position) = compare::search(self.comparator.as_ref(), &leaf_node.keys, key) {
+                values[index] = Some(leaf_node.values[position].clone());
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Returns the pair with the greatest key less than or equal to `key`.
+    pub fn floor(&self, key: K) -> Result<Option<(K, V)>> {
+        let (mut cursor, Some(mut leaf_node)) = self.cursor_for(&key)? else {
+            return Ok(None);
+        };
+
+        let mut position = match compare::search(self.comparator.as_ref(), &leaf_node.keys, &key) {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+        // Every key of the leaf is greater, so the floor is the last key of
+        // the first non-empty leaf before it.
+        while position == 0 {
+            match cursor.prev(self.pager.operator())? {
+                None => return Ok(None),
+                Some(prev_leaf) => leaf_node = prev_leaf,
+            }
+            position = leaf_node.keys.len();
+        }
+
+        Ok(Some((leaf_node.keys.swap_remove(position - 1), leaf_node.values.swap_remove(position - 1))))
+    }
+
+    /// Returns the pair with the least key greater than or equal to `key`.
+    pub fn ceiling(&self, key: K) -> Result<Option<(K, V)>> {
+        let (mut cursor, Some(mut leaf_node)) = self.cursor_for(&key)? else {
+            return Ok(None);
+        };
+
+        let mut position = compare::search(self.comparator.as_ref(), &leaf_node.keys, &key).unwrap_or_else(|pos| pos);
+        // Every key of the leaf is less, so the ceiling is the first key of
+        // the first non-empty leaf after it.
+        while position == leaf_node.keys.len() {
+            match cursor.next(self.pager.operator())? {
+                None => return Ok(None),
+                Some(next_leaf) => leaf_node = next_leaf,
+            }
+            position = 0;
+        }
+
+        Ok(Some((leaf_node.keys.swap_remove(position), leaf_node.values.swap_remove(position))))
+    }
+
+    /// Descends to the leaf that would hold `key`, keeping the path so the
+    /// walk can go on to its neighbours.
+    fn cursor_for(&self, key: &K) -> anyhow::Result<(LeafCursor, Option<LeafNode<K, V>>)> {
+        LeafCursor::seek(self.pager.operator(), self.comparator.as_ref(), self.root_node, key)
+    }
+
+    /// Returns `true` if `key` is stored in the tree.
+    pub fn contains_key(&self, key: K) -> Result<bool> {
+        Ok(self.search(key)?.is_some())
+    }
+
+    /// Returns every key/value pair between `start` and `end` in ascending
+    /// key order, walking on leaf by leaf once the first leaf is found.
+    pub fn range(&self, start: Bound<K>, end: Bound<K>) -> Result<Vec<(K, V)>> {
+        let mut result = Vec::new();
+        self.for_each_in_range(start, end, |key, value| {
+            result.push((key.clone(), value.clone()));
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Returns up to `limit` pairs in ascending key order with keys greater
+    /// than `after`, or from the first key when it is `None`. Passing the
+    /// last key of one page back as `after` returns the next page, whether or
+    /// not that key is still in the tree.
+    pub fn scan_from(&self, after: Option<K>, limit: usize) -> Result<Vec<(K, V)>> {
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result);
+        }
+
+        let (mut cursor, mut leaf, mut position) = match &after {
+            None => {
+                let (cursor, leaf) = LeafCursor::first(self.pager.operator(), self.root_node)?;
+                (cursor, leaf, 0)
+            },
+            Some(after) => {
+                let (cursor, leaf) = self.cursor_for(after)?;
+                let position = leaf.as_ref().map_or(0, |leaf_node| {
+                    match compare::search(self.comparator.as_ref(), &leaf_node.keys, after) {
+                        Ok(position) => position + 1,
+                        Err(position) => position,
+                    }
+                });
+                (cursor, leaf, position)
+            },
+        };
+
+        while let Some(leaf_node) = leaf {
+            for (key, value) in leaf_node.keys[position..].iter().zip(&leaf_node.values[position..]) {
+                result.push((key.clone(), value.clone()));
+                if result.len() == limit {
+                    return Ok(result);
+                }
+            }
+
+            leaf = cursor.next(self.pager.operator())?;
+            position = 0;
+        }
+
+        Ok(result)
+    }
+
+    /// Calls `f` for every key/value pair between `start` and `end` in
+    /// ascending key order without collecting them, so only one leaf is
+    /// held in memory at a time. Stops at the first error `f` returns.
+    pub fn for_each_in_range<F>(&self, start: Bound<K>, end: Bound<K>, f: F) -> Result<()>
+    where
+        F: FnMut(&K, &V) -> anyhow::Result<()>,
+    {
+        Ok(walk_range(self.pager.operator(), self.comparator.as_ref(), self.root_node, start, end, f)?)
+    }
+
+    /// Folds every key/value pair in `[start, end)` into `init` in ascending
+    /// key order, walking the leaves without collecting the pairs.
+    pub fn fold_range<B>(&self, start: K, end: K, init: B, mut f: impl FnMut(B, &K, &V) -> B) -> Result<B> {
+        let pager = self.pager.operator();
+        let cmp = self.comparator.as_ref();
+        let (mut cursor, mut leaf) = LeafCursor::seek(pager, cmp, self.root_node, &start)?;
+
+        let mut acc = init;
+        while let Some(leaf_node) = leaf {
+            for (key, value) in leaf_node.keys.iter().zip(leaf_node.values.iter()) {
+                if cmp.compare(key, &start) == Ordering::Less {
+                    continue;
+                }
+                if cmp.compare(key, &end) != Ordering::Less {
+                    return Ok(acc);
+                }
+                acc = f(acc, key, value);
+            }
+            leaf = cursor.next(pager)?;
+        }
+        Ok(acc)
+    }
+
+    /// Returns the smallest key in the tree, reading one node per level.
+    pub fn min_key(&self) -> Result<Option<K>> {
+        Ok(self.first_key_value()?.map(|(key, _)| key))
+    }
+
+    /// Returns the largest key in the tree, reading one node per level.
+    pub fn max_key(&self) -> Result<Option<K>> {
+        Ok(self.last_key_value()?.map(|(key, _)| key))
+    }
+
+    /// Returns the pair with the smallest key, reading one node per level.
+    pub fn first_key_value(&self) -> Result<Option<(K, V)>> {
+        let leaf = self.first_leaf()?;
+        Ok(leaf.and_then(|leaf_node| leaf_node.keys.into_iter().zip(leaf_node.values).next()))
+    }
+
+    /// Returns the pair with the largest key, reading one node per level.
+    pub fn last_key_value(&self) -> Result<Option<(K, V)>> {
+        match self.root_node {
+            None => Ok(None),
+            Some(root_offset) => {
+                let root_node = self.pager.read_at(root_offset)?;
+                let leaf = root_node.last_leaf(self.pager.operator())?;
+                Ok(leaf.keys.into_iter().zip(leaf.values).last())
+            },
+        }
+    }
+
+    /// Returns up to `n` pairs with the smallest keys, in ascending order.
+    /// The leaves are walked from the leftmost one and only until `n` pairs
+    /// are found.
+    pub fn first_n(&self, n: usize) -> Result<Vec<(K, V)>> {
+        self.iter().take(n).collect()
+    }
+
+    /// Returns up to `n` pairs with the largest keys, largest first. The
+    /// leaves are walked backwards from the rightmost one and only until `n`
+    /// pairs are found.
+    pub fn last_n(&self, n: usize) -> Result<Vec<(K, V)>> {
+        self.iter_rev().take(n).collect()
+    }
+
+    /// Returns a lazy iterator over every key/value pair in ascending key
+    /// order. I/O errors hit during the traversal are yielded as items.
+    pub fn iter(&self) -> TreeIter<'_, K, V> {
+        TreeIter::new(self.pager.operator(), self.root_node)
+    }
+
+    /// Returns a lazy iterator over the pairs within `range` in ascending
+    /// key order, taking the usual range syntax, e.g.
+    /// `tree.range_iter("a".to_string()..="m".to_string())`. Leaves are read
+    /// one at a time as the iteration reaches them.
+    pub fn range_iter(&self, range: impl RangeBounds<K>) -> RangeIter<'_, K, V> {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        RangeIter::new(self.pager.operator(), self.comparator.as_ref(), self.root_node, start, end)
+    }
+
+    /// Returns a lazy iterator over every key in ascending order. Values
+    /// are dropped as their leaf is read, never cloned.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys::new(self.iter())
+    }
+
+    /// Returns a lazy iterator over every value in ascending key order.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values::new(self.iter())
+    }
+
+    pub(crate) fn iter_from(&self, root_node: Option<Offset>) -> SnapshotIter<'_, K, V> {
+        SnapshotIter::new(self.pager.operator(), root_node)
+    }
+
+    /// Returns a handle other threads can read the tree through while it is
+    /// being written here. Readers see the last committed version, see
+    /// [`TreeReader`]. Only file-backed trees can be shared.
+    pub fn reader(&self) -> Result<TreeReader<K, V>> {
+        let pages = self.pager.share()?;
+        Ok(TreeReader::new(pages, Arc::clone(&self.comparator)))
+    }
+
+    /// Pins the current version of the tree. The snapshot keeps reading
+    /// that version while the tree is modified, because pages the
+    /// copy-on-write updates abandon are not reused until every snapshot is
+    /// released. Pinned snapshots are recorded in the header and survive a
+    /// reopen, see [`BPTree::snapshots`].
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        if let Some(root_offset) = self.root_node {
+            self.snapshots.push(root_offset);
+            self.flush_header()?;
+        }
+
+        Ok(Snapshot::new(self.root_node))
+    }
+
+    /// Unpins a snapshot, letting the pages only it still read be reused.
+    pub fn release(&mut self, snapshot: Snapshot) -> Result<()> {
+        let Some(root_offset) = snapshot.root_node() else {
+            return Ok(());
+        };
+        let Some(position) = self.snapshots.iter().position(|pinned| *pinned == root_offset) else {
+            return Err(anyhow::anyhow!("release: no snapshot pinned at offset {root_offset}").into());
+        };
+
+        self.snapshots.remove(position);
+        Ok(self.flush_header()?)
+    }
+
+    /// Snapshots pinned when the tree was last written, oldest first.
+    pub fn snapshots(&self) -> Vec<Snapshot> {
+        self.snapshots.iter().map(|root_offset| Snapshot::new(Some(*root_offset))).collect()
+    }
+
+    /// Like [`BPTree::iter`], in descending key order. The leaves are read
+    /// from the rightmost one backwards, so taking the last N keys reads
+    /// only the leaves that hold them.
+    pub fn iter_rev(&self) -> TreeIterRev<'_, K, V> {
+        TreeIterRev::new(self.pager.operator(), self.root_node)
+    }
+
+    /// Walks the whole tree and checks its structural invariants: keys are
+    /// sorted and fall within the separators above them, internal nodes have
+    /// one more child than keys, every leaf sits at the same depth, no page
+    /// is reached twice, no node overflows and none but the root
+    /// underflows, apart from the right edge of a tree that splits with
+    /// [`SplitBias::Append`] and the leaves of one that deletes with
+    /// [`DeleteMode::Deferred`]. The error names the offset of the first
+    /// offending node.
+    pub fn validate(&self) -> Result<()> {
+        let Some(root_offset) = self.root_node else {
+            return Ok(());
+        };
+
+        let mut walk = ValidateWalk {
+            leaf_depth: None,
+            visited: HashSet::new(),
+        };
+        Ok(self.validate_node(root_offset, None, None, 0, &mut walk)?)
+    }
+
+    fn validate_node(
+        &self,
+        offset: Offset,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        walk: &mut ValidateWalk,
+    ) -> anyhow::Result<()> {
+        if !walk.visited.insert(offset) {
+            anyhow::bail!("validate: node at offset {offset} is reached twice");
+        }
+        let node = self.pager.read_at(offset)?;
+        let keys = match &node {
+            Node::Leaf(leaf_node) => &leaf_node.keys,
+            Node::Internal(internal_node) => &internal_node.keys,
+        };
+
+        let cmp = self.comparator.as_ref();
+        if keys.windows(2).any(|pair| cmp.compare(&pair[0], &pair[1]) != Ordering::Less) {
+            anyhow::bail!("validate: keys of node at offset {offset} are not sorted");
+        }
+        if keys.first().zip(lower).is_some_and(|(first, lower)| cmp.compare(first, lower) != Ordering::Greater)
+            || keys.last().zip(upper).is_some_and(|(last, upper)| cmp.compare(last, upper) == Ordering::Greater)
+        {
+            anyhow::bail!("validate: keys of node at offset {offset} fall outside its separators");
+        }
+        if keys.len() > self.degree - 1 {
+            anyhow::bail!("validate: node at offset {offset} overflows with {} keys", keys.len());
+        }
+        // Appending splits leave the nodes on the right edge, the ones
+        // without a separator above them on the right, short of the minimum.
+        let right_edge = upper.is_none() && self.short_right_edge;
+        // Deferred deletes leave leaves short until the next gc.
+        let deferred = matches!(node, Node::Leaf(_)) && self.short_leaves;
+        if depth > 0 && !right_edge && !deferred && keys.len() < min_keys(self.degree) {
+            anyhow::bail!("validate: node at offset {offset} underflows with {} keys", keys.len());
+        }
+
+        match node {
+            Node::Leaf(_) => match walk.leaf_depth {
+                Some(leaf_depth) if leaf_depth != depth => {
+                    anyhow::bail!("validate: leaf at offset {offset} is at depth {depth}, expected {leaf_depth}")
+                },
+                _ => walk.leaf_depth = Some(depth),
+            },
+            Node::Internal(internal_node) => {
+                if internal_node.children.len() != internal_node.keys.len() + 1 {
+                    anyhow::bail!(
+                        "validate: node at offset {offset} has {} keys but {} children",
+                        internal_node.keys.len(),
+                        internal_node.children.len()
+                    );
+                }
+                if depth == 0 && internal_node.keys.is_empty() {
+                    anyhow::bail!("validate: internal root at offset {offset} has no keys");
+                }
+
+                for (i, child_offset) in internal_node.children.iter().enumerate() {
+                    let child_lower = if i == 0 { lower } else { internal_node.keys.get(i - 1) };
+                    let child_upper = internal_node.keys.get(i).or(upper);
+                    self.validate_node(*child_offset, child_lower, child_upper, depth + 1, walk)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Returns the offsets of the leaves in key order, starting from the
+    /// leftmost leaf. Meant for debugging the leaf walk of iterators and
+    /// scans; stops with an error if a leaf is reached twice.
+    pub fn leaf_offsets(&self) -> Result<Vec<Offset>> {
+        let mut offsets = Vec::new();
+        let mut visited = HashSet::new();
+        let (mut cursor, mut leaf) = LeafCursor::first(self.pager.operator(), self.root_node)?;
+        while let Some(leaf_node) = leaf {
+            let offset = leaf_node.offset.ok_or_else(|| anyhow::anyhow!("leaf_offsets: leaf has no page"))?;
+            if !visited.insert(offset) {
+                return Err(anyhow::anyhow!("leaf_offsets: leaf at offset {offset} is reached twice").into());
+            }
+            offsets.push(offset);
+            leaf = cursor.next(self.pager.operator())?;
+        }
+
+        Ok(offsets)
+    }
+
+    /// Walks the leaves the way iterators and scans do and checks the walk
+    /// against the tree: the keys ascend across leaf boundaries, walking
+    /// backwards visits the same leaves in reverse, and the walk holds as
+    /// many leaves as a level by level walk finds.
+    pub fn validate_leaf_chain(&self) -> Result<()> {
+        let offsets = self.leaf_offsets()?;
+        let cmp = self.comparator.as_ref();
+
+        let mut last_key: Option<K> = None;
+        for offset in &offsets {
+            let leaf_node = self.pager.read_at(*offset)?.into_leaf()?;
+            for key in leaf_node.keys {
+                if last_key.as_ref().is_some_and(|last_key| cmp.compare(last_key, &key) != Ordering::Less) {
+                    return Err(anyhow::anyhow!("validate_leaf_chain: keys of leaf at offset {offset} don't ascend").into());
+                }
+                last_key = Some(key);
+            }
+        }
+
+        let (mut cursor, mut leaf) = LeafCursor::last(self.pager.operator(), self.root_node)?;
+        for offset in offsets.iter().rev() {
+            let reached = leaf.and_then(|leaf_node| leaf_node.offset);
+            if reached != Some(*offset) {
+                return Err(anyhow::anyhow!("validate_leaf_chain: walking back reaches {reached:?} instead of leaf {offset}").into());
+            }
+            leaf = cursor.prev(self.pager.operator())?;
+        }
+
+        let leaf_nodes = self.stats()?.leaf_nodes;
+        if offsets.len() != leaf_nodes {
+            return Err(anyhow::anyhow!(
+                "validate_leaf_chain: walk holds {} leaves, the tree {leaf_nodes}",
+                offsets.len()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Reports the height of the tree and how many nodes and keys it holds,
+    /// reading every node once.
+    pub fn stats(&self) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        let mut level = self.root_node.into_iter().collect::<Vec<_>>();
+
+        while !level.is_empty() {
+            stats.height += 1;
+            let mut next_level = Vec::new();
+            for offset in level {
+                match self.pager.read_at(offset)? {
+                    Node::Leaf(leaf_node) => {
+                        stats.leaf_nodes += 1;
+                        stats.total_keys += leaf_node.keys.len();
+                    },
+                    Node::Internal(internal_node) => {
+                        stats.internal_nodes += 1;
+                        next_level.extend(internal_node.children);
+                    },
+                }
+            }
+            level = next_level;
+        }
+
+        if stats.leaf_nodes > 0 {
+            stats.avg_leaf_fill = stats.total_keys as f64 / (stats.leaf_nodes * (self.degree - 1)) as f64;
+        }
+
+        Ok(stats)
+    }
+
+    /// Renders the tree as a Graphviz DOT graph. Every node is a record with
+    /// its offset and keys, child edges are labeled with the separator that
+    /// bounds the child.
+    pub fn to_dot(&self) -> Result<String>
+    where
+        K: Debug,
+    {
+        let mut dot = String::from("digraph bptree {\n  node [shape=record];\n");
+        let mut level = self.root_node.into_iter().collect::<Vec<_>>();
+
+        while !level.is_empty() {
+            let mut next_level = Vec::new();
+            for offset in level {
+                match self.pager.read_at(offset)? {
+                    Node::Leaf(leaf_node) => {
+                        writeln!(dot, "  n{offset} [label=\"{}\"];", dot_record("leaf", offset, &leaf_node.keys))?;
+                    },
+                    Node::Internal(internal_node) => {
+                        writeln!(dot, "  n{offset} [label=\"{}\"];", dot_record("internal", offset, &internal_node.keys))?;
+                        for (i, child_offset) in internal_node.children.iter().enumerate() {
+                            let label = match internal_node.keys.get(i) {
+                                Some(key) => format!("<= {key:?}"),
+                                None => format!("> {:?}", internal_node.keys[i - 1]),
+                            };
+                            writeln!(dot, "  n{offset} -> n{child_offset} [label=\"{}\"];", dot_escape(&label, false))?;
+                        }
+                        next_level.extend(internal_node.children);
+                    },
+                }
+            }
+            level = next_level;
+        }
+
+        dot.push_str("}\n");
+        Ok(dot)
+    }
+
+    /// Decodes the node page at `offset` without descending the tree, to
+    /// inspect a page by its offset while debugging. Fails for an offset
+    /// that isn't the start of a page or lies beyond the end of the file.
+    /// Only file-backed trees have pages to dump.
+    pub fn dump_page(&self, offset: Offset) -> Result<Node<K, V>> {
+        Ok(self.pager.page_node(offset)?)
+    }
+
+    /// Returns the bytes of the node page at `offset` as they are on disk,
+    /// checksum and padding included, see [`BPTree::dump_page`].
+    pub fn dump_page_bytes(&self, offset: Offset) -> Result<Vec<u8>> {
+        Ok(self.pager.page_bytes(offset)?)
+    }
+
+    /// Writes an indented dump of every node to `out`, e.g.
+    /// `&mut std::io::stdout()` or a `Vec<u8>` to inspect in a test.
+    pub fn debug_print(&self, out: &mut dyn Write) -> Result<()>
+    where
+        K: Debug,
+        V: Debug,
+    {
+        if let Some(node_offset) = self.root_node {
+            let node = self.pager.read_at(node_offset)?;
+            node.debug_print(self.pager.operator(), out, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a few lines describing the shape of the tree to `out`: per
+    /// level from the root down, the number of nodes and keys and the
+    /// smallest and largest key, then the total number of keys in the
+    /// leaves. Unlike [`BPTree::debug_print`] it lists no entries, so it
+    /// stays short on a large tree.
+    pub fn debug_summary(&self, out: &mut dyn Write) -> Result<()>
+    where
+        K: Debug,
+    {
+        let mut level = self.root_node.into_iter().collect::<Vec<_>>();
+        let mut depth = 0;
+        let mut total_keys = 0;
+
+        while !level.is_empty() {
+            let nodes = level.len();
+            let mut next_level = Vec::new();
+            let mut keys = 0;
+            let (mut min, mut max) = (None, None);
+            for offset in level {
+                let node_keys = match self.pager.read_at(offset)? {
+                    Node::Leaf(leaf_node) => {
+                        total_keys += leaf_node.keys.len();
+                        leaf_node.keys
+                    },
+                    Node::Internal(internal_node) => {
+                        next_level.extend(internal_node.children);
+                        internal_node.keys
+                    },
+                };
+                keys += node_keys.len();
+                if min.is_none() {
+                    min = node_keys.first().cloned();
+                }
+                max = node_keys.last().cloned().or(max);
+            }
+
+            writeln!(out, "level {depth}: {nodes} nodes, {keys} keys, min = {min:?}, max = {max:?}")?;
+            level = next_level;
+            depth += 1;
+        }
+        writeln!(out, "total keys: {total_keys}")?;
+
+        Ok(())
+    }
+}
+
+impl<K, V, P: ?Sized + DynOperator<K, V>> BPTree<K, V, P> {
+    fn flush_header(&mut self) -> anyhow::Result<()> {
+        self.commit_header(false)
+    }
+
+    /// Writes the header, and syncs it if `sync` is set or the flush policy
+    /// says it is due. A header that is synced is written between two
+    /// syncs: the first makes the pages it points at durable before it can
+    /// reach the storage, the second makes the header itself durable.
+    fn commit_header(&mut self, sync: bool) -> anyhow::Result<()> {
+        // Stays set if the write fails, so that a flush retries it.
+        self.dirty = true;
+        let header = Header {
+            root_node: self.root_node,
+            degree: self.degree,
+            snapshots: self.snapshots.clone(),
+            short_right_edge: self.short_right_edge,
+            short_leaves: self.short_leaves,
+            ..Header::default()
+        };
+        let sync = sync || self.flush_policy.is_due(self.unsynced + 1);
+        if sync {
+            self.pager.flush()?;
+        }
+        self.pager.write_header(&header)?;
+        self.dirty = false;
+
+        self.unsynced += 1;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.pager.flush()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+/// Flushes the tree like [`BPTree::flush`], on a best-effort basis: an
+/// error is printed to stderr and a panic raised while flushing is caught
+/// and swallowed. Call `flush` before dropping the tree to handle errors.
+impl<K, V, P: ?Sized + DynOperator<K, V>> Drop for BPTree<K, V, P> {
+    fn drop(&mut self) {
+        let flushed = panic::catch_unwind(AssertUnwindSafe(|| {
+            if self.dirty {
+                self.commit_header(true)
+            } else {
+                self.pager.flush()
+            }
+        }));
+        match flushed {
+            Ok(Ok(())) => {},
+            Ok(Err(err)) => eprintln!("bptree: flush on drop failed: {err:#}"),
+            Err(_) => eprintln!("bptree: flush on drop panicked"),
+        }
+    }
+}
+
+impl<'a, K, V, P> IntoIterator for &'a BPTree<K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    type Item = Result<(K, V)>;
+    type IntoIter = TreeIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, K, V, P> IntoIterator for &'a mut BPTree<K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    type Item = Result<(K, V)>;
+    type IntoIter = TreeIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<V, P> BPTree<Key, V, P>
+where
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<Key, V>,
+{
+    /// Returns every pair whose key starts with `prefix` in the order of the
+    /// tree. With string ordering the walk starts at the prefix and stops
+    /// at the first key past it. Under a comparator that orders keys some
+    /// other way, keys with the prefix may lie anywhere, so every leaf is
+    /// walked.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(Key, V)>> {
+        if !self.comparator.orders_like_ord() {
+            return self.iter().filter(|pair| pair.as_ref().map_or(true, |(key, _)| key.starts_with(prefix))).collect();
+        }
+
+        let mut result = Vec::new();
+        let (mut cursor, mut leaf) = self.cursor_for(&prefix.to_string())?;
+
+        while let Some(leaf_node) = leaf {
+            for (key, value) in leaf_node.keys.iter().zip(leaf_node.values.iter()) {
+                if key.as_str() < prefix {
+                    continue;
+                }
+                if !key.starts_with(prefix) {
+                    return Ok(result);
+                }
+
+                result.push((key.clone(), value.clone()));
+            }
+
+            leaf = cursor.next(self.pager.operator())?;
+        }
+
+        Ok(result)
+    }
+
+    /// Looks up a borrowed key without building a `String` for it, for hot
+    /// lookup loops that already hold a `&str`. Keys are compared as
+    /// strings, which only finds them in string order: under a comparator
+    /// that orders keys some other way this builds the `String` and
+    /// searches like [`BPTree::search`].
+    pub fn search_str(&self, key: &str) -> Result<Option<V>> {
+        if !self.comparator.orders_like_ord() {
+            return self.search(key.to_string());
+        }
+
+        let Some(root_offset) = self.root_node else {
+            return Ok(None);
+        };
+
+        let mut node = self.pager.read_at(root_offset)?;
+        loop {
+            match node {
+                Node::Internal(internal_node) => {
+                    let position = internal_node
+                        .keys
+                        .binary_search_by(|probe| probe.as_str().cmp(key))
+                        .unwrap_or_else(|pos| pos);
+                    node = self.pager.read_at(internal_node.child(position)?)?;
+                },
+                Node::Leaf(leaf_node) => {
+                    let position = leaf_node.keys.binary_search_by(|probe| probe.as_str().cmp(key));
+                    return Ok(position.ok().map(|position| leaf_node.values[position].clone()));
+                },
+            }
+        }
+    }
+
+    /// Returns the pair whose key is closest to `key`: the floor or the
+    /// ceiling of `key`, whichever shares the longer prefix with it, or on a
+    /// tie has the nearer byte where it diverges. A key that ends there
+    /// counts as a zero byte. The floor wins an exact tie, and an empty tree
+    /// has no nearest pair.
+    pub fn nearest(&self, key: Key) -> Result<Option<(Key, V)>> {
+        let floor = self.floor(key.clone())?;
+        if floor.as_ref().is_some_and(|(floor_key, _)| *floor_key == key) {
+            return Ok(floor);
+        }
+
+        match (floor, self.ceiling(key.clone())?) {
+            (Some(floor), Some(ceiling)) if key_distance(&ceiling.0, &key) < key_distance(&floor.0, &key) => Ok(Some(ceiling)),
+            (Some(floor), _) => Ok(Some(floor)),
+            (None, ceiling) => Ok(ceiling),
+        }
+    }
+}
+
+/// Distance between two string keys for [`BPTree::nearest`], ordered from
+/// closest: a longer common prefix, then a smaller gap between the first
+/// bytes that differ.
+fn key_distance(a: &str, b: &str) -> (Reverse<usize>, u8) {
+    let common = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    let byte = |key: &str| key.as_bytes().get(common).copied().unwrap_or(0);
+    (Reverse(common), byte(a).abs_diff(byte(b)))
+}
+
+/// Record label of a node: its kind and offset followed by one field per
+/// key.
+fn dot_record<K: Debug>(kind: &str, offset: Offset, keys: &[K]) -> String {
+    let mut fields = vec![format!("{kind} {offset}")];
+    fields.extend(keys.iter().map(|key| dot_escape(&format!("{key:?}"), true)));
+    fields.join(" | ")
+}
+
+/// Escapes the characters that are special in a quoted DOT string and, for
+/// record labels, the ones that delimit record fields.
+fn dot_escape(text: &str, record: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\') || (record && matches!(c, '{' | '}' | '|' | '<' | '>')) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Calls `f` for every pair between `start` and `end` of the version at
+/// `root_node`, walking on leaf by leaf from the first leaf in range.
+pub(crate) fn walk_range<K, V, F>(
+    pager: &dyn PageRead<K, V>,
+    cmp: &dyn KeyComparator<K>,
+    root_node: Option<Offset>,
+    start: Bound<K>,
+    end: Bound<K>,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    K: Ord + Clone,
+    V: Clone,
+    F: FnMut(&K, &V) -> anyhow::Result<()>,
+{
+    let (mut cursor, mut leaf) = match &start {
+        Bound::Included(key) | Bound::Excluded(key) => LeafCursor::seek(pager, cmp, root_node, key)?,
+        Bound::Unbounded => LeafCursor::first(pager, root_node)?,
+    };
+
+    while let Some(leaf_node) = leaf {
+        for (key, value) in leaf_node.keys.iter().zip(leaf_node.values.iter()) {
+            let after_start = match &start {
+                Bound::Included(start) => cmp.compare(key, start) != Ordering::Less,
+                Bound::Excluded(start) => cmp.compare(key, start) == Ordering::Greater,
+                Bound::Unbounded => true,
+            };
+            if !after_start {
+                continue;
+            }
+
+            let before_end = match &end {
+                Bound::Included(end) => cmp.compare(key, end) != Ordering::Greater,
+                Bound::Excluded(end) => cmp.compare(key, end) == Ordering::Less,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                return Ok(());
+            }
+
+            f(key, value)?;
+        }
+
+        leaf = cursor.next(pager)?;
+    }
+
+    Ok(())
+}
+
+/// Largest encoded key and value an entry may have.
+#[derive(Clone, Copy)]
+struct EntryLimits {
+    max_key_len: usize,
+    max_value_len: usize,
+    encoding: Encoding,
+}
+
+impl EntryLimits {
+    /// Encoded length of `key`, or zero when keys aren't limited.
+    fn key_len<K: Encode>(&self, key: &K) -> anyhow::Result<usize> {
+        match self.max_key_len {
+            usize::MAX => Ok(0),
+            _ => Ok(self.encoding.encoded_len(key)?),
+        }
+    }
+
+    fn check<V: Encode>(&self, key_len: usize, value: &V) -> anyhow::Result<()> {
+        if key_len > self.max_key_len {
+            return Err(BPTreeError::KeyTooLarge { size: key_len, limit: self.max_key_len }.into());
+        }
+        if self.max_value_len < usize::MAX {
+            let size = self.encoding.encoded_len(value)?;
+            if size > self.max_value_len {
+                return Err(BPTreeError::ValueTooLarge { size, limit: self.max_value_len }.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `data` preceded by its length, as [`BPTree::export`] does for
+/// every key and value.
+fn write_record(out: &mut impl Write, data: &[u8]) -> anyhow::Result<()> {
+    out.write_all(&u32::try_from(data.len())?.to_le_bytes())?;
+    out.write_all(data)?;
+    Ok(())
+}
+
+/// Reads one length-prefixed record, or `None` if `reader` ends where the
+/// next one would start.
+fn read_record(reader: &mut impl std::io::Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut length = [0x00; 4];
+    let mut filled = 0;
+    while filled < length.len() {
+        match reader.read(&mut length[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            read => filled += read,
+        }
+    }
+
+    // The length comes from the reader, so the buffer only grows as bytes
+    // arrive rather than being sized up front by what may be garbage.
+    let length = u64::from(u32::from_le_bytes(length));
+    let mut data = Vec::new();
+    std::io::Read::read_to_end(&mut std::io::Read::take(reader, length), &mut data)?;
+    if (data.len() as u64) < length {
+        anyhow::bail!("import: record of {length} bytes ends after {}", data.len());
+    }
+    Ok(Some(data))
+}
+
+/// Sorts `pairs` by key, keeping only the last value given for each key.
+pub(crate) fn sort_dedup<K, V>(cmp: &dyn KeyComparator<K>, pairs: impl IntoIterator<Item = (K, V)>) -> Vec<(K, V)> {
+    let mut pairs = pairs.into_iter().collect::<Vec<_>>();
+    // The sort is stable, so later values of a key stay after earlier ones.
+    pairs.sort_by(|a, b| cmp.compare(&a.0, &b.0));
+
+    let mut unique: Vec<(K, V)> = Vec::with_capacity(pairs.len());
+    for (key, value) in pairs {
+        match unique.last_mut() {
+            Some(last) if cmp.compare(&last.0, &key) == Ordering::Equal => last.1 = value,
+            _ => unique.push((key, value)),
+        }
+    }
+    unique
+}
+
+/// State carried across the leaves of a `validate` walk.
+struct ValidateWalk {
+    leaf_depth: Option<usize>,
+    /// Offsets of the nodes visited so far.
+    visited: HashSet<Offset>,
+}
+
+/// Splits `len` entries into chunks of `max`, evening out the last two
+/// chunks when the trailing one would hold fewer than `min` entries.
+fn packed_sizes(len: usize, max: usize, min: usize) -> Vec<usize> {
+    let mut sizes = vec![max; len / max];
+    let rest = len % max;
+
+    if rest > 0 {
+        match sizes.pop() {
+            Some(last) if rest < min => {
+                let total = last + rest;
+                sizes.push(total - total / 2);
+                sizes.push(total / 2);
+            },
+            Some(last) => {
+                sizes.push(last);
+                sizes.push(rest);
+            },
+            None => sizes.push(rest),
+        }
+    }
+
+    sizes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        fs::OpenOptions,
+        io::Cursor,
+    };
+
+    use crate::compare::ShortSeparators;
+    use crate::header::FORMAT_VERSION;
+    use crate::pager::{memory::MemoryPager, HEADER_SIZE, STARTUP_OFFSET};
+
+    use super::*;
+
+    #[test]
+    fn test_tree_structure() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/test_tree_structure.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+
+        tree.insert("0010".to_string(), "ten".as_bytes().to_vec())?;
+        tree.insert("0020".to_string(), "twenty".as_bytes().to_vec())?;
+        tree.insert("0005".to_string(), "five".as_bytes().to_vec())?;
+        tree.insert("0006".to_string(), "six".as_bytes().to_vec())?;
+        tree.insert("0012".to_string(), "twelve".as_bytes().to_vec())?;
+        tree.insert("0030".to_string(), "thirty".as_bytes().to_vec())?;
+        tree.insert("0007".to_string(), "seven".as_bytes().to_vec())?;
+        tree.insert("0017".to_string(), "seventeen".as_bytes().to_vec())?;
+
+        assert_eq!(tree.search("0010".to_string())?, Some("ten".as_bytes().to_vec()));
+        assert_eq!(tree.search("0020".to_string())?, Some("twenty".as_bytes().to_vec()));
+        assert_eq!(tree.search("0005".to_string())?, Some("five".as_bytes().to_vec()));
+        assert_eq!(tree.search("0006".to_string())?, Some("six".as_bytes().to_vec()));
+        assert_eq!(tree.search("0012".to_string())?, Some("twelve".as_bytes().to_vec()));
+        assert_eq!(tree.search("0030".to_string())?, Some("thirty".as_bytes().to_vec()));
+        assert_eq!(tree.search("0007".to_string())?, Some("seven".as_bytes().to_vec()));
+        assert_eq!(
+            tree.search("0017".to_string())?,
+            Some("seventeen".as_bytes().to_vec())
+        );
+
+        assert_eq!(tree.search("2000".to_string())?, None);
+        assert_eq!(tree.search("3000".to_string())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_insertions() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/test_large_insertions.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(300, STARTUP_OFFSET, file)?;
+
+        for i in 1..=100000 {
+            tree.insert(i.to_string(), i.to_string().as_bytes().to_vec())?;
+        }
+
+        for i in 1..=100000 {
+            assert_eq!(tree.search(i.to_string())?, Some(i.to_string().as_bytes().to_vec()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn assemble_disassemble() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/assemble_disassemble.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(4, 0, file)?;
+
+        let key_value_pairs = BTreeMap::from([
+            ("001".to_string(), "derby".as_bytes().to_vec()),
+            ("002".to_string(), "elephant".as_bytes().to_vec()),
+            ("003".to_string(), "four".as_bytes().to_vec()),
+            ("004".to_string(), "avengers".as_bytes().to_vec()),
+            ("005".to_string(), "bing".as_bytes().to_vec()),
+            ("006".to_string(), "center".as_bytes().to_vec()),
+            ("007".to_string(), "center".as_bytes().to_vec()),
+            ("008".to_string(), "bing".as_bytes().to_vec()),
+            ("009".to_string(), "center".as_bytes().to_vec()),
+            ("010".to_string(), "center".as_bytes().to_vec()),
+            ("011".to_string(), "derby".as_bytes().to_vec()),
+            ("012".to_string(), "elephant".as_bytes().to_vec()),
+            ("013".to_string(), "four".as_bytes().to_vec()),
+            ("014".to_string(), "avengers".as_bytes().to_vec()),
+            ("015".to_string(), "bing".as_bytes().to_vec()),
+            ("016".to_string(), "center".as_bytes().to_vec()),
+            ("017".to_string(), "center".as_bytes().to_vec()),
+            ("018".to_string(), "bing".as_bytes().to_vec()),
+            ("019".to_string(), "center".as_bytes().to_vec()),
+            ("020".to_string(), "center".as_bytes().to_vec()),
+        ]);
+
+        for (key, value) in &key_value_pairs {
+            tree.insert(key.clone(), value.clone())?;
+        }
+
+        for (key, value) in &key_value_pairs {
+            assert_eq!(tree.search(key.clone())?, Some(value.clone()));
+        }
+
+        assert!(!tree.is_empty()?);
+
+        tree.delete("006".to_string())?;
+        tree.delete("012".to_string())?;
+        tree.delete("002".to_string())?;
+        tree.delete("005".to_string())?;
+        tree.delete("001".to_string())?;
+        tree.delete("003".to_string())?;
+        tree.delete("004".to_string())?;
+        tree.delete("007".to_string())?;
+        tree.delete("008".to_string())?;
+        tree.delete("009".to_string())?;
+        tree.delete("010".to_string())?;
+        tree.delete("011".to_string())?;
+        tree.delete("018".to_string())?;
+        tree.delete("019".to_string())?;
+        tree.delete("017".to_string())?;
+        tree.delete("020".to_string())?;
+        tree.delete("014".to_string())?;
+        tree.delete("015".to_string())?;
+        tree.delete("016".to_string())?;
+        tree.delete("013".to_string())?;
+
+        assert!(tree.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_works() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/delete_works.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+
+        let key_value_pairs = BTreeMap::from([
+            ("d".to_string(), "derby".as_bytes().to_vec()),
+            ("e".to_string(), "elephant".as_bytes().to_vec()),
+            ("f".to_string(), "four".as_bytes().to_vec()),
+            ("a".to_string(), "avengers".as_bytes().to_vec()),
+            ("b".to_string(), "bing".as_bytes().to_vec()),
+            ("c".to_string(), "center".as_bytes().to_vec()),
+            ("g".to_string(), "gover".as_bytes().to_vec()),
+        ]);
+
+        for (key, value) in &key_value_pairs {
+            tree.insert(key.clone(), value.clone())?;
+        }
+
+        for (key, value) in &key_value_pairs {
+            assert_eq!(tree.search(key.clone())?, Some(value.clone()));
+        }
+
+        let keys_for_delete = vec![
+            "f".to_string(),
+            "e".to_string(),
+            "c".to_string(),
+            "a".to_string(),
+            "b".to_string(),
+            "d".to_string(),
+            "g".to_string(),
+        ];
+
+        let mut deleted_keys = HashSet::new();
+
+        for key in &keys_for_delete {
+            tree.delete(key.clone())?;
+            assert_eq!(tree.search(key.clone())?, None);
+            deleted_keys.insert(key.clone());
+
+            for (initial_key, value) in &key_value_pairs {
+                if !deleted_keys.contains(initial_key) {
+                    assert_eq!(tree.search(initial_key.clone())?, Some(value.clone()));
+                }
+            }
+        }
+
+        assert!(tree.is_empty()?);
+        Ok(())
+    }
+
+    #[test]
+    fn deleting_absent_keys_writes_nothing() -> anyhow::Result<()> {
+        let path = "/tmp/deleting_absent_keys_writes_nothing.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in (0..100u32).step_by(2) {
+            tree.insert(i, i)?;
+        }
+        let root_node = tree.root_node;
+        let size = std::fs::metadata(path)?.len();
+        let size_pages = tree.file_size_pages();
+
+        for i in 0..1000u32 {
+            assert_eq!(tree.delete(i * 2 + 1)?, None);
+        }
+        assert_eq!(tree.remove(1001)?, None);
+        assert_eq!(tree.root_node, root_node);
+        assert_eq!(tree.file_size_pages(), size_pages);
+        assert_eq!(std::fs::metadata(path)?.len(), size);
+
+        // Nor is the header rewritten by a flush or a drop after them.
+        let writes = tree.io_stats().writes;
+        assert!(!tree.dirty);
+        tree.flush()?;
+        assert_eq!(tree.io_stats().writes, writes);
+        let mut deferred = BPTree::with_options(Cursor::new(Vec::new()), TreeOptions { delete_mode: DeleteMode::Deferred, ..TreeOptions::new(4) })?;
+        for i in (0..100u32).step_by(2) {
+            deferred.insert(i, i)?;
+        }
+        assert_eq!(deferred.delete(7)?, None);
+        assert!(!deferred.dirty);
+
+        assert_eq!(tree.remove(10)?, Some(10));
+        assert_eq!(tree.len()?, 49);
+
+        Ok(())
+    }
+
+    #[test]
+    fn is_empty_after_deleting_every_key() -> anyhow::Result<()> {
+        let ascending = (0..100).collect::<Vec<_>>();
+        let descending = (0..100).rev().collect::<Vec<_>>();
+        let middle_out = (0..50).flat_map(|i| [50 + i, 49 - i]).collect::<Vec<_>>();
+        let strided = (0..7).flat_map(|offset| (offset..100).step_by(7)).collect::<Vec<_>>();
+
+        for degree in [3, 4, 5] {
+            for order in [&ascending, &descending, &middle_out, &strided] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+                for i in 0..100 {
+                    tree.insert(format!("{i:03}"), b"value".to_vec())?;
+                }
+
+                for (deleted, i) in order.iter().enumerate() {
+                    assert!(!tree.is_empty()?);
+                    tree.delete(format!("{i:03}"))?;
+                    assert_eq!(tree.len()?, 99 - deleted);
+                }
+
+                tree.validate()?;
+                assert!(tree.is_empty()?);
+                assert_eq!(tree.iter().count(), 0);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_works() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/range_works.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+
+        for i in (10..100).step_by(2) {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        let keys = |pairs: Vec<(Key, Value)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded)?;
+        assert_eq!(all.len(), 45);
+        assert_eq!(all[0], ("0010".to_string(), "10".as_bytes().to_vec()));
+        assert_eq!(all[44], ("0098".to_string(), "98".as_bytes().to_vec()));
+
+        assert_eq!(
+            keys(tree.range(Bound::Included("0020".to_string()), Bound::Excluded("0026".to_string()))?),
+            vec!["0020", "0022", "0024"]
+        );
+        assert_eq!(
+            keys(tree.range(Bound::Excluded("0020".to_string()), Bound::Included("0026".to_string()))?),
+            vec!["0022", "0024", "0026"]
+        );
+
+        // Odd keys are never stored, so these bounds fall between entries and
+        // some of them between two leaves.
+        for start in (11..95).step_by(2) {
+            let result = keys(tree.range(
+                Bound::Included(format!("{start:04}")),
+                Bound::Included(format!("{:04}", start + 4)),
+            )?);
+            assert_eq!(result, vec![format!("{:04}", start + 1), format!("{:04}", start + 3)]);
+        }
+
+        assert!(tree.range(Bound::Included("0021".to_string()), Bound::Excluded("0022".to_string()))?.is_empty());
+        assert!(tree.range(Bound::Included("0050".to_string()), Bound::Excluded("0040".to_string()))?.is_empty());
+        assert!(tree.range(Bound::Excluded("0098".to_string()), Bound::Unbounded)?.is_empty());
+        assert!(tree.range(Bound::Unbounded, Bound::Excluded("0010".to_string()))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_range_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in (10..200u32).step_by(2) {
+            tree.insert(i, i)?;
+        }
+
+        for start in 0..210u32 {
+            for end in (start..215).step_by(7) {
+                let expected = tree.range(Bound::Included(start), Bound::Excluded(end))?.len();
+                assert_eq!(tree.count_range(start, end)?, expected, "[{start}, {end})");
+            }
+        }
+        assert_eq!(tree.count_range(0, 1000)?, 95);
+        assert_eq!(tree.count_range(50, 40)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn any_in_range_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(!tree.any_in_range(0, 100)?);
+
+        for i in (10..200u32).step_by(10) {
+            tree.insert(i, i)?;
+        }
+        for start in 0..210u32 {
+            for end in start..215 {
+                assert_eq!(tree.any_in_range(start, end)?, tree.count_range(start, end)? > 0, "[{start}, {end})");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn fold_range_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..100u32 {
+            tree.insert(i, i * 2)?;
+        }
+
+        assert_eq!(tree.fold_range(10, 20, 0, |sum, _, value| sum + value)?, (10..20).map(|i| i * 2).sum());
+        assert_eq!(tree.fold_range(0, 100, 0, |max, _, value| max.max(*value))?, 198);
+        assert_eq!(
+            tree.fold_range(3, 6, String::new(), |acc, key, _| acc + &key.to_string())?,
+            "345"
+        );
+        assert_eq!(tree.fold_range(200, 300, 7, |acc, _, _| acc + 1)?, 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_after_deletes() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/range_after_deletes.ldb")
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        let mut expected = BTreeMap::new();
+
+        for i in 0..200 {
+            let key = format!("{:04}", (i * 37) % 200);
+            tree.insert(key.clone(), key.as_bytes().to_vec())?;
+            expected.insert(key.clone(), key.as_bytes().to_vec());
+        }
+
+        for i in 0..150 {
+            let key = format!("{:04}", (i * 53) % 200);
+            tree.delete(key.clone())?;
+            expected.remove(&key);
+
+            let all = tree.range(Bound::Unbounded, Bound::Unbounded)?;
+            assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_works() -> anyhow::Result<()> {
+        let path = "/tmp/reopen_works.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..50 {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+        tree.delete("0010".to_string())?;
+        tree.flush()?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut tree = BPTree::open(file)?;
+
+        for i in 0..50 {
+            let expected = if i == 10 { None } else { Some(i.to_string().as_bytes().to_vec()) };
+            assert_eq!(tree.search(format!("{i:04}"))?, expected);
+        }
+
+        tree.insert("0100".to_string(), "hundred".as_bytes().to_vec())?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let tree = BPTree::open(file)?;
+        assert_eq!(tree.search("0100".to_string())?, Some("hundred".as_bytes().to_vec()));
+        assert_eq!(tree.search("0049".to_string())?, Some("49".as_bytes().to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn open_without_header_fails() {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/open_without_header_fails.ldb")
+            .unwrap();
+
+        assert!(BPTree::<Key, Value>::open(file).is_err());
+    }
+
+    #[test]
+    fn memory_pager_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.is_empty()?);
+
+        for i in 0..500 {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        for i in 0..500 {
+            assert_eq!(tree.search(format!("{i:04}"))?, Some(i.to_string().as_bytes().to_vec()));
+        }
+
+        for i in (0..500).step_by(2) {
+            tree.delete(format!("{i:04}"))?;
+        }
+
+        for i in 0..500 {
+            let expected = if i % 2 == 0 { None } else { Some(i.to_string().as_bytes().to_vec()) };
+            assert_eq!(tree.search(format!("{i:04}"))?, expected);
+        }
+
+        assert_eq!(tree.range(Bound::Unbounded, Bound::Unbounded)?.len(), 250);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_existing_key_replaces_value() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+
+        tree.insert("k".to_string(), "first".as_bytes().to_vec())?;
+        tree.insert("k".to_string(), "second".as_bytes().to_vec())?;
+
+        assert_eq!(tree.search("k".to_string())?, Some("second".as_bytes().to_vec()));
+        assert_eq!(
+            tree.range(Bound::Unbounded, Bound::Unbounded)?,
+            vec![("k".to_string(), "second".as_bytes().to_vec())]
+        );
+
+        for i in 0..20 {
+            tree.insert(format!("{i:02}"), "old".as_bytes().to_vec())?;
+        }
+        for i in 0..20 {
+            tree.insert(format!("{i:02}"), "new".as_bytes().to_vec())?;
+        }
+
+        let all = tree.range(Bound::Unbounded, Bound::Unbounded)?;
+        assert_eq!(all.len(), 21);
+        assert!(all.iter().filter(|(key, _)| key != "k").all(|(_, value)| value == "new".as_bytes()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_returns_removed_value() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+
+        assert_eq!(tree.delete("missing".to_string())?, None);
+
+        for i in 0..30 {
+            tree.insert(format!("{i:02}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        for i in 0..30 {
+            assert_eq!(tree.delete(format!("{i:02}"))?, Some(i.to_string().as_bytes().to_vec()));
+            assert_eq!(tree.delete(format!("{i:02}"))?, None);
+        }
+
+        assert!(tree.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn integer_keys_sort_numerically() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/integer_keys_sort_numerically.ldb")
+            .unwrap();
+
+        let mut tree: BPTree<u64, u64> = BPTree::new(4, STARTUP_OFFSET, file)?;
+
+        for i in (1..=100).rev() {
+            tree.insert(i, i * 10)?;
+        }
+
+        assert_eq!(tree.search(2)?, Some(20));
+        assert_eq!(tree.search(10)?, Some(100));
+        assert_eq!(tree.search(101)?, None);
+
+        let keys = tree
+            .range(Bound::Included(2), Bound::Included(10))?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, (2..=10).collect::<Vec<_>>());
+
+        assert_eq!(tree.delete(50)?, Some(500));
+        assert_eq!(tree.range(Bound::Unbounded, Bound::Unbounded)?.len(), 99);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.iter().next().is_none());
+
+        for i in (0..300).rev() {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        let pairs = tree.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 300);
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            assert_eq!(key, format!("{i:04}"));
+            assert_eq!(value, i.to_string().as_bytes().to_vec());
+        }
+
+        let first_three = tree.iter().take(3).map(|item| item.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(first_three, vec!["0000", "0001", "0002"]);
+
+        let mut count = 0;
+        for pair in &mut tree {
+            let (key, _) = pair?;
+            assert_eq!(key, format!("{count:04}"));
+            count += 1;
+        }
+        assert_eq!(count, 300);
+        assert_eq!((&tree).into_iter().count(), 300);
+
+        Ok(())
+    }
+
+    #[test]
+    fn works_over_any_storage() -> anyhow::Result<()> {
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, Cursor::new(Vec::new()))?;
+        for i in 0..500u32 {
+            tree.insert(i, i * 2)?;
+        }
+        assert_eq!(tree.delete_range(100, 200)?, 100);
+        tree.validate()?;
+        assert_eq!(tree.len()?, 400);
+        assert_eq!(tree.search(150)?, None);
+        assert_eq!(tree.search(250)?, Some(500));
+
+        let (compacted, stats) = tree.compact(Cursor::new(Vec::new()))?;
+        assert_eq!(stats.pages_after, compacted.file_size_pages());
+        compacted.validate()?;
+        assert_eq!(compacted.iter().collect::<Result<Vec<_>>>()?, tree.iter().collect::<Result<Vec<_>>>()?);
+
+        tree.clear()?;
+        assert!(tree.is_empty()?);
+        tree.insert(1, 1)?;
+        assert_eq!(tree.search(1)?, Some(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn works_over_an_operator_of_public_types() -> anyhow::Result<()> {
+        use std::cell::Cell;
+        use std::collections::HashMap;
+        use std::rc::Rc;
+
+        use crate::{Header, InternalNode, LeafNode, Node, Offset, PageOperator, PageRead};
+
+        // Spells every type through the crate root, as an operator written
+        // outside the crate has to. The `Rc` keeps it on one thread, which
+        // the tree doesn't ask of an operator.
+        #[derive(Default)]
+        struct Pages {
+            nodes: HashMap<Offset, Node<u32, u32>>,
+            header: Option<Header>,
+            cursor: Offset,
+            writes: Rc<Cell<usize>>,
+        }
+
+        impl PageRead<u32, u32> for Pages {
+            fn read_at(&self, offset: Offset) -> anyhow::Result<Node<u32, u32>> {
+                self.nodes.get(&offset).cloned().ok_or_else(|| anyhow::anyhow!("no page at {offset}"))
+            }
+        }
+
+        impl PageOperator<u32, u32> for Pages {
+            fn next_offset(&self) -> Offset {
+                self.cursor
+            }
+
+            fn allocate(&mut self) -> Offset {
+                self.cursor += 1;
+                self.cursor - 1
+            }
+
+            fn write_at(&mut self, node: &Node<u32, u32>, offset: Offset) -> anyhow::Result<()> {
+                let node = match node {
+                    Node::Leaf(LeafNode { keys, values, .. }) => {
+                        Node::Leaf(LeafNode { keys: keys.clone(), values: values.clone(), offset: Some(offset) })
+                    },
+                    Node::Internal(InternalNode { keys, children, .. }) => {
+                        Node::Internal(InternalNode { keys: keys.clone(), children: children.clone(), offset: Some(offset) })
+                    },
+                };
+                self.nodes.insert(offset, node);
+                self.writes.set(self.writes.get() + 1);
+                Ok(())
+            }
+
+            fn free(&mut self, offset: Offset) {
+                self.nodes.remove(&offset);
+            }
+
+            fn rollback(&mut self) {}
+
+            fn size_pages(&self) -> usize {
+                self.cursor
+            }
+
+            fn read_header(&mut self) -> anyhow::Result<Header> {
+                self.header.clone().ok_or_else(|| anyhow::anyhow!("no header"))
+            }
+
+            fn write_header(&mut self, header: &Header) -> anyhow::Result<()> {
+                self.header = Some(header.clone());
+                Ok(())
+            }
+
+            fn clear(&mut self) -> anyhow::Result<()> {
+                self.nodes.clear();
+                self.cursor = 0;
+                Ok(())
+            }
+        }
+
+        let pages = Pages::default();
+        let writes = Rc::clone(&pages.writes);
+        let mut tree = BPTree::with_pager(4, Box::new(pages))?;
+        for i in 0..200u32 {
+            tree.insert(i, i * 2)?;
+        }
+        assert_eq!(tree.delete_range(50, 150)?, 100);
+        tree.validate()?;
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?.len(), 100);
+        assert_eq!(tree.search(160)?, Some(320));
+        assert!(writes.get() >= 200);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keys_and_values_work() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.keys().next().is_none());
+        assert!(tree.values().next().is_none());
+
+        for i in (0..300).rev() {
+            tree.insert(format!("{i:04}"), vec![i as u8; 100])?;
+        }
+
+        let keys = tree.keys().collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (0..300).map(|i| format!("{i:04}")).collect::<Vec<_>>());
+        let values = tree.values().collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, (0..300).map(|i| vec![i as u8; 100]).collect::<Vec<_>>());
+        assert_eq!(tree.keys().nth(150).transpose()?, Some("0150".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn free_pages_are_reused() -> anyhow::Result<()> {
+        let path = "/tmp/free_pages_are_reused.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+
+        for i in 0..200 {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        // Freed pages are reused once a sync made the header freeing them
+        // durable.
+        let churn = |tree: &mut BPTree, rounds: usize| -> anyhow::Result<()> {
+            for i in 0..rounds {
+                let key = format!("{:04}", (i * 37) % 200);
+                tree.delete(key.clone())?;
+                tree.insert(key.clone(), key.as_bytes().to_vec())?;
+                if i % 20 == 19 {
+                    tree.flush()?;
+                }
+            }
+            Ok(())
+        };
+
+        churn(&mut tree, 400)?;
+        let size = tree.file_size_pages();
+        churn(&mut tree, 2000)?;
+        assert_eq!(tree.file_size_pages(), size);
+
+        for i in 0..200 {
+            let key = format!("{i:04}");
+            assert_eq!(tree.search(key.clone())?, Some(key.as_bytes().to_vec()));
+        }
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut tree = BPTree::open(file)?;
+        churn(&mut tree, 400)?;
+        assert_eq!(tree.file_size_pages(), size);
+        assert_eq!(tree.range(Bound::Unbounded, Bound::Unbounded)?.len(), 200);
+
+        Ok(())
+    }
+
+    #[test]
+    fn min_max_key_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.min_key()?, None);
+        assert_eq!(tree.max_key()?, None);
+
+        for i in [50, 20, 80, 10, 90, 30, 70, 40, 60] {
+            tree.insert(format!("{i:03}"), i.to_string().as_bytes().to_vec())?;
+        }
+
+        assert_eq!(tree.min_key()?, Some("010".to_string()));
+        assert_eq!(tree.max_key()?, Some("090".to_string()));
+        assert_eq!(tree.first_key_value()?, Some(("010".to_string(), b"10".to_vec())));
+        assert_eq!(tree.last_key_value()?, Some(("090".to_string(), b"90".to_vec())));
+
+        tree.delete("010".to_string())?;
+        tree.delete("090".to_string())?;
+        assert_eq!(tree.min_key()?, Some("020".to_string()));
+        assert_eq!(tree.max_key()?, Some("080".to_string()));
+
+        for i in [20, 30, 40, 50, 60, 70, 80] {
+            tree.delete(format!("{i:03}"))?;
+        }
+        assert_eq!(tree.min_key()?, None);
+        assert_eq!(tree.max_key()?, None);
+        assert_eq!(tree.first_key_value()?, None);
+        assert_eq!(tree.last_key_value()?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn first_n_and_last_n_work() -> anyhow::Result<()> {
+        let mut tree = BPTree::new_with_cache(4, STARTUP_OFFSET, Cursor::new(Vec::new()), 0)?;
+        assert!(tree.first_n(3)?.is_empty());
+        assert!(tree.last_n(3)?.is_empty());
+
+        for i in (0..100u32).rev() {
+            tree.insert(i, i * 10)?;
+        }
+        assert_eq!(tree.first_n(3)?, vec![(0, 0), (1, 10), (2, 20)]);
+        assert_eq!(tree.last_n(3)?, vec![(99, 990), (98, 980), (97, 970)]);
+        assert!(tree.first_n(0)?.is_empty());
+        assert_eq!(tree.first_n(150)?.len(), 100);
+        assert_eq!(tree.last_n(150)?.len(), 100);
+
+        // Only the leaves holding the pairs asked for are read.
+        let leaves = tree.stats()?.leaf_nodes;
+        let reads = tree.io_stats().reads;
+        tree.first_n(5)?;
+        tree.last_n(5)?;
+        let reads = tree.io_stats().reads - reads;
+        assert!(reads > 0 && reads < leaves as u64, "{reads} reads for {leaves} leaves");
+
+        Ok(())
+    }
+
+    #[test]
+    fn degree_below_minimum_is_rejected() {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/degree_below_minimum_is_rejected.ldb")
+            .unwrap();
+
+        assert!(matches!(
+            BPTree::<Key, Value>::new(2, STARTUP_OFFSET, file),
+            Err(BPTreeError::InvalidDegree(2))
+        ));
+        assert!(BPTree::<Key, Value>::with_pager(1, Box::new(MemoryPager::new())).is_err());
+        assert!(BPTree::<Key, Value>::with_pager(MIN_DEGREE, Box::new(MemoryPager::new())).is_ok());
+    }
+
+    #[test]
+    fn new_tree_can_be_reopened() -> anyhow::Result<()> {
+        let path = "/tmp/new_tree_can_be_reopened.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        drop(BPTree::<Key, Value>::new(4, STARTUP_OFFSET, file)?);
+
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let tree = BPTree::<Key, Value>::open(file)?;
+        assert!(tree.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn estimate_len_is_close_and_cheap() -> anyhow::Result<()> {
+        let mut tree = BPTree::new_with_cache(8, STARTUP_OFFSET, Cursor::new(Vec::new()), 0)?;
+        assert_eq!(tree.estimate_len()?, 0);
+        for i in 0..5u64 {
+            tree.insert(i, i)?;
+        }
+        assert_eq!(tree.estimate_len()?, 5);
+
+        // Keys in a scattered order, so the leaves fill unevenly.
+        for i in 0..20_000u64 {
+            tree.insert(i * 7919 % 20_011, i)?;
+        }
+        let len = tree.len()?;
+        let before = tree.io_stats();
+        let estimate = tree.estimate_len()?;
+        let reads = tree.io_stats().reads - before.reads;
+
+        assert!(estimate.abs_diff(len) * 5 < len, "estimated {estimate} for {len}");
+        assert!(reads as usize <= 16 * tree.stats()?.height, "{reads} reads");
+        assert!((reads as usize) < tree.stats()?.leaf_nodes / 10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn len_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.len()?, 0);
+        assert!(tree.is_empty()?);
+
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), i.to_string().as_bytes().to_vec())?;
+            assert_eq!(tree.len()?, i + 1);
+        }
+
+        tree.insert("050".to_string(), "again".as_bytes().to_vec())?;
+        assert_eq!(tree.len()?, 100);
+
+        for i in 0..100 {
+            tree.delete(format!("{i:03}"))?;
+            assert_eq!(tree.len()?, 99 - i);
+            assert_eq!(tree.is_empty()?, i == 99);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_sibling_returns_error() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for key in ["a", "b", "c", "d"] {
+            tree.insert(key.to_string(), key.as_bytes().to_vec())?;
+        }
+
+        let root_offset = tree.root_node.unwrap();
+        let Node::Internal(root) = tree.pager.read_at(root_offset)? else {
+            panic!("expected the root to split");
+        };
+        let corrupted = Node::Internal(InternalNode {
+            keys: vec![],
+            children: vec![],
+            offset: Some(root.children[0]),
+        });
+        tree.pager.write_at(&corrupted, root.children[0])?;
+
+        // Emptying the right leaf makes it borrow from the corrupted one.
+        tree.delete("c".to_string())?;
+        assert!(tree.delete("d".to_string()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn childless_internal_node_fails_reads() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..20u8 {
+            tree.insert(format!("{i:02}"), vec![i])?;
+        }
+
+        // A page that decodes cleanly into an internal node without
+        // children, in place of the root.
+        let root_offset = tree.root_node.unwrap();
+        let corrupted = Node::Internal(InternalNode {
+            keys: vec!["10".to_string()],
+            children: vec![],
+            offset: Some(root_offset),
+        });
+        tree.pager.write_at(&corrupted, root_offset)?;
+
+        assert!(tree.search("05".to_string()).is_err());
+        assert!(tree.search_str("15").is_err());
+        assert!(tree.search_path("15".to_string()).is_err());
+        assert!(tree.entry("05".to_string()).is_err());
+        assert!(tree.min_key().is_err());
+        assert!(tree.max_key().is_err());
+        assert!(tree.iter().next().unwrap().is_err());
+        assert!(tree.iter_rev().next().unwrap().is_err());
+        assert!(tree.range(Bound::Unbounded, Bound::Unbounded).is_err());
+        let err = tree.estimate_len().unwrap_err();
+        assert!(err.to_string().contains("has no children"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_child_internal_node_fails_rebalance() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..4u32 {
+            tree.insert(i, i)?;
+        }
+
+        // A malformed root whose only child is a leaf at the minimum: the
+        // delete underflows it with no sibling to borrow from or merge with.
+        let root_offset = tree.root_node.unwrap();
+        let Node::Internal(root) = tree.pager.read_at(root_offset)? else {
+            panic!("expected the root to split");
+        };
+        let mut leaf_node = tree.pager.read_at(root.children[0])?.into_leaf()?;
+        leaf_node.keys.truncate(1);
+        leaf_node.values.truncate(1);
+        tree.pager.write_at(&Node::Leaf(leaf_node), root.children[0])?;
+        let malformed = Node::Internal(InternalNode {
+            keys: vec![],
+            children: vec![root.children[0]],
+            offset: Some(root_offset),
+        });
+        tree.pager.write_at(&malformed, root_offset)?;
+
+        let err = tree.delete(0).unwrap_err();
+        assert!(err.to_string().contains("has no sibling for child 0"), "{err}");
+        assert_eq!(tree.search(0)?, Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_page_fails_checksum() -> anyhow::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let path = "/tmp/corrupted_page_fails_checksum.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        tree.insert("key".to_string(), "value".as_bytes().to_vec())?;
+        let root_offset = tree.root_node.unwrap();
+        drop(tree);
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(root_offset as u64 + 8))?;
+        file.write_all(b"K")?;
+
+        let tree = BPTree::<Key, Value>::open(file)?;
+        let err = tree.search("key".to_string()).unwrap_err();
+        assert!(matches!(err, BPTreeError::Corrupt { offset } if offset == root_offset), "{err}");
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupted_page_is_rejected_before_decoding() -> anyhow::Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let path = "/tmp/corrupted_page_is_rejected_before_decoding.ldb";
+        for (at, bytes) in [(4, &u32::MAX.to_le_bytes()[..]), (4, &1u32.to_le_bytes()[..]), (8, &[0xFF][..])] {
+            let file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(true)
+                .open(path)
+                .unwrap();
+
+            let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+            tree.insert("key".to_string(), "value".as_bytes().to_vec())?;
+            let root_offset = tree.root_node.unwrap();
+            drop(tree);
+
+            let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+            file.seek(SeekFrom::Start((root_offset + at) as u64))?;
+            file.write_all(bytes)?;
+
+            let tree = BPTree::<Key, Value>::open(file)?;
+            let err = tree.search("key".to_string()).unwrap_err();
+            assert!(matches!(err, BPTreeError::Corrupt { offset } if offset == root_offset), "{err}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_page_fails_to_read() -> anyhow::Result<()> {
+        let path = "/tmp/truncated_page_fails_to_read.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        tree.insert("key".to_string(), "value".as_bytes().to_vec())?;
+        let root_offset = tree.root_node.unwrap();
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        file.set_len(root_offset as u64 + 10)?;
+
+        let tree = BPTree::<Key, Value>::open(file)?;
+        let err = tree.search("key".to_string()).unwrap_err();
+        assert!(matches!(&err, BPTreeError::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof), "{err}");
+        assert!(err.to_string().contains(&format!("page at offset {root_offset} ends after 10 bytes")), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_many_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        let mut expected = BTreeMap::new();
+
+        tree.insert_many((0..200).map(|i| (format!("{:04}", i * 2), b"first".to_vec())))?;
+        for i in 0..200 {
+            expected.insert(format!("{:04}", i * 2), b"first".to_vec());
+        }
+
+        // Interleave new keys with existing ones, repeat some of them and
+        // feed them in an unsorted order.
+        let mut batch = Vec::new();
+        for i in (0..400).rev() {
+            batch.push((format!("{:04}", i), b"stale".to_vec()));
+        }
+        for i in (0..400).step_by(3) {
+            batch.push((format!("{:04}", i), i.to_string().into_bytes()));
+        }
+        for (key, value) in &batch {
+            expected.insert(key.clone(), value.clone());
+        }
+        tree.insert_many(batch)?;
+
+        tree.validate()?;
+        assert_eq!(tree.len()?, expected.len());
+        let pairs = tree.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, expected.clone().into_iter().collect::<Vec<_>>());
+        assert_eq!(
+            tree.range(Bound::Included("0100".to_string()), Bound::Excluded("0110".to_string()))?,
+            expected.range("0100".to_string().."0110".to_string()).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>()
+        );
+
+        for i in (0..400).step_by(2) {
+            let key = format!("{:04}", i);
+            assert_eq!(tree.delete(key.clone())?, expected.remove(&key));
+        }
+        let pairs = tree.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, expected.into_iter().collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load_works() -> anyhow::Result<()> {
+        let path = "/tmp/bulk_load_works.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let pairs = (0..1000).map(|i| (format!("{i:04}"), i.to_string().into_bytes())).collect::<Vec<_>>();
+        let mut tree = BPTree::bulk_load(4, file, pairs.clone().into_iter())?;
+
+        tree.validate()?;
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, pairs);
+        assert_eq!(tree.search("0500".to_string())?, Some(b"500".to_vec()));
+        assert_eq!(tree.min_key()?, Some("0000".to_string()));
+        assert_eq!(tree.max_key()?, Some("0999".to_string()));
+
+        // Full leaves hold three keys each, so 334 leaves plus the internal
+        // levels above them.
+        assert!(tree.file_size_pages() < 550, "{} pages", tree.file_size_pages());
+
+        for i in (0..1000).step_by(3) {
+            assert_eq!(tree.delete(format!("{i:04}"))?, Some(i.to_string().into_bytes()));
+        }
+        tree.insert("0000".to_string(), b"again".to_vec())?;
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open(file)?;
+        assert_eq!(tree.len()?, 667);
+        assert_eq!(tree.search("0000".to_string())?, Some(b"again".to_vec()));
+        assert_eq!(tree.search("0003".to_string())?, None);
+
+        let file = OpenOptions::new().read(true).write(true).truncate(true).open(path)?;
+        let unsorted: Vec<(Key, Value)> = vec![("b".to_string(), vec![]), ("a".to_string(), vec![])];
+        assert!(BPTree::bulk_load(4, file, unsorted.into_iter()).is_err());
+
+        // The last leaves are evened out rather than left short, whatever
+        // the number of pairs.
+        for len in 0..40u32 {
+            for degree in [3, 4, 5, 8] {
+                let tree = BPTree::<u32, u32>::bulk_load(degree, Cursor::new(Vec::new()), (0..len).map(|i| (i, i)))?;
+                tree.validate()?;
+                assert_eq!(tree.len()?, len as usize);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_works() -> anyhow::Result<()> {
+        let path = "/tmp/clear_works.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        // Appending splits and deferred deletes leave nodes short, which
+        // the tree records.
+        let mut tree = BPTree::new(8, STARTUP_OFFSET, file)?.with_split_bias(SplitBias::Append).with_delete_mode(DeleteMode::Deferred);
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        for i in (0..100).filter(|i| i % 3 != 0) {
+            tree.delete(format!("{i:03}"))?;
+        }
+        assert!(tree.short_right_edge && tree.short_leaves);
+
+        tree.clear()?;
+        assert!(tree.is_empty()?);
+        assert_eq!(tree.search("050".to_string())?, None);
+        assert_eq!(std::fs::metadata(path)?.len(), STARTUP_OFFSET as u64);
+        assert!(!tree.short_right_edge && !tree.short_leaves);
+
+        let mut tree = tree.with_split_bias(SplitBias::Middle).with_delete_mode(DeleteMode::Rebalance);
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        let size_pages = tree.file_size_pages();
+        tree.clear()?;
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        assert_eq!(tree.file_size_pages(), size_pages);
+        drop(tree);
+
+        // The cleared tree is checked against strict occupancy again.
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open(file)?;
+        assert!(!tree.short_right_edge && !tree.short_leaves);
+        tree.validate()?;
+        assert_eq!(tree.len()?, 100);
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_str_matches_search() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.search_str("a")?, None);
+
+        for i in (0..200).step_by(2) {
+            tree.insert(format!("key{i:03}"), i)?;
+        }
+        for i in 0..200 {
+            let key = format!("key{i:03}");
+            assert_eq!(tree.search_str(&key)?, tree.search(key.clone())?);
+        }
+        assert_eq!(tree.search_str("key010")?, Some(10));
+        assert_eq!(tree.search_str("key011")?, None);
+        assert_eq!(tree.search_str("")?, None);
+        assert_eq!(tree.search_str("zzz")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn nearest_picks_the_closer_neighbour() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.nearest("apple".to_string())?, None);
+
+        for key in ["apple", "apply", "banana", "cherry", "mango", "melon"] {
+            tree.insert(key.to_string(), key.as_bytes().to_vec())?;
+        }
+
+        let nearest = |key: &str| -> anyhow::Result<Option<Key>> { Ok(tree.nearest(key.to_string())?.map(|(key, _)| key)) };
+        assert_eq!(nearest("cherry")?.as_deref(), Some("cherry"));
+        // Only one neighbour exists at either end.
+        assert_eq!(nearest("aardvark")?.as_deref(), Some("apple"));
+        assert_eq!(nearest("zebra")?.as_deref(), Some("melon"));
+        // "applz" shares "appl" with both; 'z' is nearer to 'y' than to 'e'.
+        assert_eq!(nearest("applz")?.as_deref(), Some("apply"));
+        // "bz" shares "b" with the floor "banana" and nothing with "cherry".
+        assert_eq!(nearest("bz")?.as_deref(), Some("banana"));
+        // "mb" diverges from "mango" by one byte and from "melon" by three.
+        assert_eq!(nearest("mb")?.as_deref(), Some("mango"));
+        assert_eq!(nearest("md")?.as_deref(), Some("melon"));
+        // 'c' is as far from 'a' as from 'e', so the floor wins.
+        assert_eq!(nearest("mc")?.as_deref(), Some("mango"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn locate_finds_the_leaf_and_position() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.locate(1)?, None);
+
+        for i in (0..100u32).step_by(2) {
+            tree.insert(i, i * 10)?;
+        }
+        for i in (0..100u32).step_by(2) {
+            let (offset, position) = tree.locate(i)?.unwrap();
+            let leaf_node = tree.pager.read_at(offset)?.into_leaf()?;
+            assert_eq!(leaf_node.keys[position], i);
+            assert_eq!(leaf_node.values[position], i * 10);
+        }
+        assert_eq!(tree.locate(51)?, None);
+        assert_eq!(tree.locate(1000)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn search_path_follows_the_descent() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.search_path("key".to_string())?.is_empty());
+
+        tree.insert("000".to_string(), b"0".to_vec())?;
+        assert_eq!(tree.search_path("000".to_string())?, vec![tree.root_node.unwrap()]);
+
+        for i in 1..100 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        let height = tree.stats()?.height;
+        let leaves = tree.leaf_offsets()?;
+        for i in [0, 37, 99] {
+            let key = format!("{i:03}");
+            let path = tree.search_path(key.clone())?;
+            assert_eq!(path.len(), height);
+            assert_eq!(path[0], tree.root_node.unwrap());
+
+            let leaf = tree.pager.read_at(*path.last().unwrap())?.into_leaf()?;
+            assert!(leaves.contains(path.last().unwrap()));
+            assert!(leaf.keys.contains(&key));
+        }
+        assert!(height > 2);
+        // A missing key past the last one ends at the rightmost leaf.
+        assert_eq!(tree.search_path("999".to_string())?.last(), leaves.last());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_from_pages_through_the_tree() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.scan_from(None, 10)?.is_empty());
+
+        for i in (0..100).step_by(2) {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = tree.scan_from(after.clone(), 7)?;
+            if page.is_empty() {
+                break;
+            }
+            assert!(page.len() <= 7);
+            after = page.last().map(|(key, _)| key.clone());
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), 8);
+        assert_eq!(pages.concat(), tree.iter().collect::<Result<Vec<_>>>()?);
+
+        // A cursor that is no longer stored, or was never stored, still
+        // resumes right after it, even when it falls between two leaves.
+        let keys = |pairs: Vec<(Key, Value)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        for pair in tree.leaf_offsets()?.windows(2) {
+            let leaf = tree.pager.read_at(pair[0])?.into_leaf()?;
+            let next = tree.pager.read_at(pair[1])?.into_leaf()?;
+            let last = leaf.keys.last().unwrap().clone();
+            let between = format!("{last}5");
+            assert_eq!(keys(tree.scan_from(Some(between), 1)?), vec![next.keys[0].clone()]);
+        }
+        assert_eq!(keys(tree.scan_from(Some("041".to_string()), 2)?), vec!["042", "044"]);
+        tree.delete("042".to_string())?;
+        assert_eq!(keys(tree.scan_from(Some("042".to_string()), 2)?), vec!["044", "046"]);
+        assert!(tree.scan_from(Some("098".to_string()), 5)?.is_empty());
+        assert!(tree.scan_from(None, 0)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.scan_prefix("a")?.is_empty());
+
+        for key in ["app", "apple", "applied", "apply", "apt", "ap", "banana", "a", "bandana", "apps"] {
+            tree.insert(key.to_string(), key.as_bytes().to_vec())?;
+        }
+
+        let keys = |pairs: Vec<(Key, Value)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(tree.scan_prefix("appl")?), vec!["apple", "applied", "apply"]);
+        assert_eq!(keys(tree.scan_prefix("app")?), vec!["app", "apple", "applied", "apply", "apps"]);
+        assert_eq!(keys(tree.scan_prefix("ban")?), vec!["banana", "bandana"]);
+        assert_eq!(tree.scan_prefix("")?.len(), 10);
+        assert!(tree.scan_prefix("c")?.is_empty());
+        assert!(tree.scan_prefix("applz")?.is_empty());
+
+        Ok(())
+    }
+
+    /// Checks that every node below the root holds between `min_keys` and
+    /// `degree - 1` keys and returns the number of keys in the subtree.
+    fn check_occupancy(tree: &BPTree<u32, u32>, offset: Offset, is_root: bool) -> anyhow::Result<usize> {
+        let degree = tree.degree;
+        match tree.pager.read_at(offset)? {
+            Node::Leaf(leaf_node) => check_bounds(leaf_node.keys.len(), degree, is_root, offset),
+            Node::Internal(internal_node) => {
+                check_bounds(internal_node.keys.len(), degree, is_root, offset)?;
+                let mut count = 0;
+                for child_offset in internal_node.children {
+                    count += check_occupancy(tree, child_offset, false)?;
+                }
+                Ok(count)
+            },
+        }
+    }
+
+    fn check_bounds(len: usize, degree: usize, is_root: bool, offset: Offset) -> anyhow::Result<usize> {
+        if len > degree - 1 || (!is_root && len < min_keys(degree)) {
+            anyhow::bail!("node at offset {offset} holds {len} keys with degree {degree}");
+        }
+        Ok(len)
+    }
+
+    #[test]
+    fn occupancy_holds_for_odd_degrees() -> anyhow::Result<()> {
+        let mut state = 0x2545_f491_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for degree in [3, 4, 5, 7, 9] {
+            let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+            let mut expected = BTreeMap::new();
+
+            for _ in 0..3000 {
+                let key = (next() % 400) as u32;
+                if next() % 3 == 0 {
+                    assert_eq!(tree.delete(key)?, expected.remove(&key));
+                } else {
+                    tree.insert(key, key * 2)?;
+                    expected.insert(key, key * 2);
+                }
+
+                if let Some(root_offset) = tree.root_node {
+                    assert_eq!(check_occupancy(&tree, root_offset, true)?, expected.len());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn random_operations_keep_tree_valid() -> anyhow::Result<()> {
+        let mut state = 0x9e37_79b9_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for degree in [3, 4, 5, 8] {
+            let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+            let mut expected = BTreeMap::new();
+
+            for _ in 0..2000 {
+                let key = (next() % 400) as u32;
+                match next() % 8 {
+                    0..=1 => assert_eq!(tree.delete(key)?, expected.remove(&key)),
+                    2 => {
+                        let end = key + (next() % 20) as u32;
+                        let removed = expected.range(key..end).count();
+                        expected.retain(|k, _| !(key..end).contains(k));
+                        assert_eq!(tree.delete_range(key, end)?, removed);
+                    },
+                    3 => {
+                        tree.update_with(key, |value| value.map(|value| value + 1))?;
+                        if let Some(value) = expected.get_mut(&key) {
+                            *value += 1;
+                        }
+                    },
+                    _ => {
+                        tree.insert(key, key * 2)?;
+                        expected.insert(key, key * 2);
+                    },
+                }
+
+                tree.validate()?;
+            }
+            assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected.into_iter().collect::<Vec<_>>());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_reports_broken_nodes() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..20 {
+            tree.insert(format!("{i:02}"), i.to_string().into_bytes())?;
+        }
+        tree.validate()?;
+
+        let leaf_offset = tree.first_leaf()?.and_then(|leaf_node| leaf_node.offset).unwrap();
+        let mut leaf_node = tree.pager.read_at(leaf_offset)?.into_leaf()?;
+        leaf_node.keys.swap(0, 1);
+        tree.pager.write_at(&Node::Leaf(leaf_node.clone()), leaf_offset)?;
+
+        let err = tree.validate().unwrap_err();
+        assert!(err.to_string().contains(&format!("offset {leaf_offset} are not sorted")), "{err}");
+
+        leaf_node.keys.swap(0, 1);
+        tree.pager.write_at(&Node::Leaf(leaf_node), leaf_offset)?;
+        tree.validate()?;
+
+        // A page reached from two parents, or twice from the same one.
+        let root_offset = tree.root_node.unwrap();
+        let Node::Internal(mut root) = tree.pager.read_at(root_offset)? else {
+            panic!("expected an internal root");
+        };
+        let shared = root.children[0];
+        root.children[1] = shared;
+        tree.pager.write_at(&Node::Internal(root), root_offset)?;
+
+        let err = tree.validate().unwrap_err();
+        assert!(err.to_string().contains(&format!("node at offset {shared} is reached twice")), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_leaf_chain_walks_the_leaves() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..50u32 {
+            tree.insert(i, i)?;
+        }
+        tree.validate_leaf_chain()?;
+
+        let offsets = tree.leaf_offsets()?;
+        assert_eq!(offsets.len(), tree.stats()?.leaf_nodes);
+        assert_eq!(offsets.first().copied(), tree.first_leaf()?.and_then(|leaf_node| leaf_node.offset));
+
+        assert_eq!(offsets.last().copied(), tree.search_path(u32::MAX)?.pop());
+
+        // A key of the first leaf past the start of the second one.
+        let mut leaf_node = tree.pager.read_at(offsets[0])?.into_leaf()?;
+        leaf_node.keys.push(1000);
+        leaf_node.values.push(1000);
+        tree.pager.write_at(&Node::Leaf(leaf_node), offsets[0])?;
+
+        let err = tree.validate_leaf_chain().unwrap_err();
+        assert!(err.to_string().contains(&format!("keys of leaf at offset {} don't ascend", offsets[1])), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn page_cache_serves_hot_pages() -> anyhow::Result<()> {
+        let path = "/tmp/page_cache_serves_hot_pages.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new_with_cache(4, STARTUP_OFFSET, file, 16)?;
+        for i in 0..200 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open_with_cache(file, 16)?;
+        for _ in 0..10 {
+            assert_eq!(tree.search("100".to_string())?, Some(b"100".to_vec()));
+        }
+
+        // Only the first lookup goes to disk, one read per level.
+        let stats = tree.cache_stats();
+        assert!(stats.misses > 0);
+        assert_eq!(stats.hits, stats.misses * 9);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open_with_cache(file, 0)?;
+        for i in 0..200 {
+            assert_eq!(tree.search(format!("{i:03}"))?, Some(i.to_string().into_bytes()));
+        }
+        assert_eq!(tree.cache_stats().hits, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn io_stats_count_page_writes() -> anyhow::Result<()> {
+        let mut tree = BPTree::new_with_cache(4, STARTUP_OFFSET, Cursor::new(Vec::new()), 0)?;
+        for i in (0..1000u32).step_by(10) {
+            tree.insert(i, i)?;
+        }
+        let stats = tree.stats()?;
+
+        let before = tree.io_stats();
+        tree.insert(505, 505)?;
+        let after = tree.io_stats();
+        assert_eq!(tree.stats()?.leaf_nodes, stats.leaf_nodes);
+
+        // Without a split, every node on the path is read and written once,
+        // and the header is rewritten.
+        assert_eq!(after.reads - before.reads, stats.height as u64);
+        assert_eq!(after.writes - before.writes, stats.height as u64 + 1);
+        assert!(after.bytes_written > before.bytes_written);
+
+        // Removing an absent key only reads the path.
+        let root_node = tree.root_node;
+        let before = tree.io_stats();
+        assert_eq!(tree.delete(507)?, None);
+        let after = tree.io_stats();
+        assert_eq!(tree.root_node, root_node);
+        assert_eq!(after.writes, before.writes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_share_the_tree() -> anyhow::Result<()> {
+        let path = "/tmp/reads_share_the_tree.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..100 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+
+        let tree = &tree;
+        let forward = tree.iter();
+        let shifted = tree.iter().skip(1);
+        for (current, next) in forward.zip(shifted) {
+            let ((key, _), (next_key, _)) = (current?, next?);
+            assert!(key < next_key);
+            assert!(tree.contains_key(key)?);
+        }
+        assert!(!tree.contains_key("100".to_string())?);
+        assert_eq!(tree.min_key()?, Some("000".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_with_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        let mut expected = BTreeMap::new();
+
+        // Count occurrences, inserting missing keys and growing the tree.
+        for i in 0..300u32 {
+            let key = i % 40;
+            tree.update_with(key, |count: Option<&u32>| Some(count.copied().unwrap_or(0) + 1))?;
+            *expected.entry(key).or_insert(0) += 1;
+        }
+        tree.validate()?;
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected.clone().into_iter().collect::<Vec<_>>());
+
+        // Returning `None` removes present keys and leaves absent ones alone.
+        for key in (0..60).step_by(2) {
+            tree.update_with(key, |_| None)?;
+            expected.remove(&key);
+            tree.validate()?;
+        }
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected.into_iter().collect::<Vec<_>>());
+
+        for key in 0..40 {
+            tree.update_with(key, |_| None)?;
+        }
+        assert!(tree.is_empty()?);
+        tree.update_with(7, |count| Some(count.map_or(100, |count| count + 1)))?;
+        assert_eq!(tree.search(7)?, Some(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn upsert_reports_new_keys() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..200u32 {
+            assert!(tree.upsert(i, i)?);
+        }
+        for i in (0..200u32).step_by(3) {
+            assert!(!tree.upsert(i, i + 1000)?);
+        }
+        tree.validate()?;
+
+        assert_eq!(tree.len()?, 200);
+        assert_eq!(tree.search(3)?, Some(1003));
+        assert_eq!(tree.search(4)?, Some(4));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_or_insert_with_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::new_with_cache(4, STARTUP_OFFSET, Cursor::new(Vec::new()), 0)?;
+        for i in (0..300u32).step_by(2) {
+            assert_eq!(tree.get_or_insert_with(i, || i * 10)?, i * 10);
+        }
+        tree.validate()?;
+
+        let before = tree.io_stats();
+        for i in (0..300u32).step_by(2) {
+            assert_eq!(tree.get_or_insert_with(i, || unreachable!())?, i * 10);
+        }
+        assert_eq!(tree.io_stats().writes, before.writes);
+
+        assert_eq!(tree.get_or_insert_with(7, || 70)?, 70);
+        assert_eq!(tree.search(7)?, Some(70));
+        assert_eq!(tree.len()?, 151);
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_if_absent_never_overwrites() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..100 {
+            assert!(tree.insert_if_absent(format!("lock-{i:02}"), b"owner-a".to_vec())?);
+        }
+        for i in (0..100).step_by(3) {
+            assert!(!tree.insert_if_absent(format!("lock-{i:02}"), b"owner-b".to_vec())?);
+        }
+        tree.validate()?;
+
+        assert_eq!(tree.len()?, 100);
+        assert_eq!(tree.search("lock-03".to_string())?, Some(b"owner-a".to_vec()));
+
+        tree.delete("lock-03".to_string())?;
+        assert!(tree.insert_if_absent("lock-03".to_string(), b"owner-b".to_vec())?);
+        assert_eq!(tree.search("lock-03".to_string())?, Some(b"owner-b".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter_rev_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.iter_rev().next().is_none());
+
+        for i in 0..300 {
+            tree.insert(format!("{i:04}"), i.to_string().as_bytes().to_vec())?;
+        }
+        for i in (0..300).step_by(7) {
+            tree.delete(format!("{i:04}"))?;
+        }
+
+        let mut forward = tree.iter().collect::<Result<Vec<_>>>()?;
+        forward.reverse();
+        assert_eq!(tree.iter_rev().collect::<Result<Vec<_>>>()?, forward);
+
+        let last_three = tree.iter_rev().take(3).map(|item| item.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(last_three, vec!["0299", "0298", "0297"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_iter_takes_range_syntax() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.range_iter(..).count(), 0);
+
+        for c in 'a'..='z' {
+            tree.insert(c.to_string(), c.to_string().into_bytes())?;
+        }
+        let keys = |iter: RangeIter<'_, Key, Value>| -> Result<String> {
+            iter.map(|pair| pair.map(|(key, _)| key)).collect()
+        };
+
+        assert_eq!(keys(tree.range_iter("a".to_string()..="m".to_string()))?, "abcdefghijklm");
+        assert_eq!(keys(tree.range_iter("c".to_string().."f".to_string()))?, "cde");
+        assert_eq!(keys(tree.range_iter("w".to_string()..))?, "wxyz");
+        assert_eq!(keys(tree.range_iter(..="c".to_string()))?, "abc");
+        assert_eq!(keys(tree.range_iter(.."c".to_string()))?, "ab");
+        assert_eq!(keys(tree.range_iter(..))?.len(), 26);
+        assert_eq!(keys(tree.range_iter("bb".to_string().."dd".to_string()))?, "cd");
+        assert_eq!(keys(tree.range_iter("m".to_string().."c".to_string()))?, "");
+
+        let start = Bound::Excluded("c".to_string());
+        let end = Bound::Included("f".to_string());
+        assert_eq!(keys(tree.range_iter((start.clone(), end.clone())))?, "def");
+        assert_eq!(tree.range_iter((start.clone(), end.clone())).collect::<Result<Vec<_>>>()?, tree.range(start, end)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn for_each_in_range_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..200u64 {
+            tree.insert(i, i * 10)?;
+        }
+
+        let mut sum = 0;
+        tree.for_each_in_range(Bound::Included(50), Bound::Excluded(150), |_, value| {
+            sum += value;
+            Ok(())
+        })?;
+        assert_eq!(sum, (50..150).map(|i| i * 10).sum::<u64>());
+
+        let mut visited = Vec::new();
+        let result = tree.for_each_in_range(Bound::Unbounded, Bound::Unbounded, |key, _| {
+            if *key == 5 {
+                anyhow::bail!("stop at {key}");
+            }
+            visited.push(*key);
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "stop at 5");
+        assert_eq!(visited, vec![0, 1, 2, 3, 4]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stats_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.stats()?, TreeStats::default());
+
+        for i in 0..3u32 {
+            tree.insert(i, i)?;
+        }
+        let stats = tree.stats()?;
+        assert_eq!((stats.height, stats.internal_nodes, stats.leaf_nodes, stats.total_keys), (1, 0, 1, 3));
+        assert_eq!(stats.avg_leaf_fill, 1.0);
+
+        for i in 3..100u32 {
+            tree.insert(i, i)?;
+        }
+        let stats = tree.stats()?;
+        assert_eq!(stats.total_keys, 100);
+        assert!(stats.height > 2);
+        assert!(stats.avg_leaf_fill < 0.8, "{stats:?}");
+
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/stats_works.ldb")?;
+        let tree = BPTree::bulk_load(4, file, (0..99u32).map(|i| (i, i)))?;
+        let stats = tree.stats()?;
+        assert_eq!((stats.height, stats.leaf_nodes, stats.total_keys), (4, 33, 99));
+        assert_eq!(stats.avg_leaf_fill, 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_bias_packs_ascending_inserts() -> anyhow::Result<()> {
+        for degree in [3, 4, 5, 8] {
+            let mut middle = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+            let mut append = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?.with_split_bias(SplitBias::Append);
+            for i in 0..1000u32 {
+                middle.insert(i, i)?;
+                append.update_with(i, |_| Some(i))?;
+            }
+            append.validate()?;
+            assert_eq!(append.len()?, 1000);
+
+            let (middle, append) = (middle.stats()?, append.stats()?);
+            assert!(middle.avg_leaf_fill < 0.8, "{middle:?}");
+            assert!(append.avg_leaf_fill > 0.9, "{append:?}");
+            assert!(append.leaf_nodes < middle.leaf_nodes);
+        }
+
+        // Removals rebalance the short nodes on the right edge like any
+        // other.
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?.with_split_bias(SplitBias::Append);
+        for i in 0..300u32 {
+            tree.insert(i, i)?;
+        }
+        for i in (0..300u32).rev().step_by(2) {
+            tree.delete(i)?;
+            tree.validate()?;
+        }
+        assert_eq!(tree.len()?, 150);
+
+        // Out of order keys still split at the midpoint and keep the tree
+        // valid.
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?.with_split_bias(SplitBias::Append);
+        for i in 0..500u32 {
+            tree.insert((i * 7919) % 500, i)?;
+        }
+        tree.validate()?;
+        assert_eq!(tree.len()?, 500);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_right_edge_survives_a_reopen() -> anyhow::Result<()> {
+        let path = "/tmp/short_right_edge_survives_a_reopen.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(8, STARTUP_OFFSET, file)?.with_split_bias(SplitBias::Append);
+        for i in 0..302u32 {
+            tree.insert(i, i)?;
+        }
+        drop(tree);
+
+        // Reopened with the default bias, the short nodes appending left on
+        // the right edge are still accepted until a gc fills them.
+        let mut tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        tree.validate()?;
+        tree.insert(1000, 1000)?;
+        tree.validate()?;
+        assert!(tree.gc()?);
+        drop(tree);
+
+        let tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        tree.validate()?;
+        assert!(!tree.short_right_edge);
+        assert_eq!(tree.len()?, 303);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_works() -> anyhow::Result<()> {
+        let path = "/tmp/compact_works.ldb";
+        let compacted_path = "/tmp/compact_works_compacted.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..500 {
+            tree.insert(format!("{i:03}"), i.to_string().into_bytes())?;
+        }
+        for i in (0..500).filter(|i| i % 5 != 0) {
+            tree.delete(format!("{i:03}"))?;
+        }
+
+        let out = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(compacted_path)?;
+        let (compacted, stats) = tree.compact(out)?;
+        assert_eq!(stats, CompactStats { pages_before: tree.file_size_pages(), pages_after: compacted.file_size_pages() });
+        assert!(stats.pages_after * 2 < stats.pages_before, "{stats:?}");
+        compacted.validate()?;
+        assert_eq!(
+            compacted.iter().collect::<Result<Vec<_>>>()?,
+            tree.iter().collect::<Result<Vec<_>>>()?
+        );
+        drop(compacted);
+
+        let file = OpenOptions::new().read(true).write(true).open(compacted_path)?;
+        let compacted = BPTree::<Key, Value>::open(file)?;
+        assert_eq!(compacted.len()?, 100);
+        assert_eq!(compacted.search("495".to_string())?, Some(b"495".to_vec()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_keeps_the_configuration() -> anyhow::Result<()> {
+        let path = "/tmp/compact_keeps_the_configuration.ldb";
+        let compression = if cfg!(feature = "lz4") { Compression::Lz4 } else { Compression::None };
+        let options = TreeOptions {
+            page_size: 16384,
+            compression,
+            value_storage: ValueStorage::OutOfLine,
+            split_bias: SplitBias::Append,
+            delete_mode: DeleteMode::Deferred,
+            ..TreeOptions::new(8)
+        };
+        let mut tree = BPTree::with_options(Cursor::new(Vec::new()), options.clone())?;
+        // Keys over the limit of the default page size, and values larger
+        // than a page.
+        let key = |i: u32| format!("{i:04}{}", "k".repeat(1000));
+        for i in 0..200u32 {
+            tree.insert(key(i), vec![i as u8; 10_000])?;
+        }
+        for i in (0..200u32).step_by(2) {
+            tree.delete(key(i))?;
+        }
+
+        let out = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let (compacted, stats) = tree.compact(out)?;
+        assert!(stats.pages_after < stats.pages_before, "{stats:?}");
+        assert_eq!(compacted.options(), options);
+        compacted.validate()?;
+        assert_eq!(compacted.iter().collect::<Result<Vec<_>>>()?, tree.iter().collect::<Result<Vec<_>>>()?);
+        drop(compacted);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let compacted = BPTree::<Key, Value>::open(file)?;
+        let reopened = compacted.options();
+        assert_eq!((reopened.page_size, reopened.compression, reopened.value_storage), (16384, compression, ValueStorage::OutOfLine));
+        assert_eq!(compacted.search(key(101))?, Some(vec![101; 10_000]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_off_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, Cursor::new(Vec::new()))?;
+        for i in 0..300u32 {
+            tree.insert(i, i * 10)?;
+        }
+
+        let upper = tree.split_off(120, Cursor::new(Vec::new()))?;
+        tree.validate()?;
+        upper.validate()?;
+        assert_eq!(tree.keys().collect::<Result<Vec<_>>>()?, (0..120).collect::<Vec<_>>());
+        assert_eq!(upper.keys().collect::<Result<Vec<_>>>()?, (120..300).collect::<Vec<_>>());
+        assert_eq!(upper.search(299)?, Some(2990));
+        assert_eq!(tree.search(120)?, None);
+
+        let empty = tree.split_off(1000, Cursor::new(Vec::new()))?;
+        assert!(empty.is_empty()?);
+        assert_eq!(tree.len()?, 120);
+
+        for degree in [3, 4, 7] {
+            for at in [0u32, 1, 57, 150, 298, 299, 300] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+                for i in 0..300u32 {
+                    tree.insert(i, i)?;
+                }
+                let upper = tree.split_off(at, Cursor::new(Vec::new()))?;
+                tree.validate()?;
+                upper.validate()?;
+                assert_eq!(tree.keys().collect::<Result<Vec<_>>>()?, (0..at).collect::<Vec<_>>(), "degree {degree}, at {at}");
+                assert_eq!(upper.len()?, 300 - at as usize);
+            }
+        }
+
+        // The new tree is laid out like the one it was split from.
+        let options = TreeOptions {
+            page_size: 16384,
+            value_storage: ValueStorage::OutOfLine,
+            ..TreeOptions::new(8)
+        };
+        let mut tree = BPTree::with_options(Cursor::new(Vec::new()), options.clone())?;
+        let key = |i: u32| format!("{i:04}{}", "k".repeat(1000));
+        for i in 0..100u32 {
+            tree.insert(key(i), vec![i as u8; 10_000])?;
+        }
+        let upper = tree.split_off(key(40), Cursor::new(Vec::new()))?;
+        assert_eq!(upper.options(), options);
+        upper.validate()?;
+        assert_eq!(upper.len()?, 60);
+        assert_eq!(upper.search(key(99))?, Some(vec![99; 10_000]));
+        assert_eq!(tree.len()?, 40);
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..100u32 {
+            tree.insert(i, i)?;
+        }
+
+        let mut upper = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 100..200u32 {
+            upper.insert(i, i)?;
+        }
+        tree.append(&mut upper)?;
+        assert!(upper.is_empty()?);
+
+        let mut overlapping = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in (50..250u32).step_by(5) {
+            overlapping.insert(i, i * 10)?;
+        }
+        tree.append(&mut overlapping)?;
+
+        tree.validate()?;
+        assert_eq!(tree.len()?, 210);
+        assert_eq!(tree.search(49)?, Some(49));
+        assert_eq!(tree.search(55)?, Some(550));
+        assert_eq!(tree.search(56)?, Some(56));
+        assert_eq!(tree.search(245)?, Some(2450));
+
+        Ok(())
+    }
+
+    #[test]
+    fn append_from_a_pinned_tree_changes_neither() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..50u32 {
+            tree.insert(i, i)?;
+        }
+        let mut pinned = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 25..100u32 {
+            pinned.insert(i, i * 10)?;
+        }
+        let snapshot = pinned.snapshot()?;
+
+        let err = tree.append(&mut pinned).unwrap_err();
+        assert!(err.to_string().contains("snapshots are still pinned"), "{err}");
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, (0..50u32).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(pinned.iter().collect::<Result<Vec<_>>>()?, (25..100u32).map(|i| (i, i * 10)).collect::<Vec<_>>());
+
+        pinned.release(snapshot)?;
+        tree.append(&mut pinned)?;
+        assert_eq!(tree.len()?, 100);
+        assert!(pinned.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn dump_page_reads_any_page() -> anyhow::Result<()> {
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, Cursor::new(Vec::new()))?;
+        for i in 0..50u32 {
+            tree.insert(i, i * 10)?;
+        }
+
+        for offset in tree.leaf_offsets()? {
+            let reads = tree.io_stats().reads;
+            let leaf_node = tree.dump_page(offset)?.into_leaf()?;
+            assert_eq!(tree.io_stats().reads, reads + 1);
+            assert_eq!(leaf_node.offset, Some(offset));
+            assert!(leaf_node.keys.iter().zip(&leaf_node.values).all(|(key, value)| *value == key * 10));
+
+            let bytes = tree.dump_page_bytes(offset)?;
+            assert_eq!(bytes.len(), DEFAULT_PAGE_SIZE);
+            assert!(bytes.iter().any(|byte| *byte != 0x00));
+        }
+        let root_offset = tree.root_node.unwrap();
+        assert!(matches!(tree.dump_page(root_offset)?, Node::Internal(_)));
+
+        let err = tree.dump_page(root_offset + 1).unwrap_err();
+        assert!(err.to_string().contains("is not the start of a page"), "{err}");
+        assert!(tree.dump_page_bytes(0).is_err());
+        let end = tree.file_size_pages() * DEFAULT_PAGE_SIZE;
+        let err = tree.dump_page_bytes(end).unwrap_err();
+        assert!(err.to_string().contains("is beyond the end of the file"), "{err}");
+
+        let tree = BPTree::<u32, u32>::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(tree.dump_page(STARTUP_OFFSET).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_summary_describes_each_level() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        let mut out = Vec::new();
+        tree.debug_summary(&mut out)?;
+        assert_eq!(String::from_utf8(out)?, "total keys: 0\n");
+
+        for i in 0..1000u32 {
+            tree.insert(i, i)?;
+        }
+        let mut out = Vec::new();
+        tree.debug_summary(&mut out)?;
+        let summary = String::from_utf8(out)?;
+
+        let stats = tree.stats()?;
+        let lines = summary.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), stats.height + 1, "{summary}");
+        assert!(lines[0].starts_with("level 0: 1 nodes"), "{summary}");
+        let leaves = format!(
+            "level {}: {} nodes, 1000 keys, min = Some(0), max = Some(999)",
+            stats.height - 1,
+            stats.leaf_nodes
+        );
+        assert_eq!(lines[stats.height - 1], leaves);
+        assert_eq!(lines[stats.height], "total keys: 1000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn debug_print_writes_to_any_writer() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..4u32 {
+            tree.insert(i, i * 10)?;
+        }
+
+        let mut out = Vec::new();
+        tree.debug_print(&mut out)?;
+        let dump = String::from_utf8(out)?;
+
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5, "{dump}");
+        assert!(lines[0].starts_with("InternalNode:") && lines[0].contains("len = 1, keys = [1]"), "{dump}");
+        assert_eq!(lines[1], "  Child 0:");
+        assert!(lines[2].starts_with("  LeafNode:") && lines[2].contains("len = 2, keys = [0, 1], values = [0, 10]"), "{dump}");
+        assert!(lines[4].contains("keys = [2, 3], values = [20, 30]"), "{dump}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn to_dot_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.to_dot()?, "digraph bptree {\n  node [shape=record];\n}\n");
+
+        for key in ["a", "b", "c", "d|e"] {
+            tree.insert(key.to_string(), key.as_bytes().to_vec())?;
+        }
+        let dot = tree.to_dot()?;
+
+        let root_offset = tree.root_node.unwrap();
+        let Node::Internal(root) = tree.pager.read_at(root_offset)? else {
+            panic!("expected an internal root");
+        };
+        let (left, right) = (root.children[0], root.children[1]);
+
+        assert!(dot.contains(&format!("n{root_offset} [label=\"internal {root_offset} | \\\"b\\\"\"];")), "{dot}");
+        assert!(dot.contains(&format!("n{right} [label=\"leaf {right} | \\\"c\\\" | \\\"d\\|e\\\"\"];")), "{dot}");
+        assert!(dot.contains(&format!("n{root_offset} -> n{left} [label=\"<= \\\"b\\\"\"];")), "{dot}");
+        assert!(dot.contains(&format!("n{root_offset} -> n{right} [label=\"> \\\"b\\\"\"];")), "{dot}");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "direct-io")]
+    #[test]
+    fn direct_io_writes_whole_aligned_pages() -> anyhow::Result<()> {
+        let path = "/tmp/direct_io_writes_whole_aligned_pages.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let options = TreeOptions {
+            page_size: 512,
+            direct_io: true,
+            ..TreeOptions::new(8)
+        };
+        assert!(BPTree::<u32, u32>::with_options(file.try_clone()?, options).is_err());
+
+        let options = TreeOptions {
+            direct_io: true,
+            ..TreeOptions::new(8)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        for i in 0..500u32 {
+            tree.insert(i, i)?;
+        }
+        tree.flush()?;
+        assert!(tree.leaf_offsets()?.iter().all(|offset| offset % DEFAULT_PAGE_SIZE == 0));
+        drop(tree);
+        assert_eq!(std::fs::metadata(path)?.len() % DEFAULT_PAGE_SIZE as u64, 0);
+
+        // The layout is stored in the header, so a reopened tree keeps it.
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTree::<u32, u32>::open(file)?;
+        for i in 500..1000u32 {
+            tree.insert(i, i)?;
+        }
+        tree.validate()?;
+        assert_eq!(tree.search(250)?, Some(250));
+        assert!(tree.leaf_offsets()?.iter().all(|offset| offset % DEFAULT_PAGE_SIZE == 0));
+        drop(tree);
+        assert_eq!(std::fs::metadata(path)?.len() % DEFAULT_PAGE_SIZE as u64, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn files_hold_whole_pages() -> anyhow::Result<()> {
+        for (page_size, value_storage) in [(1024, ValueStorage::Inline), (4096, ValueStorage::Inline), (1024, ValueStorage::OutOfLine)] {
+            let path = format!("/tmp/files_hold_whole_pages_{page_size}.ldb");
+            let file = OpenOptions::new()
+                .create(true)
+                .read(true)
+                .write(true)
+                .truncate(true)
+                .open(&path)?;
+
+            let options = TreeOptions {
+                page_size,
+                value_storage,
+                ..TreeOptions::new(8)
+            };
+            let mut tree = BPTree::with_options(file, options)?;
+            for i in 0..300u32 {
+                tree.insert(i, vec![i as u8; 100])?;
+            }
+            tree.flush()?;
+
+            let len = std::fs::metadata(&path)?.len() as usize;
+            assert_eq!((len - HEADER_SIZE) % page_size, 0, "{len}");
+            assert_eq!(HEADER_SIZE + (tree.file_size_pages() - HEADER_SIZE / page_size) * page_size, len);
+            assert_eq!(tree.search(150)?, Some(vec![150; 100]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn page_size_is_configurable() -> anyhow::Result<()> {
+        let path = "/tmp/page_size_is_configurable.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let options = TreeOptions {
+            page_size: 16384,
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        let value = vec![7u8; 5000];
+        for i in 0..20 {
+            tree.insert(format!("{i:02}"), value.clone())?;
+        }
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTree::<Key, Value>::open(file)?;
+        tree.insert("20".to_string(), value.clone())?;
+        for i in 0..21 {
+            assert_eq!(tree.search(format!("{i:02}"))?, Some(value.clone()));
+        }
+        tree.validate()?;
+
+        let file = OpenOptions::new().read(true).write(true).truncate(true).open(path)?;
+        let mut tree = BPTree::<Key, Value>::new(4, STARTUP_OFFSET, file)?;
+        let err = tree.insert("00".to_string(), value).unwrap_err();
+        assert!(matches!(err, BPTreeError::ValueTooLarge { limit: 1365, .. }), "{err}");
+
+        let file = OpenOptions::new().read(true).write(true).truncate(true).open(path)?;
+        let options = TreeOptions {
+            page_size: 64,
+            ..TreeOptions::new(4)
+        };
+        assert!(BPTree::<Key, Value>::with_options(file, options).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encoding_is_kept_across_reopen() -> anyhow::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let path = "/tmp/encoding_is_kept_across_reopen.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let options = TreeOptions {
+            encoding: Encoding::fixed_big_endian(),
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        for i in 0..50u32 {
+            tree.insert(i, i.to_be_bytes().to_vec())?;
+        }
+        drop(tree);
+
+        // A fixed-width big-endian leaf stores its first key as four bytes.
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<u32, Value>::open(file.try_clone()?)?;
+        let leaf = tree.leaf_offsets()?[0];
+        let mut page = vec![0x00; DEFAULT_PAGE_SIZE];
+        file.seek(SeekFrom::Start(leaf as u64))?;
+        file.read_exact(&mut page)?;
+        assert!(page.windows(4).any(|bytes| bytes == 0u32.to_be_bytes()));
+
+        let mut tree = tree;
+        tree.insert(50, 50u32.to_be_bytes().to_vec())?;
+        tree.validate()?;
+        for i in 0..51u32 {
+            assert_eq!(tree.search(i)?, Some(i.to_be_bytes().to_vec()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn out_of_line_values_round_trip() -> anyhow::Result<()> {
+        let path = "/tmp/out_of_line_values_round_trip.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        // Every value is larger than a page, so none of them would fit
+        // inline, while a full leaf of keys and references still does.
+        let value = |i: usize, round: usize| format!("{round}:{i} ").repeat(1000).into_bytes();
+        let options = TreeOptions {
+            value_storage: ValueStorage::OutOfLine,
+            ..TreeOptions::new(32)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        for i in 0..200 {
+            tree.insert(format!("{i:03}"), value(i, 0))?;
+        }
+        tree.delete("100".to_string())?;
+        assert!(tree.stats()?.avg_leaf_fill > 0.5);
+
+        // Rewriting every value frees the value pages it replaces, so later
+        // rounds reuse them instead of growing the file.
+        for round in 1..4 {
+            for i in (0..200).filter(|&i| i != 100) {
+                tree.insert(format!("{i:03}"), value(i, round))?;
+            }
+        }
+        let size_pages = tree.file_size_pages();
+        for i in (0..200).filter(|&i| i != 100) {
+            tree.insert(format!("{i:03}"), value(i, 4))?;
+        }
+        assert!(tree.file_size_pages() <= size_pages * 11 / 10);
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open(file)?;
+        tree.validate()?;
+        assert_eq!(tree.len()?, 199);
+        assert_eq!(tree.search("100".to_string())?, None);
+        for i in (0..200).filter(|&i| i != 100) {
+            assert_eq!(tree.search(format!("{i:03}"))?, Some(value(i, 4)));
+        }
+
+        Ok(())
+    }
+
+    /// In-memory storage that counts its syncs.
+    struct CountSyncs(Cursor<Vec<u8>>, Arc<std::sync::atomic::AtomicUsize>);
+
+    impl std::io::Read for CountSyncs {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for CountSyncs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for CountSyncs {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.seek(pos)
+        }
+    }
+
+    impl ReadWriteSeek for CountSyncs {
+        fn sync(&mut self) -> std::io::Result<()> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_policy_syncs_on_its_own() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+        let syncs_after_ten_inserts = |flush_policy: FlushPolicy| -> anyhow::Result<usize> {
+            let syncs = Arc::new(AtomicUsize::new(0));
+            let options = TreeOptions {
+                flush_policy,
+                ..TreeOptions::new(4)
+            };
+            let mut tree = BPTree::with_options(CountSyncs(Cursor::new(Vec::new()), Arc::clone(&syncs)), options)?;
+            let created = syncs.load(AtomicOrdering::SeqCst);
+            for i in 0..10 {
+                tree.insert(format!("{i:02}"), b"value".to_vec())?;
+            }
+            let synced = syncs.load(AtomicOrdering::SeqCst) - created;
+            drop(tree);
+            Ok(synced)
+        };
+
+        // A header the policy syncs is written between two syncs, one for
+        // the pages it points at and one for itself.
+        assert_eq!(syncs_after_ten_inserts(FlushPolicy::Never)?, 0);
+        assert_eq!(syncs_after_ten_inserts(FlushPolicy::EveryWrite)?, 20);
+        assert_eq!(syncs_after_ten_inserts(FlushPolicy::EveryN(3))?, 6);
+        assert_eq!(syncs_after_ten_inserts(FlushPolicy::EveryN(0))?, 20);
+
+        Ok(())
+    }
+
+    #[test]
+    fn every_n_syncs_once_per_n_writes() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+        for n in [1, 4, 25] {
+            let syncs = Arc::new(AtomicUsize::new(0));
+            let options = TreeOptions {
+                flush_policy: FlushPolicy::EveryN(n),
+                ..TreeOptions::new(4)
+            };
+            let mut tree = BPTree::with_options(CountSyncs(Cursor::new(Vec::new()), Arc::clone(&syncs)), options)?;
+            let created = syncs.load(AtomicOrdering::SeqCst);
+            for i in 0..100u32 {
+                tree.insert(i, i)?;
+                // Each sync of the policy is the pair around the header of
+                // its nth write.
+                let written = i as usize + 1;
+                assert_eq!(syncs.load(AtomicOrdering::SeqCst) - created, 2 * (written / n), "EveryN({n}) after {written} writes");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn never_policy_syncs_only_on_flush() -> anyhow::Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let mut tree = BPTree::with_options(CountSyncs(Cursor::new(Vec::new()), Arc::clone(&syncs)), TreeOptions::new(4))?;
+        for i in 0..1000u32 {
+            tree.insert(i, i)?;
+        }
+        for i in 0..100u32 {
+            tree.delete(i)?;
+        }
+        assert_eq!(syncs.load(AtomicOrdering::SeqCst), 0);
+
+        // The header of the last delete is already written, so one sync
+        // makes every write durable.
+        tree.flush()?;
+        assert_eq!(syncs.load(AtomicOrdering::SeqCst), 1);
+        drop(tree);
+        assert_eq!(syncs.load(AtomicOrdering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_pool_collapses_writes() -> anyhow::Result<()> {
+        let writes_for_batch = |buffer_pages: usize| -> anyhow::Result<u64> {
+            let options = TreeOptions {
+                cache_pages: 0,
+                buffer_pages,
+                ..TreeOptions::new(4)
+            };
+            let mut tree = BPTree::with_options(Cursor::new(Vec::new()), options)?;
+            let writes = tree.io_stats().writes;
+            tree.transaction(|txn| {
+                for i in 0..500u32 {
+                    txn.insert(i * 7919 % 500, i)?;
+                }
+                Ok(())
+            })?;
+            let written = tree.io_stats().writes - writes;
+
+            tree.validate()?;
+            assert_eq!(tree.len()?, 500);
+            assert_eq!(tree.search(7919 % 500)?, Some(1));
+            Ok(written)
+        };
+
+        // Unbuffered, every copy on the path of every insert is written.
+        // Buffered, only the pages of the final tree are, plus the header.
+        let unbuffered = writes_for_batch(0)?;
+        let buffered = writes_for_batch(1024)?;
+        let pressed = writes_for_batch(8)?;
+        assert!(buffered * 4 < unbuffered, "{buffered} writes buffered, {unbuffered} unbuffered");
+        assert!(buffered < pressed && pressed < unbuffered, "{pressed} writes with 8 buffered pages");
+
+        let path = "/tmp/buffer_pool_collapses_writes.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let options = TreeOptions {
+            buffer_pages: 16,
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        tree.insert_many((0..300u32).map(|i| (i, i * 2)))?;
+        // A failed operation rolls back whatever it left in the buffer.
+        let result = tree.transaction(|txn| {
+            for i in 300..400u32 {
+                txn.insert(i, i)?;
+            }
+            anyhow::bail!("abort")
+        });
+        assert!(result.is_err());
+        tree.delete(10)?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<u32, u32>::open(file)?;
+        tree.validate()?;
+        assert_eq!(tree.len()?, 299);
+        assert_eq!(tree.search(299)?, Some(598));
+        assert_eq!(tree.search(300)?, None);
+
+        // Leaves read back from the buffer still find their values out of
+        // line.
+        let options = TreeOptions {
+            buffer_pages: 16,
+            value_storage: ValueStorage::OutOfLine,
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(Cursor::new(Vec::new()), options)?;
+        tree.transaction(|txn| {
+            for i in 0..200u32 {
+                txn.insert(format!("{i:03}"), vec![i as u8; 100 + i as usize])?;
+            }
+            Ok(())
+        })?;
+        tree.validate()?;
+        assert_eq!(tree.search("123".to_string())?, Some(vec![123; 223]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_separators_still_route_lookups() -> anyhow::Result<()> {
+        let separator = |left: &str, right: &str| ShortSeparators.separator(&left.to_string(), &right.to_string());
+        assert_eq!(separator("application", "apply"), "applj");
+        assert_eq!(separator("apple", "apricot"), "apq");
+        assert_eq!(separator("az", "bc"), "b");
+        // Nothing shorter than the left key fits.
+        assert_eq!(separator("abc", "abcd"), "abc");
+        assert_eq!(separator("az", "b"), "az");
+        assert_eq!(separator("a\u{d7ff}", "a\u{e001}"), "a\u{e000}");
+
+        let key = |i: u32| format!("/var/lib/service/partition-{:02}/segment-{i:05}.log", i % 7);
+        let mut tree = BPTree::with_pager(6, Box::new(MemoryPager::new()))?.with_comparator(ShortSeparators);
+        for i in (0..600).step_by(2) {
+            tree.insert(key(i), i.to_string().into_bytes())?;
+        }
+        tree.insert_many((1..600).step_by(2).map(|i| (key(i), i.to_string().into_bytes())))?;
+        tree.validate()?;
+
+        // Every separator split leaves left above them is shorter than the
+        // keys. Leaves that borrow from a sibling take its full last key.
+        let mut level = vec![tree.root_node.unwrap()];
+        let mut separators = 0;
+        while let Node::Internal(_) = tree.pager.read_at(level[0])? {
+            let mut next_level = Vec::new();
+            for offset in level {
+                let Node::Internal(internal_node) = tree.pager.read_at(offset)? else {
+                    panic!("expected an internal node at {offset}");
+                };
+                assert!(internal_node.keys.iter().all(|separator| separator.len() < key(0).len()), "{:?}", internal_node.keys);
+                separators += internal_node.keys.len();
+                next_level.extend(internal_node.children);
+            }
+            level = next_level;
+        }
+        assert!(separators > 50);
+
+        for i in (0..600).step_by(3) {
+            tree.delete(key(i))?;
+        }
+        tree.validate()?;
+
+        for i in 0..600 {
+            let expected = (i % 3 != 0).then(|| i.to_string().into_bytes());
+            assert_eq!(tree.search(key(i))?, expected, "{}", key(i));
+        }
+        assert_eq!(tree.search("/var/lib/service/partition-03/segment-0001".to_string())?, None);
+        assert_eq!(tree.iter().count(), 400);
+
+        Ok(())
+    }
+
+    #[test]
+    fn export_and_import_round_trip() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in (0..300).rev() {
+            tree.insert(format!("{i:03}"), format!("value {i}").into_bytes())?;
+        }
+        tree.delete("150".to_string())?;
+
+        let mut exported = Vec::new();
+        assert_eq!(tree.export(&mut exported)?, 299);
+
+        let imported = BPTree::<Key, Value>::import(8, Cursor::new(Vec::new()), exported.as_slice())?;
+        imported.validate()?;
+        assert_eq!(
+            imported.iter().collect::<Result<Vec<_>>>()?,
+            tree.iter().collect::<Result<Vec<_>>>()?
+        );
+
+        let empty = BPTree::<Key, Value>::import(4, Cursor::new(Vec::new()), &[][..])?;
+        assert!(empty.is_empty()?);
+
+        // A record cut short is an error, not a shorter tree.
+        let truncated = &exported[..exported.len() - 3];
+        assert!(BPTree::<Key, Value>::import(4, Cursor::new(Vec::new()), truncated).is_err());
+        // A length prefix that claims far more than follows fails on the
+        // missing bytes, without allocating what it claims.
+        let oversized = [&u32::MAX.to_le_bytes()[..], b"key"].concat();
+        let err = BPTree::<Key, Value>::import(4, Cursor::new(Vec::new()), oversized.as_slice()).err().unwrap();
+        assert!(err.to_string().contains("record of 4294967295 bytes ends after 3"), "{err}");
+
+        // Records are loaded as they are read, so an out of order key stops
+        // the import before the rest of the reader is touched.
+        let twice = [exported.as_slice(), exported.as_slice()].concat();
+        let mut reader = twice.as_slice();
+        assert!(BPTree::<Key, Value>::import(4, Cursor::new(Vec::new()), &mut reader).is_err());
+        assert!(reader.len() > exported.len() / 2, "{} bytes left", reader.len());
+
+        Ok(())
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn compression_without_its_feature_is_refused() -> anyhow::Result<()> {
+        let options = TreeOptions {
+            compression: Compression::Lz4,
+            ..TreeOptions::new(4)
+        };
+        let err = BPTree::<Key, Value>::with_options(Cursor::new(Vec::new()), options).err().unwrap();
+        assert!(err.to_string().contains("`lz4` feature"), "{err}");
+
+        Ok(())
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn compressed_pages_round_trip() -> anyhow::Result<()> {
+        let path = "/tmp/compressed_pages_round_trip.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        // Three of these values overflow an uncompressed 4096 byte page.
+        let value = |i: usize| format!("value {i} ").repeat(300).into_bytes();
+        let options = TreeOptions {
+            compression: Compression::Lz4,
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(file, options)?;
+        for i in 0..50 {
+            tree.insert(format!("{i:02}"), value(i))?;
+        }
+        tree.delete("10".to_string())?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTree::<Key, Value>::open(file)?;
+        tree.insert("50".to_string(), value(50))?;
+        tree.validate()?;
+        assert_eq!(tree.len()?, 50);
+        assert_eq!(tree.search("10".to_string())?, None);
+        for i in (0..51).filter(|&i| i != 10) {
+            assert_eq!(tree.search(format!("{i:02}"))?, Some(value(i)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_node_is_rejected() -> anyhow::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open("/tmp/oversized_node_is_rejected.ldb")?;
+
+        // Lifting the value limit lets the oversized node reach the pager.
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?.with_max_value_len(usize::MAX);
+        for i in 0..20 {
+            tree.insert(format!("{i:02}"), vec![1u8; 100])?;
+        }
+
+        let err = tree.insert("10a".to_string(), vec![2u8; 5000]).unwrap_err();
+        assert!(matches!(err, BPTreeError::PageOverflow { page_size: 4096, .. }), "{err}");
+        assert!(err.to_string().contains("exceeds page size 4096"), "{err}");
+        let err = tree.update_with("05".to_string(), |_| Some(vec![2u8; 5000])).unwrap_err();
+        assert!(err.to_string().contains("exceeds page size 4096"), "{err}");
+
+        // The failed writes left the committed tree in place.
+        assert_eq!(tree.len()?, 20);
+        assert_eq!(tree.search("05".to_string())?, Some(vec![1u8; 100]));
+        assert_eq!(tree.search("10a".to_string())?, None);
+
+        for i in 20..40 {
+            tree.insert(format!("{i:02}"), vec![1u8; 100])?;
+        }
+        for i in 0..40 {
+            assert_eq!(tree.search(format!("{i:02}"))?, Some(vec![1u8; 100]));
+        }
+        tree.validate()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn entry_limits_are_enforced() -> anyhow::Result<()> {
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, Cursor::new(Vec::new()))?;
+        tree.insert("key".to_string(), b"value".to_vec())?;
+        let writes = tree.io_stats().writes;
+
+        // The defaults for 4096 byte pages: 512 byte keys, 1365 byte values,
+        // each counting the length prefix of its encoding.
+        let err = tree.insert("k".repeat(600), b"value".to_vec()).unwrap_err();
+        assert!(matches!(err, BPTreeError::KeyTooLarge { size: 603, limit: 512 }), "{err}");
+        assert_eq!(err.to_string(), "key encoding 603 bytes exceeds the limit of 512");
+        let err = tree.insert("big".to_string(), vec![0u8; 1400]).unwrap_err();
+        assert!(matches!(err, BPTreeError::ValueTooLarge { size: 1403, limit: 1365 }), "{err}");
+        assert!(tree.insert_many([("a".to_string(), vec![0u8; 1400])]).is_err());
+        assert!(tree.update_with("key".to_string(), |_| Some(vec![0u8; 1400])).is_err());
+        assert!(tree.insert_if_absent("new".to_string(), vec![0u8; 1400]).is_err());
+        assert!(tree.transaction(|txn| Ok(txn.insert("txn".to_string(), vec![0u8; 1400])?)).is_err());
+
+        // Nothing was written for the rejected entries.
+        assert_eq!(tree.io_stats().writes, writes);
+        assert_eq!(tree.len()?, 1);
+        assert_eq!(tree.search("key".to_string())?, Some(b"value".to_vec()));
+
+        let mut tree = tree.with_max_key_len(8).with_max_value_len(2000);
+        tree.insert("big".to_string(), vec![0u8; 1400])?;
+        assert!(tree.insert("too long key".to_string(), Vec::new()).is_err());
+        // Removing a key never checks it against the limits.
+        tree.update_with("big".to_string(), |_| None)?;
+
+        let mut memory = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        memory.insert("k".repeat(600), vec![0u8; 10000])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_many_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.get_many(&[1, 2])?, vec![None, None]);
+
+        for i in (0..200u64).step_by(2) {
+            tree.insert(i, i * 10)?;
+        }
+
+        let keys = [150, 3, 0, 198, 150, 500, 64, 1];
+        let expected = keys.iter().map(|key| (key % 2 == 0 && *key < 200).then_some(key * 10)).collect::<Vec<_>>();
+        assert_eq!(tree.get_many(&keys)?, expected);
+        assert!(tree.get_many(&[])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_range_works() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..300u64 {
+            tree.insert(i, i)?;
+        }
+
+        assert_eq!(tree.delete_range(100, 200)?, 100);
+        tree.validate()?;
+        assert_eq!(tree.len()?, 200);
+        assert_eq!(tree.search(99)?, Some(99));
+        assert_eq!(tree.search(100)?, None);
+        assert_eq!(tree.search(199)?, None);
+        assert_eq!(tree.search(200)?, Some(200));
+
+        assert_eq!(tree.delete_range(100, 200)?, 0);
+        assert_eq!(tree.delete_range(200, 100)?, 0);
+        assert_eq!(tree.delete_range(250, 1000)?, 50);
+        assert_eq!(tree.delete_range(0, 250)?, 150);
+        tree.validate()?;
+        assert!(tree.is_empty()?);
+
+        for degree in [3, 4, 5, 8] {
+            for (start, end) in [(1u64, 2), (0, 300), (1, 299), (37, 263), (0, 150), (150, 300), (120, 128)] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+                for i in 0..300u64 {
+                    tree.insert(i, i)?;
+                }
+                assert_eq!(tree.delete_range(start, end)?, (end - start) as usize);
+                tree.validate()?;
+                tree.validate_leaf_chain()?;
+                let expected = (0..300u64).filter(|i| !(start..end).contains(i)).map(|i| (i, i)).collect::<Vec<_>>();
+                assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected);
+
+                for i in start..end {
+                    tree.insert(i, i)?;
+                }
+                tree.validate()?;
+                assert_eq!(tree.len()?, 300);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn deferred_deletes_rebalance_on_gc() -> anyhow::Result<()> {
+        for degree in [3, 7, 16] {
+            for step in [1u64, 3, 7] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?.with_delete_mode(DeleteMode::Deferred);
+                for i in 0..500u64 {
+                    tree.insert(i, i)?;
+                }
+
+                let deleted = (0..500u64).filter(|i| i % step == 0 || i % 5 == 1).collect::<Vec<_>>();
+                for &i in &deleted {
+                    assert_eq!(tree.delete(i)?, Some(i));
+                }
+                assert_eq!(tree.delete(deleted[0])?, None);
+                tree.validate()?;
+                tree.validate_leaf_chain()?;
+                assert_eq!(tree.search(deleted[1])?, None);
+                assert_eq!(tree.len()?, 500 - deleted.len());
+
+                tree.gc()?;
+                assert!(!tree.gc()?);
+                let mut tree = tree.with_delete_mode(DeleteMode::Rebalance);
+                tree.validate()?;
+                tree.validate_leaf_chain()?;
+                let expected = (0..500u64).filter(|i| !deleted.contains(i)).map(|i| (i, i)).collect::<Vec<_>>();
+                assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected);
+
+                tree.insert(1, 1)?;
+                tree.delete(2)?;
+                tree.validate()?;
+            }
+        }
+
+        // Merging the short leaves can shrink the tree, while a snapshot
+        // taken before gc keeps reading the pages it had.
+        let mut tree = BPTree::with_pager(8, Box::new(MemoryPager::new()))?.with_delete_mode(DeleteMode::Deferred);
+        for i in 0..200u64 {
+            tree.insert(i, i)?;
+        }
+        for i in (0..200u64).filter(|i| i % 4 != 0) {
+            tree.delete(i)?;
+        }
+        let snapshot = tree.snapshot()?;
+        let depth = tree.search_path(0)?.len();
+        assert!(tree.gc()?);
+        assert!(tree.search_path(0)?.len() < depth);
+        assert_eq!(snapshot.iter(&tree).count(), 50);
+        assert_eq!(snapshot.search(&tree, 196)?, Some(196));
+        assert_eq!(tree.search(196)?, Some(196));
+        assert_eq!(tree.search(197)?, None);
+
+        let mut tree = BPTree::<u64, u64>::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert!(!tree.gc()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn short_leaves_survive_a_reopen() -> anyhow::Result<()> {
+        let path = "/tmp/short_leaves_survive_a_reopen.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(8, STARTUP_OFFSET, file)?.with_delete_mode(DeleteMode::Deferred);
+        for i in 0..200u32 {
+            tree.insert(i, i)?;
+        }
+        for i in (0..200u32).filter(|i| i % 7 != 0) {
+            tree.delete(i)?;
+        }
+        drop(tree);
+
+        // Reopened with the default mode, the short leaves are still
+        // accepted, and the first delete fills them before rebalancing a
+        // leaf it could otherwise only borrow one key for.
+        let mut tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        tree.validate()?;
+        assert!(tree.short_leaves);
+        assert_eq!(tree.delete(98)?, Some(98));
+        assert!(!tree.short_leaves);
+        tree.validate()?;
+        drop(tree);
+
+        let mut tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        assert!(!tree.short_leaves);
+        tree.validate()?;
+        assert_eq!(tree.len()?, 28);
+        assert!(!tree.gc()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_below_drops_the_head() -> anyhow::Result<()> {
+        for degree in [3, 4, 7] {
+            for watermark in [0u64, 1, 5, 17, 150, 151, 298, 299, 300, 1000] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+                for i in 0..300u64 {
+                    tree.insert(i * 2, i)?;
+                }
+
+                // Truncating copies every node it changes, so a snapshot of
+                // the tree before still reads all of it.
+                let snapshot = tree.snapshot()?;
+                let expected = 300 - watermark.div_ceil(2).min(300);
+                let removed = tree.truncate_below(watermark)?;
+                assert_eq!(removed as u64, 300 - expected, "degree {degree}, watermark {watermark}");
+                assert_eq!(snapshot.iter(&tree).collect::<Result<Vec<_>>>()?, (0..300u64).map(|i| (i * 2, i)).collect::<Vec<_>>());
+                tree.release(snapshot)?;
+                tree.validate()?;
+                tree.validate_leaf_chain()?;
+                assert_eq!(tree.len()? as u64, expected);
+                assert_eq!(tree.min_key()?, (expected > 0).then_some(watermark.div_ceil(2) * 2));
+                assert_eq!(tree.iter_rev().count() as u64, expected);
+
+                // The tree keeps working once the head is gone.
+                tree.insert(1, 1)?;
+                tree.delete(598)?;
+                tree.validate()?;
+            }
+        }
+
+        // Released pages, value pages included, are reused by later inserts.
+        let options = TreeOptions {
+            value_storage: ValueStorage::OutOfLine,
+            ..TreeOptions::new(8)
+        };
+        let mut tree = BPTree::with_options(Cursor::new(Vec::new()), options)?;
+        for i in 0..1000u64 {
+            tree.insert(i, vec![i as u8; 64])?;
+        }
+        let size = tree.file_size_pages();
+        assert_eq!(tree.truncate_below(900)?, 900);
+        tree.validate()?;
+        assert_eq!(tree.search(950)?, Some(vec![950u64 as u8; 64]));
+        for i in 1000..1800u64 {
+            tree.insert(i, vec![i as u8; 64])?;
+        }
+        assert!(tree.file_size_pages() <= size, "{} > {size}", tree.file_size_pages());
+
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.truncate_below(10)?, 0);
+        tree.insert(20, 20)?;
+        assert_eq!(tree.truncate_below(10)?, 0);
+        assert_eq!(tree.truncate_below(21)?, 1);
+        assert!(tree.is_empty()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_sorted_removes_present_keys() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        for i in 0..300u64 {
+            tree.insert(i, i)?;
+        }
+
+        let mut keys = (0..300).step_by(2).collect::<Vec<u64>>();
+        keys.extend([500, 600]);
+        assert_eq!(tree.delete_sorted(&keys)?, 150);
+        tree.validate()?;
+        tree.validate_leaf_chain()?;
+        assert_eq!(tree.len()?, 150);
+        assert_eq!(tree.search(10)?, None);
+        assert_eq!(tree.search(11)?, Some(11));
+
+        assert_eq!(tree.delete_sorted(&keys)?, 0);
+        assert_eq!(tree.delete_sorted(&[])?, 0);
+        assert!(tree.delete_sorted(&[5, 3]).is_err());
+        assert!(tree.delete_sorted(&[5, 5]).is_err());
+        assert_eq!(tree.search(5)?, Some(5));
+
+        let odd = (1..300).step_by(2).collect::<Vec<u64>>();
+        assert_eq!(tree.delete_sorted(&odd)?, 150);
+        tree.validate()?;
+        assert!(tree.is_empty()?);
+
+        // Runs that empty whole leaves and subtrees, and scattered keys
+        // that leave many leaves short at once, are rebalanced in one go.
+        for degree in [3, 4, 5, 8] {
+            for (start, end, step) in [(40u64, 260, 1), (0, 300, 3), (0, 299, 1), (150, 300, 1), (7, 290, 2)] {
+                let mut tree = BPTree::with_pager(degree, Box::new(MemoryPager::new()))?;
+                for i in 0..300u64 {
+                    tree.insert(i, i)?;
+                }
+                let keys = (start..end).step_by(step).collect::<Vec<u64>>();
+                assert_eq!(tree.delete_sorted(&keys)?, keys.len());
+                tree.validate()?;
+                tree.validate_leaf_chain()?;
+                let expected = (0..300u64).filter(|i| !keys.contains(i)).map(|i| (i, i)).collect::<Vec<_>>();
+                assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, expected);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn custom_comparator_orders_keys() -> anyhow::Result<()> {
+        fn numeric(a: &Key, b: &Key) -> Ordering {
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+        }
+
+        let path = "/tmp/custom_comparator_orders_keys.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?.with_comparator(numeric);
+        for i in (0..100).rev() {
+            tree.insert(i.to_string(), vec![i as u8])?;
+        }
+        tree.insert_many((100..150).map(|i| (i.to_string(), vec![i as u8])))?;
+        tree.validate()?;
+
+        let keys = tree.iter().map(|pair| pair.map(|(key, _)| key)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (0..150).map(|i| i.to_string()).collect::<Vec<_>>());
+        let range = tree.range(Bound::Included("9".to_string()), Bound::Excluded("12".to_string()))?;
+        assert_eq!(range.iter().map(|(key, _)| key.as_str()).collect::<Vec<_>>(), vec!["9", "10", "11"]);
+        assert_eq!(tree.delete("10".to_string())?, Some(vec![10]));
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open(file)?.with_comparator(numeric);
+        tree.validate()?;
+        assert_eq!(tree.search("2".to_string())?, Some(vec![2]));
+        assert_eq!(tree.search("10".to_string())?, None);
+        assert_eq!(tree.get_many(&["149".to_string(), "20".to_string()])?, vec![Some(vec![149]), Some(vec![20])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn borrowed_lookups_follow_the_comparator() -> anyhow::Result<()> {
+        fn reversed(a: &Key, b: &Key) -> Ordering {
+            b.cmp(a)
+        }
+
+        let words = ["app", "apple", "applied", "apply", "apps", "banana", "bandana", "cherry"];
+        for (comparator, order) in [
+            (Arc::new(reversed) as Arc<dyn KeyComparator<Key> + Send + Sync>, words.iter().rev().copied().collect::<Vec<_>>()),
+            (Arc::new(ShortSeparators), words.to_vec()),
+        ] {
+            let mut tree = BPTree::with_pager(3, Box::new(MemoryPager::new()))?;
+            tree.comparator = comparator;
+            for (i, word) in words.iter().enumerate() {
+                tree.insert(word.to_string(), i)?;
+            }
+            tree.validate()?;
+
+            for (i, word) in words.iter().enumerate() {
+                assert_eq!(tree.search_str(word)?, Some(i));
+            }
+            assert_eq!(tree.search_str("apples")?, None);
+            let keys = |pairs: Vec<(Key, usize)>| pairs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+            let expected = |prefix: &str| order.iter().filter(|word| word.starts_with(prefix)).map(|word| word.to_string()).collect::<Vec<_>>();
+            assert_eq!(keys(tree.scan_prefix("appl")?), expected("appl"));
+            assert_eq!(keys(tree.scan_prefix("ban")?), expected("ban"));
+            assert_eq!(tree.scan_prefix("")?.len(), words.len());
+            assert!(tree.scan_prefix("d")?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn crash_mid_operation_keeps_last_commit() -> anyhow::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+        use std::sync::Mutex;
+
+        /// Storage over shared bytes that takes `writes` more writes, the
+        /// last of them torn in half, and fails every one after, like a
+        /// process killed in the middle of an operation.
+        struct Crashing {
+            bytes: Arc<Mutex<Vec<u8>>>,
+            position: u64,
+            writes: usize,
+        }
+
+        impl Read for Crashing {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                let bytes = self.bytes.lock().unwrap();
+                let start = (self.position as usize).min(bytes.len());
+                let read = buffer.len().min(bytes.len() - start);
+                buffer[..read].copy_from_slice(&bytes[start..start + read]);
+                self.position += read as u64;
+                Ok(read)
+            }
+        }
+
+        impl Write for Crashing {
+            fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+                if self.writes == 0 {
+                    return Err(std::io::Error::other("crashed"));
+                }
+                self.writes -= 1;
+                let data = if self.writes == 0 { &data[..data.len() / 2] } else { data };
+
+                let mut bytes = self.bytes.lock().unwrap();
+                let end = self.position as usize + data.len();
+                if bytes.len() < end {
+                    bytes.resize(end, 0x00);
+                }
+                bytes[self.position as usize..end].copy_from_slice(data);
+                self.position = end as u64;
+                Ok(data.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
         }
-    }
 
-    pub fn is_empty(&mut self) -> anyhow::Result<bool> {
-        match self.root_node.take() {
-            None => Ok(true),
-            Some(root_offset) => {
-                let node = self.pager.read(root_offset)?;
-                self.root_node = Some(root_offset);
-                Ok(node.is_empty())
-            },
+        impl Seek for Crashing {
+            fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                let len = self.bytes.lock().unwrap().len() as u64;
+                self.position = match position {
+                    SeekFrom::Start(offset) => offset,
+                    SeekFrom::End(offset) => len.saturating_add_signed(offset),
+                    SeekFrom::Current(offset) => self.position.saturating_add_signed(offset),
+                };
+                Ok(self.position)
+            }
         }
-    }
 
-    pub fn insert(&mut self, key: Key, value: Value) -> anyhow::Result<()> {
-        match self.root_node.take() {
-            None => {
-                let root_node = Node::Leaf(LeafNode {
-                    keys: vec![key],
-                    values: vec![value],
-                    offset: Some(self.pager.next_offset()),
-                });
-                let root_offset = self.pager.write(&root_node)?;
-                self.root_node = Some(root_offset);
-            },
-            Some(root_offset) => {
-                let mut root_node = self.pager.read(root_offset)?;
-                let root_copy_offset = self.pager.write(&root_node)?;
+        impl ReadWriteSeek for Crashing {
+            fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+                self.bytes.lock().unwrap().resize(len as usize, 0x00);
+                Ok(())
+            }
+        }
 
-                match root_node.insert(&mut self.pager, key, value, self.degree)? {
-                    None => {
-                        self.pager.write_at(&root_node, root_copy_offset)?;
-                        self.root_node = Some(root_copy_offset);
-                    },
-                    Some((mid_key, sibling)) => {
-                        let sibling_offset = self.pager.write(&sibling)?;
-                        self.pager.write_at(&root_node, root_copy_offset)?;
+        let bytes = Arc::new(Mutex::new(Vec::new()));
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, Crashing { bytes: Arc::clone(&bytes), position: 0, writes: usize::MAX })?;
+        for i in 0..300u32 {
+            tree.insert(i, i)?;
+        }
+        tree.flush()?;
+        drop(tree);
+        let committed = bytes.lock().unwrap().clone();
+        let before = (0..300u32).map(|i| (i, i)).collect::<Vec<_>>();
+
+        type Operation = fn(&mut BPTree<u32, u32>) -> Result<()>;
+        let operations: [(Operation, Vec<(u32, u32)>); 3] = [
+            (|tree| tree.delete_range(50, 120).map(drop), before.iter().copied().filter(|(i, _)| !(50..120).contains(i)).collect()),
+            (|tree| tree.truncate_below(250).map(drop), (250..300u32).map(|i| (i, i)).collect()),
+            (
+                |tree| {
+                    tree.transaction(|txn| {
+                        for i in 300..320u32 {
+                            txn.insert(i, i)?;
+                        }
+                        for i in (100..140u32).step_by(2) {
+                            txn.delete(i)?;
+                        }
+                        Ok(())
+                    })
+                },
+                (0..320u32).filter(|i| !(100..140).contains(i) || i % 2 == 1).map(|i| (i, i)).collect(),
+            ),
+        ];
 
-                        let new_root = Node::Internal(InternalNode {
-                            keys: vec![mid_key],
-                            children: vec![root_copy_offset, sibling_offset],
-                            offset: Some(self.pager.next_offset()),
-                        });
+        // Whichever write the crash stops at, the tree reopens exactly as
+        // of its last commit, or with the operation whole once its header
+        // was written.
+        for (operation, after) in operations {
+            for writes in 0.. {
+                let bytes = Arc::new(Mutex::new(committed.clone()));
+                let mut tree = BPTree::<u32, u32>::open(Crashing { bytes: Arc::clone(&bytes), position: 0, writes })?;
+                let result = operation(&mut tree);
+                std::mem::forget(tree);
 
-                        let new_root_offset = self.pager.write(&new_root)?;
-                        self.root_node = Some(new_root_offset);
-                    },
+                let data = bytes.lock().unwrap().clone();
+                let tree = BPTree::<u32, u32>::open(Cursor::new(data))?;
+                tree.validate()?;
+                let pairs = tree.iter().collect::<Result<Vec<_>>>()?;
+                if result.is_ok() {
+                    assert_eq!(pairs, after);
+                    break;
                 }
-            },
+                assert_eq!(pairs, before, "crash after {writes} writes");
+            }
         }
 
         Ok(())
     }
 
-    pub fn delete(&mut self, key: Key) -> anyhow::Result<()> {
-        match self.root_node.take() {
-            None => {},
-            Some(root_offset) => {
-                let mut root_node = self.pager.read(root_offset)?;
-                let root_copy_offset = self.pager.write(&root_node)?;
+    #[test]
+    fn power_loss_keeps_the_last_synced_commit() -> anyhow::Result<()> {
+        use std::io::{Read, Seek, SeekFrom};
+        use std::sync::Mutex;
 
-                let need_rebalance = root_node.remove(&mut self.pager, key, self.degree)?;
-                self.pager.write_at(&root_node, root_copy_offset)?;
+        enum Op {
+            Write(u64, Vec<u8>),
+            Sync,
+        }
 
-                self.root_node = match need_rebalance {
-                    None => Some(root_copy_offset),
-                    Some(value) => {
-                        if value {
-                            match root_node {
-                                Node::Leaf(_) => Some(root_copy_offset),
-                                Node::Internal(payload) => {
-                                    if payload.keys.is_empty() {
-                                        Some(payload.children[0])
-                                    } else {
-                                        Some(root_copy_offset)
-                                    }
-                                },
-                            }
-                        } else {
-                            Some(root_copy_offset)
-                        }
-                    },
+        /// In-memory storage that logs its writes and syncs, so that what
+        /// a power loss could leave on disk can be replayed from the log.
+        struct Recording(Cursor<Vec<u8>>, Arc<Mutex<Vec<Op>>>);
+
+        impl Read for Recording {
+            fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+                self.0.read(buffer)
+            }
+        }
+
+        impl Write for Recording {
+            fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+                self.1.lock().unwrap().push(Op::Write(self.0.position(), data.to_vec()));
+                self.0.write(data)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        impl Seek for Recording {
+            fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+                self.0.seek(position)
+            }
+        }
+
+        impl ReadWriteSeek for Recording {
+            fn sync(&mut self) -> std::io::Result<()> {
+                self.1.lock().unwrap().push(Op::Sync);
+                Ok(())
+            }
+        }
+
+        let mut state = 0x2545_f491_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let options = TreeOptions {
+            flush_policy: FlushPolicy::EveryN(7),
+            ..TreeOptions::new(4)
+        };
+        let mut tree = BPTree::with_options(Recording(Cursor::new(Vec::new()), Arc::clone(&log)), options)?;
+        tree.flush()?;
+        // The pairs of the tree after each operation, with the length of
+        // the log when it returned.
+        let mut commits = vec![(log.lock().unwrap().len(), Vec::new())];
+        let mut expected = BTreeMap::new();
+        for step in 0..120 {
+            let key = (next() % 60) as u32;
+            if step < 40 || next() % 3 != 0 {
+                tree.insert(key, step)?;
+                expected.insert(key, step);
+            } else {
+                tree.delete(key)?;
+                expected.remove(&key);
+            }
+            if step % 25 == 24 {
+                tree.flush()?;
+            }
+            commits.push((log.lock().unwrap().len(), expected.clone().into_iter().collect::<Vec<_>>()));
+        }
+        drop(tree);
+        let log = std::mem::take(&mut *log.lock().unwrap());
+
+        // Power lost after any write keeps the writes synced before it, and
+        // any of the node page writes made since, but not the header
+        // written since: that has to read back as the last synced commit.
+        for crash in 0..=log.len() {
+            let Some(synced) = log[..crash].iter().rposition(|op| matches!(op, Op::Sync)) else {
+                continue;
+            };
+            let header = log[..synced]
+                .iter()
+                .rposition(|op| matches!(op, Op::Write(offset, _) if (*offset as usize) < STARTUP_OFFSET))
+                .unwrap();
+            let (_, pairs) = commits.iter().find(|(len, _)| *len > header).unwrap();
+
+            for subset in 0..4 {
+                let mut bytes = Cursor::new(Vec::new());
+                for (i, op) in log[..crash].iter().enumerate() {
+                    let Op::Write(offset, data) = op else {
+                        continue;
+                    };
+                    let kept = i < synced
+                        || (*offset as usize) >= STARTUP_OFFSET && match subset {
+                            0 => true,
+                            1 => false,
+                            _ => next() % 2 == 0,
+                        };
+                    if kept {
+                        bytes.seek(SeekFrom::Start(*offset))?;
+                        bytes.write_all(data)?;
+                    }
                 }
-            },
+
+                let tree = BPTree::<u32, u32>::open(bytes)?;
+                tree.validate()?;
+                assert_eq!(&tree.iter().collect::<Result<Vec<_>>>()?, pairs, "power lost after {crash} writes");
+                std::mem::forget(tree);
+            }
         }
 
         Ok(())
     }
 
-    pub fn search(&mut self, key: Key) -> anyhow::Result<Option<Value>> {
-        match self.root_node.take() {
-            None => Ok(None),
-            Some(root_offset) => {
-                let root_node = self.pager.read(root_offset)?;
-                self.root_node = Some(root_offset);
-                root_node.search(&mut self.pager, key)
-            },
-        }
-    }
+    #[test]
+    fn drop_flushes_header() -> anyhow::Result<()> {
+        let path = "/tmp/drop_flushes_header.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
 
-    pub fn debug_print(&mut self) -> anyhow::Result<()> {
-        if let Some(node_offset) = self.root_node {
-            let node = self.pager.read(node_offset)?;
-            let _ = node.debug_print(&mut self.pager, 0)?;
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..100u32 {
+            tree.insert(i, i)?;
         }
+        for i in 0..10u32 {
+            tree.take(i)?;
+        }
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut tree = BPTree::<u32, u32>::open(file)?;
+        assert_eq!(tree.len()?, 90);
+
+        // A panic inside a transaction is rolled back before it unwinds
+        // through the drop.
+        let result = panic::catch_unwind(AssertUnwindSafe(move || {
+            let _ = tree.transaction(|txn| {
+                txn.delete(50)?;
+                panic!("abort");
+            });
+        }));
+        assert!(result.is_err());
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<u32, u32>::open(file)?;
+        tree.validate()?;
+        assert_eq!(tree.len()?, 90);
+        assert_eq!(tree.search(50)?, Some(50));
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        collections::{BTreeMap, HashSet},
-        fs::OpenOptions,
-    };
+    #[test]
+    fn floor_and_ceiling_work() -> anyhow::Result<()> {
+        let mut tree = BPTree::with_pager(4, Box::new(MemoryPager::new()))?;
+        assert_eq!(tree.floor(5)?, None);
+        assert_eq!(tree.ceiling(5)?, None);
 
-    use crate::pager::STARTUP_OFFSET;
+        // Multiples of ten spread over many leaves, so most lookups between
+        // them cross a leaf boundary in one direction or the other.
+        for i in (10..=500u32).step_by(10) {
+            tree.insert(i, i * 2)?;
+        }
 
-    use super::*;
+        assert_eq!(tree.floor(5)?, None);
+        assert_eq!(tree.ceiling(5)?, Some((10, 20)));
+        assert_eq!(tree.floor(505)?, Some((500, 1000)));
+        assert_eq!(tree.ceiling(505)?, None);
+        for i in (10..=500u32).step_by(10) {
+            assert_eq!(tree.floor(i)?, Some((i, i * 2)));
+            assert_eq!(tree.ceiling(i)?, Some((i, i * 2)));
+            assert_eq!(tree.floor(i + 5)?, Some((i, i * 2)));
+            assert_eq!(tree.ceiling(i - 5)?, Some((i, i * 2)));
+        }
+
+        Ok(())
+    }
 
     #[test]
-    fn test_tree_structure() -> anyhow::Result<()> {
+    fn reopening_checks_the_degree() -> anyhow::Result<()> {
+        let path = "/tmp/reopening_checks_the_degree.ldb";
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(true)
-            .open("/tmp/test_tree_structure.ldb")
-            .unwrap();
+            .open(path)?;
 
-        let mut tree = BPTree::new(4, STARTUP_OFFSET, file);
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..100u32 {
+            tree.insert(i, i)?;
+        }
+        drop(tree);
 
-        tree.insert("0010".to_string(), "ten".as_bytes().to_vec())?;
-        tree.insert("0020".to_string(), "twenty".as_bytes().to_vec())?;
-        tree.insert("0005".to_string(), "five".as_bytes().to_vec())?;
-        tree.insert("0006".to_string(), "six".as_bytes().to_vec())?;
-        tree.insert("0012".to_string(), "twelve".as_bytes().to_vec())?;
-        tree.insert("0030".to_string(), "thirty".as_bytes().to_vec())?;
-        tree.insert("0007".to_string(), "seven".as_bytes().to_vec())?;
-        tree.insert("0017".to_string(), "seventeen".as_bytes().to_vec())?;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let err = BPTree::<u32, u32>::new(8, STARTUP_OFFSET, file).err().unwrap();
+        assert!(matches!(err, BPTreeError::DegreeMismatch { stored: 4, requested: 8 }), "{err}");
+        assert_eq!(err.to_string(), "file holds a tree of degree 4, not 8");
 
-        assert_eq!(tree.search("0010".to_string())?, Some("ten".as_bytes().to_vec()));
-        assert_eq!(tree.search("0020".to_string())?, Some("twenty".as_bytes().to_vec()));
-        assert_eq!(tree.search("0005".to_string())?, Some("five".as_bytes().to_vec()));
-        assert_eq!(tree.search("0006".to_string())?, Some("six".as_bytes().to_vec()));
-        assert_eq!(tree.search("0012".to_string())?, Some("twelve".as_bytes().to_vec()));
-        assert_eq!(tree.search("0030".to_string())?, Some("thirty".as_bytes().to_vec()));
-        assert_eq!(tree.search("0007".to_string())?, Some("seven".as_bytes().to_vec()));
-        assert_eq!(
-            tree.search("0017".to_string())?,
-            Some("seventeen".as_bytes().to_vec())
-        );
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let err = BPTree::<u32, u32>::with_options(file, TreeOptions::new(8)).err().unwrap();
+        assert!(matches!(err, BPTreeError::DegreeMismatch { stored: 4, requested: 8 }), "{err}");
 
-        assert_eq!(tree.search("2000".to_string())?, None);
-        assert_eq!(tree.search("3000".to_string())?, None);
+        // The matching degree reopens the tree rather than starting over.
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<u32, u32>::new(4, STARTUP_OFFSET, file)?;
+        assert_eq!(tree.len()?, 100);
+        tree.validate()?;
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        assert!(BPTree::<u32, u32>::bulk_load(4, file, (0..10).map(|i| (i, i))).is_err());
 
         Ok(())
     }
 
     #[test]
-    fn test_large_insertions() -> anyhow::Result<()> {
+    fn reload_picks_up_commits_of_another_tree() -> anyhow::Result<()> {
+        let path = "/tmp/reload_picks_up_commits_of_another_tree.ldb";
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(true)
-            .open("/tmp/test_large_insertions.ldb")
-            .unwrap();
-
-        let mut tree = BPTree::new(300, STARTUP_OFFSET, file);
+            .open(path)?;
 
-        for i in 1..=100000 {
-            tree.insert(i.to_string(), i.to_string().as_bytes().to_vec())?;
+        let mut writer = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..100u32 {
+            writer.insert(i, i)?;
         }
+        let mut reader = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        assert_eq!(reader.search(50)?, Some(50));
 
-        for i in 1..=100000 {
-            assert_eq!(tree.search(i.to_string())?, Some(i.to_string().as_bytes().to_vec()));
+        // Deleting frees pages the next inserts reuse, rewriting pages the
+        // reader has cached.
+        for i in 0..50u32 {
+            writer.delete(i)?;
         }
+        for i in 100..200u32 {
+            writer.insert(i, i * 2)?;
+        }
+        assert_eq!(reader.search(150)?, None);
+
+        reader.reload()?;
+        reader.validate()?;
+        assert_eq!(reader.search(10)?, None);
+        assert_eq!(reader.search(150)?, Some(300));
+        assert_eq!(reader.len()?, 150);
+        assert_eq!(reader.file_size_pages(), writer.file_size_pages());
+        drop(reader);
+
+        writer.insert(500, 500)?;
+        drop(writer);
+        let tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        assert_eq!(tree.len()?, 151);
 
         Ok(())
     }
 
     #[test]
-    fn assemble_disassemble() -> anyhow::Result<()> {
+    fn dropping_a_reloaded_tree_keeps_newer_commits() -> anyhow::Result<()> {
+        let path = "/tmp/dropping_a_reloaded_tree_keeps_newer_commits.ldb";
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(true)
-            .open("/tmp/assemble_disassemble.ldb")
-            .unwrap();
-
-        let mut tree = BPTree::new(4, 0, file);
-
-        let key_value_pairs = BTreeMap::from([
-            ("001".to_string(), "derby".as_bytes().to_vec()),
-            ("002".to_string(), "elephant".as_bytes().to_vec()),
-            ("003".to_string(), "four".as_bytes().to_vec()),
-            ("004".to_string(), "avengers".as_bytes().to_vec()),
-            ("005".to_string(), "bing".as_bytes().to_vec()),
-            ("006".to_string(), "center".as_bytes().to_vec()),
-            ("007".to_string(), "center".as_bytes().to_vec()),
-            ("008".to_string(), "bing".as_bytes().to_vec()),
-            ("009".to_string(), "center".as_bytes().to_vec()),
-            ("010".to_string(), "center".as_bytes().to_vec()),
-            ("011".to_string(), "derby".as_bytes().to_vec()),
-            ("012".to_string(), "elephant".as_bytes().to_vec()),
-            ("013".to_string(), "four".as_bytes().to_vec()),
-            ("014".to_string(), "avengers".as_bytes().to_vec()),
-            ("015".to_string(), "bing".as_bytes().to_vec()),
-            ("016".to_string(), "center".as_bytes().to_vec()),
-            ("017".to_string(), "center".as_bytes().to_vec()),
-            ("018".to_string(), "bing".as_bytes().to_vec()),
-            ("019".to_string(), "center".as_bytes().to_vec()),
-            ("020".to_string(), "center".as_bytes().to_vec()),
-        ]);
+            .open(path)?;
 
-        for (key, value) in &key_value_pairs {
-            tree.insert(key.clone(), value.clone())?;
+        let mut writer = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..50u32 {
+            writer.insert(i, i)?;
         }
+        let mut reader = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        for i in 50..100u32 {
+            writer.insert(i, i)?;
+        }
+        reader.reload()?;
+        assert_eq!(reader.len()?, 100);
 
-        for (key, value) in &key_value_pairs {
-            assert_eq!(tree.search(key.clone())?, Some(value.clone()));
+        // The reader's header is stale once the writer commits again, and
+        // neither flushing nor dropping the reader may put it back.
+        for i in 100..150u32 {
+            writer.insert(i, i)?;
         }
+        writer.flush()?;
+        reader.flush()?;
+        drop(reader);
 
-        assert!(!tree.is_empty()?);
+        let tree = BPTree::<u32, u32>::open(OpenOptions::new().read(true).write(true).open(path)?)?;
+        assert_eq!(tree.len()?, 150);
+        tree.validate()?;
+        drop(tree);
+        drop(writer);
 
-        tree.delete("006".to_string())?;
-        tree.delete("012".to_string())?;
-        tree.delete("002".to_string())?;
-        tree.delete("005".to_string())?;
-        tree.delete("001".to_string())?;
-        tree.delete("003".to_string())?;
-        tree.delete("004".to_string())?;
-        tree.delete("007".to_string())?;
-        tree.delete("008".to_string())?;
-        tree.delete("009".to_string())?;
-        tree.delete("010".to_string())?;
-        tree.delete("011".to_string())?;
-        tree.delete("018".to_string())?;
-        tree.delete("019".to_string())?;
-        tree.delete("017".to_string())?;
-        tree.delete("020".to_string())?;
-        tree.delete("014".to_string())?;
-        tree.delete("015".to_string())?;
-        tree.delete("016".to_string())?;
-        tree.delete("013".to_string())?;
+        Ok(())
+    }
 
-        assert!(tree.is_empty()?);
+    #[test]
+    fn torn_header_falls_back_to_the_older_slot() -> anyhow::Result<()> {
+        use std::io::{Seek, SeekFrom};
+
+        let path = "/tmp/torn_header_falls_back_to_the_older_slot.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        // Creating the tree writes the first header to slot 1, and each
+        // insert the next one to the other slot. The tree is forgotten so
+        // dropping it doesn't write another.
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        tree.insert(1u32, 10u32)?;
+        tree.insert(2u32, 20u32)?;
+        std::mem::forget(tree);
+
+        // A write torn part way through the newer header, in slot 1.
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 + 40))?;
+        file.write_all(&[0xff; 100])?;
+        let mut tree = BPTree::<u32, u32>::open(file)?;
+        tree.validate()?;
+        assert_eq!(tree.search(1)?, Some(10));
+        assert_eq!(tree.search(2)?, None);
+
+        // The next header overwrites the torn slot.
+        tree.insert(3, 30)?;
+        drop(tree);
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<u32, u32>::open(file)?;
+        assert_eq!(tree.iter().collect::<Result<Vec<_>>>()?, vec![(1, 10), (3, 30)]);
+
+        // With both slots torn, nothing is left to fall back to.
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        for slot in [0, HEADER_SIZE as u64] {
+            file.seek(SeekFrom::Start(slot + 40))?;
+            file.write_all(&[0xff; 100])?;
+        }
+        let err = BPTree::<u32, u32>::open(file).err().unwrap();
+        assert!(matches!(err, BPTreeError::Corrupt { .. }), "{err}");
 
         Ok(())
     }
 
     #[test]
-    fn delete_works() -> anyhow::Result<()> {
+    fn torn_header_after_a_split_reads_the_older_tree() -> anyhow::Result<()> {
+        let path = "/tmp/torn_header_after_a_split_reads_the_older_tree.ldb";
         let file = OpenOptions::new()
             .create(true)
             .read(true)
             .write(true)
             .truncate(true)
-            .open("/tmp/delete_works.ldb")
-            .unwrap();
+            .open(path)?;
 
-        let mut tree = BPTree::new(4, STARTUP_OFFSET, file);
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in (0..200u32).step_by(2) {
+            tree.insert(i, i)?;
+        }
+        tree.flush()?;
+        // Splits a leaf in the middle of the tree.
+        tree.insert(51, 51)?;
+        std::mem::forget(tree);
 
-        let key_value_pairs = BTreeMap::from([
-            ("d".to_string(), "derby".as_bytes().to_vec()),
-            ("e".to_string(), "elephant".as_bytes().to_vec()),
-            ("f".to_string(), "four".as_bytes().to_vec()),
-            ("a".to_string(), "avengers".as_bytes().to_vec()),
-            ("b".to_string(), "bing".as_bytes().to_vec()),
-            ("c".to_string(), "center".as_bytes().to_vec()),
-            ("g".to_string(), "gover".as_bytes().to_vec()),
-        ]);
+        // Tearing the newer slot falls back to the tree without 51, the
+        // older one leaves the tree with it. Either way every leaf walk
+        // agrees with the lookups.
+        let bytes = std::fs::read(path)?;
+        let mut found = Vec::new();
+        for slot in [0, HEADER_SIZE] {
+            let mut torn = bytes.clone();
+            torn[slot + 40..slot + 140].fill(0xff);
+            let tree = BPTree::<u32, u32>::open(Cursor::new(torn))?;
+            tree.validate()?;
+            tree.validate_leaf_chain()?;
 
-        for (key, value) in &key_value_pairs {
-            tree.insert(key.clone(), value.clone())?;
+            let pairs = tree.iter().collect::<Result<Vec<_>>>()?;
+            for &(key, value) in &pairs {
+                assert_eq!(tree.search(key)?, Some(value));
+            }
+            let mut reversed = tree.iter_rev().collect::<Result<Vec<_>>>()?;
+            reversed.reverse();
+            assert_eq!(reversed, pairs);
+            let in_range = pairs.iter().filter(|(key, _)| (40..60).contains(key)).count();
+            assert_eq!(tree.range(Bound::Included(40), Bound::Excluded(60))?.len(), in_range);
+            found.push(tree.search(51)?.is_some());
+            assert_eq!(pairs.len(), 100 + usize::from(found[found.len() - 1]));
         }
+        found.sort();
+        assert_eq!(found, vec![false, true]);
 
-        for (key, value) in &key_value_pairs {
-            assert_eq!(tree.search(key.clone())?, Some(value.clone()));
-        }
+        Ok(())
+    }
 
-        let keys_for_delete = vec![
-            "f".to_string(),
-            "e".to_string(),
-            "c".to_string(),
-            "a".to_string(),
-            "b".to_string(),
-            "d".to_string(),
-            "g".to_string(),
-        ];
+    #[test]
+    fn open_checks_format() -> anyhow::Result<()> {
+        use std::io::{Seek, SeekFrom};
 
-        let mut deleted_keys = HashSet::new();
+        let path = "/tmp/open_checks_format.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
 
-        for key in &keys_for_delete {
-            tree.delete(key.clone())?;
-            assert_eq!(tree.search(key.clone())?, None);
-            deleted_keys.insert(key.clone());
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        tree.insert("key".to_string(), b"value".to_vec())?;
+        drop(tree);
 
-            for (initial_key, value) in &key_value_pairs {
-                if !deleted_keys.contains(initial_key) {
-                    assert_eq!(tree.search(initial_key.clone())?, Some(value.clone()));
-                }
-            }
+        // The version follows the four magic bytes, encoded as one byte.
+        // Either header slot of a newer version is refused, and so is one
+        // of an older version, which has no upgrade path.
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 + 4))?;
+        file.write_all(&[FORMAT_VERSION as u8 + 1])?;
+        let err = BPTree::<Key, Value>::open(file).err().unwrap();
+        assert!(matches!(err, BPTreeError::UnsupportedFormat { version } if version == FORMAT_VERSION + 1), "{err}");
+        assert_eq!(
+            err.to_string(),
+            format!("unsupported format version {}, this build supports {FORMAT_VERSION}", FORMAT_VERSION + 1),
+        );
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64 + 4))?;
+        file.write_all(&[FORMAT_VERSION as u8 - 1])?;
+        let err = BPTree::<Key, Value>::open(file).err().unwrap();
+        assert!(matches!(err, BPTreeError::OutdatedFormat { version } if version == FORMAT_VERSION - 1), "{err}");
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        for slot in [0, HEADER_SIZE as u64] {
+            file.seek(SeekFrom::Start(slot))?;
+            file.write_all(b"XXXX")?;
+            file.write_all(&[FORMAT_VERSION as u8])?;
         }
+        let err = BPTree::<Key, Value>::open(file).err().unwrap();
+        assert_eq!(err.to_string(), "file does not contain a tree header");
+
+        file = OpenOptions::new().read(true).write(true).open(path)?;
+        for slot in [0, HEADER_SIZE as u64] {
+            file.seek(SeekFrom::Start(slot))?;
+            file.write_all(b"BPTR")?;
+        }
+        let tree = BPTree::<Key, Value>::open(file)?;
+        assert_eq!(tree.search("key".to_string())?, Some(b"value".to_vec()));
 
-        assert!(tree.is_empty()?);
         Ok(())
     }
 }
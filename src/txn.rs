@@ -0,0 +1,98 @@
+use bincode::{Decode, Encode};
+
+use super::error::Result;
+use super::pager::{DynOperator, PageOperator};
+use super::tree::BPTree;
+
+/// Changes applied to a tree inside [`BPTree::transaction`]. They are
+/// written copy-on-write like any other change, but the new root is only
+/// committed once the transaction succeeds. Reads through the transaction
+/// see its own changes.
+pub struct Txn<'a, K, V, P: ?Sized + DynOperator<K, V> = dyn PageOperator<K, V>> {
+    tree: &'a mut BPTree<K, V, P>,
+}
+
+impl<'a, K, V, P> Txn<'a, K, V, P>
+where
+    K: Ord + Clone + Encode + Decode + 'static,
+    V: Clone + Encode + Decode + 'static,
+    P: ?Sized + DynOperator<K, V>,
+{
+    pub(crate) fn new(tree: &'a mut BPTree<K, V, P>) -> Self {
+        Self { tree }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        Ok(self.tree.put(key, value)?)
+    }
+
+    /// Removes `key` and returns its value, or `None` if the key was not
+    /// present.
+    pub fn delete(&mut self, key: K) -> Result<Option<V>> {
+        Ok(self.tree.take(key)?)
+    }
+
+    pub fn search(&self, key: K) -> Result<Option<V>> {
+        self.tree.search(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::pager::STARTUP_OFFSET;
+    use crate::tree::{Key, Value};
+
+    use super::*;
+
+    #[test]
+    fn transaction_commits_all_or_nothing() -> anyhow::Result<()> {
+        let path = "/tmp/transaction_commits_all_or_nothing.ldb";
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+
+        let mut tree = BPTree::new(4, STARTUP_OFFSET, file)?;
+        for i in 0..50 {
+            tree.insert(format!("{i:02}"), b"old".to_vec())?;
+        }
+
+        tree.transaction(|txn| {
+            for i in 0..10 {
+                txn.delete(format!("{i:02}"))?;
+            }
+            txn.insert("50".to_string(), b"new".to_vec())?;
+            assert_eq!(txn.search("50".to_string())?, Some(b"new".to_vec()));
+            Ok(())
+        })?;
+        assert_eq!(tree.len()?, 41);
+
+        let result = tree.transaction(|txn| {
+            for i in 10..30 {
+                txn.delete(format!("{i:02}"))?;
+            }
+            txn.insert("51".to_string(), b"new".to_vec())?;
+            anyhow::bail!("abort");
+        });
+        assert_eq!(result.unwrap_err().to_string(), "abort");
+        tree.validate()?;
+        assert_eq!(tree.len()?, 41);
+        assert_eq!(tree.search("10".to_string())?, Some(b"old".to_vec()));
+        assert_eq!(tree.search("51".to_string())?, None);
+        drop(tree);
+
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let tree = BPTree::<Key, Value>::open(file)?;
+        tree.validate()?;
+        assert_eq!(tree.len()?, 41);
+        assert_eq!(tree.search("05".to_string())?, None);
+        assert_eq!(tree.search("50".to_string())?, Some(b"new".to_vec()));
+        assert_eq!(tree.search("20".to_string())?, Some(b"old".to_vec()));
+
+        Ok(())
+    }
+}